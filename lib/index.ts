@@ -2,8 +2,8 @@ import { inflate } from 'fflate'
 
 import wasm, { size as wasmSize } from '../target/wasm32-wasi/release/tokenizer.wasm'
 
-import { WebModule } from './webmodule.js'
-export { WebModule }
+import { ErrorOutput, TokenizerError, WebModule } from './webmodule.js'
+export { TokenizerError, WebModule }
 
 export interface LoadTokenizerTiktoken {
     bpe: string
@@ -12,17 +12,246 @@ export interface LoadTokenizerTiktoken {
 }
 export interface LoadTokenizerHuggingface {
     model: string
+    // Contents of tokenizer_config.json, providing the chat template
+    config?: string
+    // Contents of special_tokens_map.json, taking precedence over the config
+    special_tokens_map?: string
 }
 
-export type LoadTokenizerInput = LoadTokenizerTiktoken | LoadTokenizerHuggingface
+export type EncodingPreset = 'r50k_base' | 'p50k_base' | 'p50k_edit' | 'cl100k_base' | 'o200k_base'
+
+export interface LoadTokenizerPreset {
+    preset: EncodingPreset
+    bpe: string
+}
+
+export type LoadTokenizerInput = LoadTokenizerTiktoken | LoadTokenizerHuggingface | LoadTokenizerPreset
+
+// Name of a loaded tokenizer, or the handle returned when loading it
+export type TokenizerRef = string | number
+
+export type SpecialTokenSet = 'all' | string[]
+
+export interface SpecialTokenOptions {
+    allowed_special?: SpecialTokenSet
+    disallowed_special?: SpecialTokenSet
+}
+
+export interface DecodeOptions {
+    special_placeholder?: string
+    // Defaults to throwing on unknown ids for tiktoken and skipping them for huggingface
+    lenient?: boolean
+    invalid_replacement?: string
+}
+
+export interface SpecialTokenRole {
+    // Name of the role in the config, like bos_token
+    role: string
+    token: string
+    id: number | null
+}
+
+export interface TokenizerInfo {
+    name: string
+    handle: number
+    kind: 'tiktoken' | 'huggingface'
+    vocab_size: number
+    special_tokens: Array<[string, number]>
+    max_token_id: number | null
+    regex: string | null
+    memory_usage: number
+    special_token_roles: SpecialTokenRole[]
+    max_length: number | null
+    add_bos_token: boolean | null
+    add_eos_token: boolean | null
+}
+
+export interface EncodingOffsets {
+    tokens: Uint32Array
+    byteOffsets: Uint32Array
+    // Char and UTF-16 spans are widened to whole characters, so tokens splitting a character overlap
+    charOffsets: Uint32Array
+    utf16Offsets?: Uint32Array
+}
+
+export interface UnstableEncoding {
+    tokens: Uint32Array
+    completions: Uint32Array[]
+}
+
+export interface HealedEncoding {
+    tokens: Uint32Array
+    prefix: Uint8Array
+    // Null when nothing was removed and any token may follow
+    allowed: Uint32Array | null
+}
+
+export type TruncationDirection = 'head' | 'tail' | 'both'
+
+export interface TruncateOptions extends SpecialTokenOptions {
+    direction?: TruncationDirection
+    // Joins head and tail when keeping both, defaults to "…"
+    ellipsis?: string
+}
+
+export interface Truncation {
+    tokens: Uint32Array
+    text: string
+    truncated: boolean
+}
+
+export interface EncodeMasksOptions {
+    // Second sequence of every input, one per text
+    pairs?: string[]
+    // Enables truncation when set
+    max_length?: number
+    truncation?: 'longest-first' | 'only-first' | 'only-second'
+    // Number of tokens repeated from the previous window in overflowing encodings
+    stride?: number
+    padding?: 'longest' | 'max-length'
+    pad_to_multiple_of?: number
+    padding_side?: 'left' | 'right'
+    // Defaults to the pad token of the tokenizer config
+    pad_token?: string
+}
+
+export interface EncodingWindow {
+    tokens: Uint32Array
+    type_ids: Uint32Array
+    attention_mask: Uint32Array
+    special_tokens_mask: Uint32Array
+}
+
+export interface MaskedEncoding extends EncodingWindow {
+    overflowing: EncodingWindow[]
+}
+
+export interface ChunkOptions extends SpecialTokenOptions {
+    // Number of tokens shared by consecutive chunks, defaults to 0
+    overlap?: number
+    // Number of tokens a chunk may be shortened by to end at a natural boundary, defaults to a quarter of the chunk size
+    slack?: number
+}
+
+export interface TextChunk {
+    tokens: [number, number]
+    bytes: [number, number]
+    chars: [number, number]
+    utf16: [number, number]
+}
+
+export interface ChatMessage {
+    role: string
+    name?: string
+    content: string
+}
+
+// Rendered as `{message_prefix}{role}[{name_separator}{name}]{role_separator}{content}{message_suffix}`, defaults to ChatML
+export interface ChatFormat {
+    message_prefix?: string
+    name_separator?: string
+    role_separator?: string
+    message_suffix?: string
+    reply_prefix?: string
+    // Billed overheads of the markers, default to 3, 1 and 3 like ChatML is billed
+    tokens_per_message?: number
+    tokens_per_name?: number
+    reply_tokens?: number
+}
+
+export interface RenderedChat {
+    tokens: Uint32Array
+    text: string
+    message_tokens: number[]
+    reply_tokens: number
+}
+
+export interface ChatTemplateOptions {
+    // Name of a template from the tokenizer config, or the template itself
+    chat_template?: string
+    add_generation_prompt?: boolean
+}
+
+export interface ChatPrompt {
+    tokens: Uint32Array
+    text: string
+}
+
+export interface JsonSchema {
+    type?: string | string[]
+    description?: string
+    properties?: Record<string, JsonSchema>
+    required?: string[]
+    enum?: (string | number | boolean | null)[]
+    items?: JsonSchema
+    anyOf?: JsonSchema[]
+    oneOf?: JsonSchema[]
+    $ref?: string
+}
+
+export interface FunctionDefinition {
+    name: string
+    description?: string
+    parameters?: JsonSchema
+}
+
+export type ToolDefinition = FunctionDefinition | { type: 'function'; function: FunctionDefinition }
+
+export interface ToolTokens {
+    text: string
+    text_tokens: number
+    prompt_tokens: number
+}
+
+type ChatPromptOutput = { tokens: Uint8Array; text: string }
+type RenderedChatOutput = { tokens: Uint8Array; text: string; message_tokens: number[]; reply_tokens: number }
+type TruncateOutput = { tokens: Uint8Array; text: string; truncated: boolean }
+type EncodeHealingOutput = { tokens: Uint8Array; prefix: Uint8Array; allowed: Uint8Array | null }
+type EncodeUnstableOutput = { tokens: Uint8Array; completions: Uint8Array[] }
+type MaskedEncodingOutput = { tokens: Uint8Array; type_ids: Uint8Array; attention_mask: Uint8Array; special_tokens_mask: Uint8Array; overflowing: MaskedEncodingOutput[] }
+type EncodeOffsetsOutput = { tokens: Uint8Array; byte_offsets: Uint8Array; char_offsets: Uint8Array; utf16_offsets?: Uint8Array }
+type BatchOutput = { tokens: Uint8Array } | { text: string } | ErrorOutput
+
+function toUint32Array(data: Uint8Array): Uint32Array {
+    const copy = new Uint8Array(data)
+    return new Uint32Array(copy.buffer, copy.byteOffset, copy.byteLength / 4)
+}
+
+export class Decoder {
+    private webm: WebModule
+    readonly id: number
+
+    constructor(webm: WebModule, id: number) {
+        this.webm = webm
+        this.id = id
+    }
+
+    public push(input: Uint32Array): string {
+        const result = this.webm.call_raw(
+            'decoder-push',
+            this.webm.pack.encode({
+                decoder: this.id,
+                input: new Uint8Array(input.buffer, input.byteOffset, input.byteLength),
+            })
+        )
+        return new TextDecoder().decode(result)
+    }
+
+    public flush(): string {
+        const result = this.webm.call_raw('decoder-flush', this.webm.pack.encode({ decoder: this.id }))
+        return new TextDecoder().decode(result)
+    }
+
+    public drop() {
+        this.webm.call('decoder-drop', { decoder: this.id })
+    }
+}
 
 export class Tokenizer {
     private webm: WebModule
-    private loaded: Array<string>
 
     private constructor(webm: WebModule) {
         this.webm = webm
-        this.loaded = []
     }
 
     public static async create() {
@@ -53,43 +282,305 @@ export class Tokenizer {
     }
 
     public getLoaded(): Array<string> {
-        return [...this.loaded]
+        return this.listTokenizers().map((x) => x.name)
+    }
+
+    public listTokenizers(): TokenizerInfo[] {
+        return this.webm.call<TokenizerInfo[]>('list-tokenizers', {}) ?? []
     }
 
-    public load(name: string, data: LoadTokenizerInput) {
-        this.webm.call('load-tokenizer', {
+    /**
+     * Loads a tokenizer under the given name, replacing any tokenizer of the same name.
+     * Returns a handle that can be passed instead of the name to skip the name lookup.
+     */
+    public load(name: string, data: LoadTokenizerInput): number {
+        return this.webm.call_u32('load-tokenizer', {
             name,
             data,
         })
-        this.loaded.push(name)
     }
 
-    public unload(name: string) {
-        this.webm.call('unload-tokenizer', name)
-        this.loaded = this.loaded.filter((x) => x !== name)
+    public unload(tokenizer: TokenizerRef) {
+        this.webm.call('unload-tokenizer', { name: tokenizer })
+    }
+
+    public encodingForModel(model: string): EncodingPreset {
+        const result = this.webm.call<EncodingPreset>('encoding-for-model', model)
+        if (!result) {
+            throw new Error('Unexpected empty result')
+        }
+        return result
+    }
+
+    public tokenToId(tokenizer: TokenizerRef, token: Uint8Array | string): number | null {
+        return this.webm.call<number | null>('token-to-id', { name: tokenizer, input: token })
+    }
+
+    public idToTokenBytes(tokenizer: TokenizerRef, token: number): Uint8Array | null {
+        return this.webm.call<Uint8Array | null>('id-to-token-bytes', { name: tokenizer, token })
+    }
+
+    public isSpecial(tokenizer: TokenizerRef, token: number): boolean {
+        return this.webm.call<boolean>('is-special', { name: tokenizer, token }) ?? false
+    }
+
+    public specialTokenId(tokenizer: TokenizerRef, name: string): number | null {
+        return this.webm.call<number | null>('special-token-id', { name: tokenizer, input: name })
+    }
+
+    /** Returns the ids of all non-special tokens whose bytes start with `prefix`. */
+    public tokensWithPrefix(tokenizer: TokenizerRef, prefix: Uint8Array | string): Uint32Array {
+        const result = this.webm.call_raw('tokens-with-prefix', this.webm.pack.encode({ name: tokenizer, input: prefix }))
+        return new Uint32Array(result.buffer, result.byteOffset, result.byteLength / 4)
     }
 
-    public encode(tokenizer: string, input: string, special_tokens = true): Uint32Array {
+    /** Returns the ids of all non-special tokens whose bytes are a prefix of `input`, shortest first. */
+    public prefixTokens(tokenizer: TokenizerRef, input: Uint8Array | string): Uint32Array {
+        const result = this.webm.call_raw('prefix-tokens', this.webm.pack.encode({ name: tokenizer, input }))
+        return new Uint32Array(result.buffer, result.byteOffset, result.byteLength / 4)
+    }
+
+    public encode(tokenizer: TokenizerRef, input: string, special_tokens = true, options: SpecialTokenOptions = {}): Uint32Array {
         const result = this.webm.call_raw(
             'encode',
             this.webm.pack.encode({
                 name: tokenizer,
                 input,
                 special_tokens,
+                ...options,
             })
         )
         return new Uint32Array(result.buffer, result.byteOffset, result.byteLength / 4)
     }
 
-    public decode(tokenizer: string, input: Uint32Array, special_tokens = true): string {
+    public decode(tokenizer: TokenizerRef, input: Uint32Array, special_tokens = true, options: DecodeOptions = {}): string {
         const result = this.webm.call_raw(
             'decode',
             this.webm.pack.encode({
                 name: tokenizer,
                 input: new Uint8Array(input.buffer),
                 special_tokens,
+                ...options,
             })
         )
         return new TextDecoder().decode(result)
     }
+
+    public count(tokenizer: TokenizerRef, input: string, special_tokens = true, options: SpecialTokenOptions = {}): number {
+        const result = this.webm.call<number>('count-tokens', {
+            name: tokenizer,
+            input,
+            special_tokens,
+            ...options,
+        })
+        return result ?? 0
+    }
+
+    /**
+     * Truncates `input` to at most `max_tokens` tokens. The returned text always encodes to exactly
+     * the returned tokens and is never cut inside of a character.
+     */
+    public truncate(tokenizer: TokenizerRef, input: string, max_tokens: number, special_tokens = true, options: TruncateOptions = {}): Truncation {
+        const result = this.webm.call<TruncateOutput>('truncate', {
+            name: tokenizer,
+            input,
+            max_tokens,
+            special_tokens,
+            ...options,
+        })
+        if (!result) {
+            throw new Error('Unexpected empty result')
+        }
+        return {
+            tokens: toUint32Array(result.tokens),
+            text: result.text,
+            truncated: result.truncated,
+        }
+    }
+
+    /**
+     * Splits `input` into chunks of at most `max_tokens` tokens, preferring to break at paragraph,
+     * sentence or whitespace boundaries. The `utf16` ranges can be used with `String.prototype.slice`.
+     */
+    public chunk(tokenizer: TokenizerRef, input: string, max_tokens: number, special_tokens = true, options: ChunkOptions = {}): TextChunk[] {
+        return (
+            this.webm.call<TextChunk[]>('chunk', {
+                name: tokenizer,
+                input,
+                max_tokens,
+                special_tokens,
+                ...options,
+            }) ?? []
+        )
+    }
+
+    /**
+     * Renders and encodes chat messages, counting the tokens of every message including its
+     * formatting overhead and the tokens priming the reply.
+     */
+    public renderChat(tokenizer: TokenizerRef, messages: ChatMessage[], format?: ChatFormat, prime_reply = true): RenderedChat {
+        const result = this.webm.call<RenderedChatOutput>('render-chat', {
+            name: tokenizer,
+            messages: messages.map((x) => ({ role: x.role, name: x.name ?? null, content: x.content })),
+            format: format ?? null,
+            prime_reply,
+        })
+        if (!result) {
+            throw new Error('Unexpected empty result')
+        }
+        return { ...result, tokens: toUint32Array(result.tokens) }
+    }
+
+    /**
+     * Renders chat messages with a Jinja chat template and encodes the prompt, by default using
+     * the template from the config of a Hugging Face tokenizer.
+     */
+    public applyChatTemplate(tokenizer: TokenizerRef, messages: ChatMessage[], options: ChatTemplateOptions = {}): ChatPrompt {
+        const result = this.webm.call<ChatPromptOutput>('apply-chat-template', {
+            name: tokenizer,
+            messages: messages.map((x) => ({ role: x.role, name: x.name ?? null, content: x.content })),
+            chat_template: options.chat_template ?? null,
+            add_generation_prompt: options.add_generation_prompt ?? false,
+        })
+        if (!result) {
+            throw new Error('Unexpected empty result')
+        }
+        return { ...result, tokens: toUint32Array(result.tokens) }
+    }
+
+    public countToolTokens(tokenizer: TokenizerRef, tools: ToolDefinition[], system_message = false): ToolTokens {
+        const result = this.webm.call<ToolTokens>('count-tool-tokens', {
+            name: tokenizer,
+            tools,
+            system_message,
+        })
+        if (!result) {
+            throw new Error('Unexpected empty result')
+        }
+        return result
+    }
+
+    public countBatch(tokenizer: TokenizerRef, input: string[], special_tokens = true, options: SpecialTokenOptions = {}): number[] {
+        const result = this.webm.call<number[]>('count-tokens-batch', {
+            name: tokenizer,
+            input,
+            special_tokens,
+            ...options,
+        })
+        return result ?? []
+    }
+
+    public encodeWithOffsets(tokenizer: TokenizerRef, input: string, special_tokens = true, utf16 = true, options: SpecialTokenOptions = {}): EncodingOffsets {
+        const result = this.webm.call<EncodeOffsetsOutput>('encode-with-offsets', {
+            name: tokenizer,
+            input,
+            special_tokens,
+            ...options,
+            utf16,
+        })
+        if (!result) {
+            throw new Error('Unexpected empty result')
+        }
+        return {
+            tokens: toUint32Array(result.tokens),
+            byteOffsets: toUint32Array(result.byte_offsets),
+            charOffsets: toUint32Array(result.char_offsets),
+            utf16Offsets: result.utf16_offsets ? toUint32Array(result.utf16_offsets) : undefined,
+        }
+    }
+
+    /**
+     * Encodes a batch of texts, optionally paired, into the inputs of a Hugging Face model, with
+     * truncation and padding applied like `transformers` does.
+     */
+    public encodeWithMasks(tokenizer: TokenizerRef, input: string[], special_tokens = true, options: EncodeMasksOptions = {}): MaskedEncoding[] {
+        const result = this.webm.call<MaskedEncodingOutput[]>('encode-with-masks', {
+            name: tokenizer,
+            input,
+            special_tokens,
+            ...options,
+        })
+        const toWindow = (x: MaskedEncodingOutput): EncodingWindow => ({
+            tokens: toUint32Array(x.tokens),
+            type_ids: toUint32Array(x.type_ids),
+            attention_mask: toUint32Array(x.attention_mask),
+            special_tokens_mask: toUint32Array(x.special_tokens_mask),
+        })
+        return (result ?? []).map((x) => ({ ...toWindow(x), overflowing: x.overflowing.map(toWindow) }))
+    }
+
+    public encodeWithUnstable(tokenizer: TokenizerRef, input: string, special_tokens = true, options: SpecialTokenOptions = {}): UnstableEncoding {
+        const result = this.webm.call<EncodeUnstableOutput>('encode-with-unstable', {
+            name: tokenizer,
+            input,
+            special_tokens,
+            ...options,
+        })
+        if (!result) {
+            throw new Error('Unexpected empty result')
+        }
+        return {
+            tokens: toUint32Array(result.tokens),
+            completions: result.completions.map(toUint32Array),
+        }
+    }
+
+    public encodeWithHealing(tokenizer: TokenizerRef, input: string, special_tokens = true, options: SpecialTokenOptions = {}): HealedEncoding {
+        const result = this.webm.call<EncodeHealingOutput>('encode-with-healing', {
+            name: tokenizer,
+            input,
+            special_tokens,
+            ...options,
+        })
+        if (!result) {
+            throw new Error('Unexpected empty result')
+        }
+        return {
+            tokens: toUint32Array(result.tokens),
+            prefix: new Uint8Array(result.prefix),
+            allowed: result.allowed ? toUint32Array(result.allowed) : null,
+        }
+    }
+
+    public encodeBatch(tokenizer: TokenizerRef, input: string[], special_tokens = true, options: SpecialTokenOptions = {}): Array<Uint32Array | Error> {
+        const result = this.webm.call<BatchOutput[]>('encode-batch', {
+            name: tokenizer,
+            input,
+            special_tokens,
+            ...options,
+        })
+        return (result ?? []).map((x) => {
+            if ('error' in x) {
+                return new TokenizerError(x)
+            }
+            return toUint32Array((x as { tokens: Uint8Array }).tokens)
+        })
+    }
+
+    public decodeBatch(tokenizer: TokenizerRef, input: Uint32Array[], special_tokens = true, options: DecodeOptions = {}): Array<string | Error> {
+        const result = this.webm.call<BatchOutput[]>('decode-batch', {
+            name: tokenizer,
+            input: input.map((x) => new Uint8Array(x.buffer, x.byteOffset, x.byteLength)),
+            special_tokens,
+            ...options,
+        })
+        return (result ?? []).map((x) => {
+            if ('error' in x) {
+                return new TokenizerError(x)
+            }
+            return (x as { text: string }).text
+        })
+    }
+
+    public createDecoder(tokenizer: TokenizerRef, special_tokens = true, options: DecodeOptions = {}): Decoder {
+        const id = this.webm.call<number>('decoder-create', {
+            name: tokenizer,
+            special_tokens,
+            ...options,
+        })
+        if (id === null) {
+            throw new Error('Unexpected empty result')
+        }
+        return new Decoder(this.webm, id)
+    }
 }
@@ -19,6 +19,24 @@ class ConsoleOutput extends Fd {
     }
 }
 
+export interface ErrorOutput {
+    error: { kind: string; [key: string]: unknown }
+    message: string
+}
+
+export class TokenizerError extends Error {
+    readonly kind: string
+    readonly context: Record<string, unknown>
+
+    constructor(output: ErrorOutput) {
+        super(output.message)
+        this.name = 'TokenizerError'
+        const { kind, ...context } = output.error
+        this.kind = kind
+        this.context = context
+    }
+}
+
 export class WebModule {
     readonly wasi: WASI
     readonly wasm: WebAssembly.Module
@@ -84,7 +102,7 @@ export class WebModule {
             }
             return this.pack.decode(result) as T
         } else {
-            throw new Error(new TextDecoder().decode(result))
+            throw this.read_error(result)
         }
     }
 
@@ -103,7 +121,39 @@ export class WebModule {
         if (success) {
             return result
         } else {
-            throw new Error(new TextDecoder().decode(result))
+            throw this.read_error(result)
+        }
+    }
+
+    public call_u32(name: string, input: object | string): number {
+        const func = this.inst.exports[name] as ((ptr: number, len: number) => number) | undefined
+        if (!func) {
+            throw new Error(`Function ${name} not found`)
+        }
+        const [inputPtr, inputLen] = typeof input === 'string' ? this.allocate(input) : this.allocate_raw(this.pack.encode(input))
+        const output = func(inputPtr, inputLen)
+        // The value is stored in place of the pointer to the error
+        const view = new DataView(this.memory.buffer)
+        const success = view.getInt32(output, true) === 0
+        const value = view.getUint32(output + 4, true)
+        const error = success ? null : this.read_output(output)[0]
+        const postFunc = this.inst.exports[`cabi_post_${name}`] as ((ptr: number) => void) | undefined
+        if (postFunc) {
+            postFunc(output)
+        }
+        if (error) {
+            throw this.read_error(error)
+        }
+        return value
+    }
+
+    // Errors are strings holding the JSON serialized ErrorOutput
+    private read_error(error: Uint8Array): Error {
+        const message = new TextDecoder().decode(error)
+        try {
+            return new TokenizerError(JSON.parse(message) as ErrorOutput)
+        } catch {
+            return new Error(message)
         }
     }
 
@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    EncodeOptions, TokenizerVariant,
+    error::TokenizerError,
+    template::{Template, Value},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role:    String,
+    pub name:    Option<String>,
+    pub content: String,
+}
+
+/// Layout of a rendered chat prompt, defaulting to ChatML.
+///
+/// Every message is rendered as `{message_prefix}{role}[{name_separator}{name}]{role_separator}
+/// {content}{message_suffix}`, followed by the `reply_prefix` that primes the reply.
+///
+/// The markers are billed at the fixed `tokens_per_message`, `tokens_per_name` and
+/// `reply_tokens`, since encodings like the `cl100k_base` preset don't include the special tokens
+/// the API renders them with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChatFormat {
+    pub message_prefix:     String,
+    pub name_separator:     String,
+    pub role_separator:     String,
+    pub message_suffix:     String,
+    pub reply_prefix:       String,
+    pub tokens_per_message: u32,
+    pub tokens_per_name:    u32,
+    pub reply_tokens:       u32,
+}
+
+impl Default for ChatFormat {
+    fn default() -> Self {
+        Self {
+            message_prefix:     "<|im_start|>".to_string(),
+            name_separator:     ":".to_string(),
+            role_separator:     "\n".to_string(),
+            message_suffix:     "<|im_end|>".to_string(),
+            reply_prefix:       "<|im_start|>assistant\n".to_string(),
+            tokens_per_message: 3,
+            tokens_per_name:    1,
+            reply_tokens:       3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedChat {
+    pub text:           String,
+    pub tokens:         Vec<u32>,
+    // Billed tokens of every message including its formatting overhead
+    pub message_tokens: Vec<usize>,
+    pub reply_tokens:   usize,
+}
+
+/// Renders and encodes `messages` and counts their tokens the way chat completions are billed.
+///
+/// The parts coming from `format` are encoded with special tokens, while roles, names and content
+/// are encoded separately as ordinary text, so neither can merge with the formatting around them.
+/// For tiktoken encodings special tokens in messages stay plain text, Hugging Face tokenizers
+/// always match their added tokens and encode them as such in messages too. The counts add the
+/// fixed overheads of `format` to the tokens of the roles, names and content instead of counting
+/// the rendered markers.
+pub fn render_chat(
+    tokenizer: &TokenizerVariant, messages: &[ChatMessage], format: &ChatFormat, prime_reply: bool,
+) -> Result<RenderedChat, TokenizerError> {
+    let mut rendered = RenderedChat {
+        text:           String::new(),
+        tokens:         vec![],
+        message_tokens: Vec::with_capacity(messages.len()),
+        reply_tokens:   0,
+    };
+    for message in messages {
+        let mut billed = format.tokens_per_message as usize;
+        rendered.push(tokenizer, &format.message_prefix, true)?;
+        billed += rendered.push(tokenizer, &message.role, false)?;
+        if let Some(name) = &message.name {
+            rendered.push(tokenizer, &format.name_separator, true)?;
+            billed += format.tokens_per_name as usize + rendered.push(tokenizer, name, false)?;
+        }
+        rendered.push(tokenizer, &format.role_separator, true)?;
+        billed += rendered.push(tokenizer, &message.content, false)?;
+        rendered.push(tokenizer, &format.message_suffix, true)?;
+        rendered.message_tokens.push(billed);
+    }
+    if prime_reply {
+        rendered.push(tokenizer, &format.reply_prefix, true)?;
+        rendered.reply_tokens = format.reply_tokens as usize;
+    }
+    Ok(rendered)
+}
+
+impl RenderedChat {
+    /// Appends `text` and returns the number of tokens it was encoded to.
+    fn push(
+        &mut self, tokenizer: &TokenizerVariant, text: &str, special: bool,
+    ) -> Result<usize, TokenizerError> {
+        if text.is_empty() {
+            return Ok(0);
+        }
+        // Hugging Face tokenizers match added tokens regardless, enabling special tokens there
+        // would only add the post-processor tokens to every part
+        let special = special && matches!(tokenizer, TokenizerVariant::TokenizerTiktoken(_));
+        let tokens = tokenizer.encode(text, &EncodeOptions::new(Some(special), None, None))?;
+        let count = tokens.len();
+        self.tokens.extend(tokens);
+        self.text.push_str(text);
+        Ok(count)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatPrompt {
+    pub text:   String,
+    pub tokens: Vec<u32>,
+}
+
+/// Renders `messages` with a Jinja chat template and encodes the resulting prompt.
+///
+/// Without `chat_template` the default template from the config of a Hugging Face tokenizer is
+/// used, otherwise it names one of the templates from the config or is the template itself.
+pub fn apply_chat_template(
+    tokenizer: &TokenizerVariant, messages: &[ChatMessage], chat_template: Option<&str>,
+    add_generation_prompt: bool,
+) -> Result<ChatPrompt, TokenizerError> {
+    let config = match tokenizer {
+        TokenizerVariant::TokenizerHuggingface(tokenizer) => Some(&tokenizer.config),
+        TokenizerVariant::TokenizerTiktoken(_) => None,
+    };
+    let source = match (config, chat_template) {
+        (Some(config), Some(name)) => config.chat_template(Some(name)).unwrap_or(name),
+        (None, Some(template)) => template,
+        (Some(config), None) => config.chat_template(None).ok_or_else(|| {
+            TokenizerError::unsupported("The tokenizer config has no chat template")
+        })?,
+        (None, None) => {
+            return Err(TokenizerError::unsupported(
+                "Tiktoken tokenizers have no chat template, one has to be passed explicitly",
+            ));
+        }
+    };
+    let template = Template::parse(source)?;
+
+    let messages = messages
+        .iter()
+        .map(|message| {
+            let mut fields = vec![
+                ("role".to_string(), Value::from(message.role.as_str())),
+                ("content".to_string(), Value::from(message.content.as_str())),
+            ];
+            if let Some(name) = &message.name {
+                fields.push(("name".to_string(), Value::from(name.as_str())));
+            }
+            Value::from(fields)
+        })
+        .collect::<Vec<_>>();
+    let mut variables = vec![
+        ("messages".to_string(), Value::from(messages)),
+        ("add_generation_prompt".to_string(), Value::from(add_generation_prompt)),
+    ];
+    for (name, token) in config.map(|x| x.special_tokens()).unwrap_or_default() {
+        variables.push((name.to_string(), Value::from(token)));
+    }
+    let text = template.render(variables)?;
+
+    // The template places the special tokens itself, so the post-processor must not add them
+    let special = matches!(tokenizer, TokenizerVariant::TokenizerTiktoken(_));
+    let tokens = tokenizer.encode(&text, &EncodeOptions::new(Some(special), None, None))?;
+    Ok(ChatPrompt { text, tokens })
+}
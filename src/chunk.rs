@@ -0,0 +1,117 @@
+use crate::{error::TokenizerError, EncodeOptions, TokenizerVariant};
+
+/// How natural a position is to split the text at, in increasing order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Boundary {
+    Token,
+    Whitespace,
+    Sentence,
+    Paragraph,
+}
+
+/// Chunk as ranges into the tokens and bytes of the full text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub tokens: (usize, usize),
+    pub bytes:  (usize, usize),
+}
+
+/// Splits `text` into chunks of at most `max_tokens` tokens, where consecutive chunks share up to
+/// `overlap` tokens.
+///
+/// Each chunk ends at the most natural boundary within the last `slack` tokens before the limit,
+/// preferring paragraph over sentence over whitespace boundaries and later over earlier ones.
+pub fn chunk(
+    tokenizer: &TokenizerVariant, text: &str, max_tokens: usize, overlap: usize, slack: usize,
+    options: &EncodeOptions,
+) -> Result<Vec<Chunk>, TokenizerError> {
+    if max_tokens == 0 || overlap >= max_tokens {
+        return Err(TokenizerError::malformed_input(format!(
+            "Chunks of {} tokens can't overlap by {} tokens",
+            max_tokens, overlap
+        )));
+    }
+    let (tokens, offsets) = tokenizer.encode_with_offsets(text, options)?;
+    let positions = token_positions(text, &offsets);
+    let splits = match tokenizer {
+        TokenizerVariant::TokenizerTiktoken(tokenizer) => tokenizer.split_positions(text)?,
+        TokenizerVariant::TokenizerHuggingface(_) => positions.clone(),
+    };
+    let boundary = |index: usize| {
+        let position = positions[index];
+        if index == tokens.len() {
+            return Some(Boundary::Paragraph);
+        }
+        if !text.is_char_boundary(position) {
+            return None;
+        }
+        if splits.binary_search(&position).is_err() {
+            return Some(Boundary::Token);
+        }
+        Some(classify(text, position))
+    };
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < tokens.len() {
+        let limit = (start + max_tokens).min(tokens.len());
+        let end = if limit == tokens.len() {
+            limit
+        } else {
+            let earliest = limit.saturating_sub(slack).max(start + overlap + 1);
+            (earliest..=limit)
+                .filter_map(|end| Some((boundary(end)?, end)))
+                .max()
+                .map(|(_, end)| end)
+                .or_else(|| (start + 1..limit).rev().find(|end| boundary(*end).is_some()))
+                .unwrap_or(limit)
+        };
+        chunks.push(Chunk {
+            tokens: (start, end),
+            bytes:  (if start == 0 { 0 } else { positions[start] }, positions[end]),
+        });
+        if end == tokens.len() {
+            break;
+        }
+        // Start the overlap on a character boundary, shrinking it where necessary
+        start = (end.saturating_sub(overlap).max(start + 1)..end)
+            .find(|start| text.is_char_boundary(positions[*start]))
+            .unwrap_or(end);
+    }
+    Ok(chunks)
+}
+
+/// Start position of every token in `text`, followed by the end of the text.
+///
+/// Tokens without a span of their own, like those added by a post-processor, are placed at the
+/// end of the previous token, so the positions never decrease.
+fn token_positions(text: &str, offsets: &[(usize, usize)]) -> Vec<usize> {
+    let mut positions = Vec::with_capacity(offsets.len() + 1);
+    let mut last = 0;
+    for (start, end) in offsets {
+        if start < end {
+            last = last.max(*start);
+        }
+        positions.push(last);
+        last = last.max(*end);
+    }
+    positions.push(text.len());
+    positions
+}
+
+fn classify(text: &str, position: usize) -> Boundary {
+    let before = &text[..position];
+    let after = &text[position..];
+    let space_before = before.len() - before.trim_end().len();
+    let space_after = after.len() - after.trim_start().len();
+    let space = &text[position - space_before..position + space_after];
+    if space.matches('\n').count() >= 2 {
+        Boundary::Paragraph
+    } else if space.is_empty() {
+        Boundary::Token
+    } else if before.trim_end().ends_with(['.', '!', '?']) {
+        Boundary::Sentence
+    } else {
+        Boundary::Whitespace
+    }
+}
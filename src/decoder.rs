@@ -0,0 +1,133 @@
+use crate::{error::TokenizerError, DecodeOptions, TokenizerVariant};
+
+/// Incremental decoder that only ever returns complete UTF-8 text.
+#[derive(Debug)]
+pub struct StreamDecoder {
+    pub tokenizer: u32,
+    options:       DecodeOptions,
+    // Undecoded bytes of an incomplete UTF-8 sequence (tiktoken)
+    buffer:        Vec<u8>,
+    // Tokens that are still needed as context for decoding (huggingface)
+    tokens:        Vec<u32>,
+    prefix_offset: usize,
+    read_offset:   usize,
+}
+
+impl StreamDecoder {
+    pub fn new(tokenizer: u32, options: DecodeOptions) -> Self {
+        Self {
+            tokenizer,
+            options,
+            buffer: Vec::new(),
+            tokens: Vec::new(),
+            prefix_offset: 0,
+            read_offset: 0,
+        }
+    }
+
+    pub fn push(
+        &mut self, tokenizer: &TokenizerVariant, tokens: &[u32],
+    ) -> Result<String, TokenizerError> {
+        match tokenizer {
+            TokenizerVariant::TokenizerTiktoken(_) => {
+                self.buffer.extend(tokenizer.decode(tokens, &self.options)?);
+                Ok(self.take_complete())
+            }
+            TokenizerVariant::TokenizerHuggingface(_) => {
+                let mut ret = String::new();
+                for token in tokens {
+                    self.tokens.push(*token);
+                    ret.push_str(&self.step(tokenizer)?);
+                }
+                Ok(ret)
+            }
+        }
+    }
+
+    pub fn flush(&mut self, tokenizer: &TokenizerVariant) -> Result<String, TokenizerError> {
+        let ret = match tokenizer {
+            TokenizerVariant::TokenizerTiktoken(_) => {
+                Ok(String::from_utf8_lossy(&self.buffer).into_owned())
+            }
+            TokenizerVariant::TokenizerHuggingface(_) => {
+                self.decode_window(tokenizer).and_then(|(prefix_text, new_text)| {
+                    Ok(new_part(&prefix_text, &new_text)?.to_string())
+                })
+            }
+        };
+        // Reset even on errors, so the decoder can be used again afterwards
+        self.buffer.clear();
+        self.tokens.clear();
+        self.prefix_offset = 0;
+        self.read_offset = 0;
+        ret
+    }
+
+    fn take_complete(&mut self) -> String {
+        let mut ret = String::new();
+        loop {
+            match std::str::from_utf8(&self.buffer) {
+                Ok(text) => {
+                    ret.push_str(text);
+                    self.buffer.clear();
+                    break;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    ret.push_str(&String::from_utf8_lossy(&self.buffer[..valid]));
+                    match e.error_len() {
+                        // Invalid sequence that can't be completed by more bytes
+                        Some(len) => {
+                            ret.push(char::REPLACEMENT_CHARACTER);
+                            self.buffer.drain(..valid + len);
+                        }
+                        // Incomplete sequence at the end, wait for more bytes
+                        None => {
+                            self.buffer.drain(..valid);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    fn decode_window(
+        &self, tokenizer: &TokenizerVariant,
+    ) -> Result<(String, String), TokenizerError> {
+        // Decoders like ByteLevel and Metaspace change the output depending on the surrounding
+        // tokens, so the new text is determined by decoding with the previous tokens as prefix
+        let prefix =
+            tokenizer.decode(&self.tokens[self.prefix_offset..self.read_offset], &self.options)?;
+        let text = tokenizer.decode(&self.tokens[self.prefix_offset..], &self.options)?;
+        Ok((
+            String::from_utf8_lossy(&prefix).into_owned(),
+            String::from_utf8_lossy(&text).into_owned(),
+        ))
+    }
+
+    fn step(&mut self, tokenizer: &TokenizerVariant) -> Result<String, TokenizerError> {
+        let (prefix_text, new_text) = self.decode_window(tokenizer)?;
+        if new_text.len() <= prefix_text.len() || new_text.ends_with(char::REPLACEMENT_CHARACTER) {
+            return Ok(String::new());
+        }
+        let ret = new_part(&prefix_text, &new_text).map(str::to_string);
+        // Moves on even past a window that failed, which would otherwise fail again on every step
+        self.tokens.drain(..self.prefix_offset);
+        self.prefix_offset = self.read_offset - self.prefix_offset;
+        self.read_offset = self.tokens.len();
+        ret
+    }
+}
+
+/// The text decoded after the prefix. Fails instead of dropping text when decoding more tokens
+/// changed the text already returned.
+fn new_part<'a>(prefix_text: &str, new_text: &'a str) -> Result<&'a str, TokenizerError> {
+    new_text.strip_prefix(prefix_text).ok_or_else(|| {
+        TokenizerError::tokenizer_failed(format!(
+            "Decoded text {:?} doesn't continue the previously decoded {:?}",
+            new_text, prefix_text
+        ))
+    })
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::typed;
+
+/// Error returned from every export, mirrored by the `tokenizer-error` variant in the WIT.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum TokenizerError {
+    TokenizerNotFound { name: String },
+    HandleNotFound { handle: u32 },
+    UnknownModel { model: String },
+    DecoderNotFound { decoder: u32 },
+    InvalidUtf8 { field: String },
+    InvalidTokenIds { tokens: Vec<InvalidToken> },
+    MalformedInput { message: String },
+    LoadFailed { message: String },
+    RegexError { message: String },
+    DisallowedSpecialToken { token: String, position: u32 },
+    Unsupported { message: String },
+    TokenizerFailed { message: String },
+    TemplateError { message: String },
+}
+
+/// Token id that is not part of the vocabulary, with its position in the decoded sequence.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InvalidToken {
+    pub position: u32,
+    pub token:    u32,
+}
+
+impl TokenizerError {
+    /// Collects every token in `tokens` rejected by `is_valid`.
+    pub fn invalid_token_ids(tokens: &[u32], is_valid: impl Fn(u32) -> bool) -> Option<Self> {
+        let tokens = tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, token)| !is_valid(**token))
+            .map(|(position, token)| InvalidToken {
+                position: position as u32,
+                token:    *token,
+            })
+            .collect::<Vec<_>>();
+        (!tokens.is_empty()).then_some(Self::InvalidTokenIds { tokens })
+    }
+
+    pub fn malformed_input(message: impl ToString) -> Self {
+        Self::MalformedInput {
+            message: message.to_string(),
+        }
+    }
+
+    pub fn load_failed(message: impl ToString) -> Self {
+        Self::LoadFailed {
+            message: message.to_string(),
+        }
+    }
+
+    pub fn regex_error(message: impl ToString) -> Self {
+        Self::RegexError {
+            message: message.to_string(),
+        }
+    }
+
+    pub fn unsupported(message: impl ToString) -> Self {
+        Self::Unsupported {
+            message: message.to_string(),
+        }
+    }
+
+    pub fn tokenizer_failed(message: impl ToString) -> Self {
+        Self::TokenizerFailed {
+            message: message.to_string(),
+        }
+    }
+
+    pub fn template_error(message: impl ToString) -> Self {
+        Self::TemplateError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for TokenizerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TokenizerNotFound { name } => write!(f, "Tokenizer not found: {}", name),
+            Self::HandleNotFound { handle } => write!(f, "Tokenizer handle not found: {}", handle),
+            Self::UnknownModel { model } => write!(f, "No encoding known for model {:?}", model),
+            Self::DecoderNotFound { decoder } => write!(f, "Decoder not found: {}", decoder),
+            Self::InvalidUtf8 { field } => write!(f, "Invalid UTF-8 in {}", field),
+            Self::InvalidTokenIds { tokens } => {
+                write!(f, "Invalid token ids:")?;
+                for (i, invalid) in tokens.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { "," };
+                    write!(f, "{} {} at position {}", separator, invalid.token, invalid.position)?;
+                }
+                Ok(())
+            }
+            Self::MalformedInput { message } => write!(f, "Malformed input: {}", message),
+            Self::LoadFailed { message } => write!(f, "Failed to load tokenizer: {}", message),
+            Self::RegexError { message } => write!(f, "Regex error: {}", message),
+            Self::DisallowedSpecialToken { token, position } => write!(
+                f,
+                "Encountered disallowed special token {:?} at position {}",
+                token, position
+            ),
+            Self::Unsupported { message } => write!(f, "{}", message),
+            Self::TokenizerFailed { message } => write!(f, "Tokenizer failed: {}", message),
+            Self::TemplateError { message } => write!(f, "Template error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for TokenizerError {}
+
+
+/// Error as serialized for the blob exports, carrying the human readable message alongside.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorOutput {
+    pub error:   TokenizerError,
+    pub message: String,
+}
+
+impl From<TokenizerError> for ErrorOutput {
+    fn from(error: TokenizerError) -> Self {
+        let message = error.to_string();
+        Self { error, message }
+    }
+}
+
+/// Blob exports return their errors as strings like they always did, holding the JSON
+/// serialized `ErrorOutput` so the kind of error can be told apart.
+impl From<TokenizerError> for String {
+    fn from(error: TokenizerError) -> Self {
+        // Serializing a plain enum can't fail, the fallback only exists to avoid a panic
+        let output = ErrorOutput::from(error);
+        serde_json::to_string(&output).unwrap_or(output.message)
+    }
+}
+
+impl From<TokenizerError> for typed::TokenizerError {
+    fn from(error: TokenizerError) -> Self {
+        match error {
+            TokenizerError::TokenizerNotFound { name } => Self::TokenizerNotFound(name),
+            TokenizerError::HandleNotFound { handle } => Self::HandleNotFound(handle),
+            TokenizerError::UnknownModel { model } => Self::UnknownModel(model),
+            TokenizerError::DecoderNotFound { decoder } => Self::DecoderNotFound(decoder),
+            TokenizerError::InvalidUtf8 { field } => Self::InvalidUtf8(field),
+            TokenizerError::InvalidTokenIds { tokens } => Self::InvalidTokenIds(
+                tokens
+                    .into_iter()
+                    .map(|x| typed::InvalidToken {
+                        position: x.position,
+                        token:    x.token,
+                    })
+                    .collect(),
+            ),
+            TokenizerError::MalformedInput { message } => Self::MalformedInput(message),
+            TokenizerError::LoadFailed { message } => Self::LoadFailed(message),
+            TokenizerError::RegexError { message } => Self::RegexError(message),
+            TokenizerError::DisallowedSpecialToken { token, position } => {
+                Self::DisallowedSpecialToken(typed::SpecialTokenMatch { token, position })
+            }
+            TokenizerError::Unsupported { message } => Self::Unsupported(message),
+            TokenizerError::TokenizerFailed { message } => Self::TokenizerFailed(message),
+            TokenizerError::TemplateError { message } => Self::TemplateError(message),
+        }
+    }
+}
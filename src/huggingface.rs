@@ -0,0 +1,351 @@
+use std::{borrow::Cow, ops::Deref};
+
+use serde::{Deserialize, Serialize};
+use tokenizers::{
+    Encoding, PaddingDirection, PaddingParams, PostProcessor, Tokenizer, TruncationParams,
+    utils::{padding::pad_encodings, truncation::truncate_encodings},
+};
+
+use crate::{error::TokenizerError, vocab::hf_sorted_token_bytes};
+
+/// Special token as written to `tokenizer_config.json`, either plain or as a serialized
+/// `AddedToken`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+enum ConfigToken {
+    Content(String),
+    AddedToken { content: String },
+}
+
+impl ConfigToken {
+    fn content(&self) -> &str {
+        match self {
+            ConfigToken::Content(content) | ConfigToken::AddedToken { content } => content,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+struct NamedTemplate {
+    name:     String,
+    template: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+enum ChatTemplates {
+    Template(String),
+    Named(Vec<NamedTemplate>),
+}
+
+// `transformers` marks an unbounded length with `int(1e30)`
+const UNBOUNDED_MAX_LENGTH: f64 = 1e30;
+
+/// The parts of `tokenizer_config.json` and `special_tokens_map.json` that `tokenizer.json`
+/// doesn't carry.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct TokenizerConfig {
+    chat_template:             Option<ChatTemplates>,
+    bos_token:                 Option<ConfigToken>,
+    eos_token:                 Option<ConfigToken>,
+    unk_token:                 Option<ConfigToken>,
+    pad_token:                 Option<ConfigToken>,
+    sep_token:                 Option<ConfigToken>,
+    cls_token:                 Option<ConfigToken>,
+    mask_token:                Option<ConfigToken>,
+    additional_special_tokens: Option<Vec<ConfigToken>>,
+    model_max_length:          Option<f64>,
+    pub add_bos_token:         Option<bool>,
+    pub add_eos_token:         Option<bool>,
+}
+
+impl TokenizerConfig {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TokenizerError> {
+        serde_json::from_slice(bytes).map_err(TokenizerError::load_failed)
+    }
+
+    /// Applies the special tokens from `special_tokens_map.json`, which take precedence over the
+    /// ones in `tokenizer_config.json` like they do in `transformers`.
+    pub fn with_special_tokens_map(mut self, bytes: &[u8]) -> Result<Self, TokenizerError> {
+        let map = Self::from_bytes(bytes)?;
+        let tokens = [
+            (&mut self.bos_token, map.bos_token),
+            (&mut self.eos_token, map.eos_token),
+            (&mut self.unk_token, map.unk_token),
+            (&mut self.pad_token, map.pad_token),
+            (&mut self.sep_token, map.sep_token),
+            (&mut self.cls_token, map.cls_token),
+            (&mut self.mask_token, map.mask_token),
+        ];
+        for (token, mapped) in tokens {
+            if mapped.is_some() {
+                *token = mapped;
+            }
+        }
+        if map.additional_special_tokens.is_some() {
+            self.additional_special_tokens = map.additional_special_tokens;
+        }
+        Ok(self)
+    }
+
+    /// Maximum number of tokens the model accepts, if it is bounded.
+    pub fn max_length(&self) -> Option<u32> {
+        self.model_max_length
+            .filter(|x| *x >= 0.0 && *x < UNBOUNDED_MAX_LENGTH.min(u32::MAX as f64))
+            .map(|x| x as u32)
+    }
+
+    /// The chat template called `name`, or the default template without a name.
+    pub fn chat_template(&self, name: Option<&str>) -> Option<&str> {
+        match (&self.chat_template, name) {
+            (Some(ChatTemplates::Template(template)), None) => Some(template),
+            (Some(ChatTemplates::Template(_)), Some(_)) => None,
+            (Some(ChatTemplates::Named(templates)), name) => templates
+                .iter()
+                .find(|x| x.name == name.unwrap_or("default"))
+                .map(|x| x.template.as_str()),
+            (None, _) => None,
+        }
+    }
+
+    /// Special tokens by their role, named like chat templates refer to them.
+    pub fn special_tokens(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("bos_token", &self.bos_token),
+            ("eos_token", &self.eos_token),
+            ("unk_token", &self.unk_token),
+            ("pad_token", &self.pad_token),
+            ("sep_token", &self.sep_token),
+            ("cls_token", &self.cls_token),
+            ("mask_token", &self.mask_token),
+        ]
+        .into_iter()
+        .filter_map(|(name, token)| Some((name, token.as_ref()?.content())))
+        .collect()
+    }
+
+    pub fn pad_token(&self) -> Option<&str> { self.pad_token.as_ref().map(ConfigToken::content) }
+
+    pub fn additional_special_tokens(&self) -> Vec<&str> {
+        self.additional_special_tokens
+            .iter()
+            .flatten()
+            .map(ConfigToken::content)
+            .collect()
+    }
+}
+
+/// Hugging Face tokenizer along with the configuration loaded next to it.
+#[derive(Debug)]
+pub struct HuggingfaceTokenizer {
+    tokenizer:          Tokenizer,
+    pub config:         TokenizerConfig,
+    // Raw bytes of the non-special tokens, see `hf_sorted_token_bytes`
+    sorted_token_bytes: Vec<(Vec<u8>, u32)>,
+}
+
+impl HuggingfaceTokenizer {
+    pub fn new(tokenizer: Tokenizer, config: TokenizerConfig) -> Self {
+        let sorted_token_bytes = hf_sorted_token_bytes(&tokenizer);
+        Self {
+            tokenizer,
+            config,
+            sorted_token_bytes,
+        }
+    }
+
+    pub fn sorted_token_bytes(&self) -> &[(Vec<u8>, u32)] { &self.sorted_token_bytes }
+}
+
+impl From<Tokenizer> for HuggingfaceTokenizer {
+    fn from(tokenizer: Tokenizer) -> Self { Self::new(tokenizer, TokenizerConfig::default()) }
+}
+
+impl Deref for HuggingfaceTokenizer {
+    type Target = Tokenizer;
+
+    fn deref(&self) -> &Self::Target { &self.tokenizer }
+}
+
+/// Which sequence of a pair loses tokens first when truncating.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum TruncationStrategy {
+    #[default]
+    LongestFirst,
+    OnlyFirst,
+    OnlySecond,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PaddingStrategy {
+    // Pads to the longest encoding of the batch
+    Longest,
+    MaxLength,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PaddingSide {
+    Left,
+    #[default]
+    Right,
+}
+
+/// Truncation and padding applied to the encodings of a batch.
+#[derive(Debug, Clone, Default)]
+pub struct EncodingLayout {
+    // Enables truncation when set
+    pub max_length:         Option<usize>,
+    pub truncation:         TruncationStrategy,
+    // Tokens repeated from the previous window in overflowing encodings
+    pub stride:             usize,
+    pub padding:            Option<PaddingStrategy>,
+    pub pad_to_multiple_of: Option<usize>,
+    pub padding_side:       PaddingSide,
+    // Defaults to the pad token of the config
+    pub pad_token:          Option<String>,
+}
+
+/// Encoding with the masks models take as input, along with the windows cut off by truncation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaskedEncoding {
+    pub tokens:              Vec<u32>,
+    pub type_ids:            Vec<u32>,
+    pub attention_mask:      Vec<u32>,
+    pub special_tokens_mask: Vec<u32>,
+    pub overflowing:         Vec<MaskedEncoding>,
+}
+
+impl From<&Encoding> for MaskedEncoding {
+    fn from(encoding: &Encoding) -> Self {
+        Self {
+            tokens:              encoding.get_ids().to_vec(),
+            type_ids:            encoding.get_type_ids().to_vec(),
+            attention_mask:      encoding.get_attention_mask().to_vec(),
+            special_tokens_mask: encoding.get_special_tokens_mask().to_vec(),
+            overflowing:         encoding.get_overflowing().iter().map(Self::from).collect(),
+        }
+    }
+}
+
+impl HuggingfaceTokenizer {
+    /// Encodes `texts`, paired with `pairs` if given, truncating and padding them as a batch.
+    pub fn encode_with_masks(
+        &self, texts: &[String], pairs: Option<&[String]>, special_tokens: bool,
+        layout: &EncodingLayout,
+    ) -> Result<Vec<MaskedEncoding>, TokenizerError> {
+        if pairs.is_some_and(|pairs| pairs.len() != texts.len()) {
+            return Err(TokenizerError::malformed_input(
+                "Every text needs a pair when pairs are given",
+            ));
+        }
+        let truncation = match layout.max_length {
+            Some(max_length) => {
+                // The post-processor adds its tokens after truncation, they are part of the length
+                let added = match self.get_post_processor() {
+                    Some(processor) if special_tokens => processor.added_tokens(pairs.is_some()),
+                    _ => 0,
+                };
+                if max_length <= added + layout.stride {
+                    return Err(TokenizerError::malformed_input(format!(
+                        "Max length {} must exceed the stride of {} and {} special tokens",
+                        max_length, layout.stride, added
+                    )));
+                }
+                Some(TruncationParams {
+                    max_length: max_length - added,
+                    stride: layout.stride,
+                    strategy: match layout.truncation {
+                        TruncationStrategy::LongestFirst => {
+                            tokenizers::TruncationStrategy::LongestFirst
+                        }
+                        TruncationStrategy::OnlyFirst => tokenizers::TruncationStrategy::OnlyFirst,
+                        TruncationStrategy::OnlySecond => {
+                            tokenizers::TruncationStrategy::OnlySecond
+                        }
+                    },
+                    ..Default::default()
+                })
+            }
+            None => None,
+        };
+        let padding = match layout.padding {
+            Some(strategy) => {
+                let pad_token = layout
+                    .pad_token
+                    .as_deref()
+                    .or_else(|| self.config.pad_token())
+                    .or_else(|| self.get_padding().map(|x| x.pad_token.as_str()))
+                    .ok_or_else(|| {
+                        TokenizerError::malformed_input("Padding requires a pad token")
+                    })?;
+                let pad_id = self.token_to_id(pad_token).ok_or_else(|| {
+                    TokenizerError::malformed_input(format!(
+                        "Pad token {:?} is not part of the vocabulary",
+                        pad_token
+                    ))
+                })?;
+                Some(PaddingParams {
+                    strategy: match (strategy, layout.max_length) {
+                        (PaddingStrategy::Longest, _) => tokenizers::PaddingStrategy::BatchLongest,
+                        (PaddingStrategy::MaxLength, Some(max_length)) => {
+                            tokenizers::PaddingStrategy::Fixed(max_length)
+                        }
+                        (PaddingStrategy::MaxLength, None) => {
+                            return Err(TokenizerError::malformed_input(
+                                "Padding to the max length requires a max length",
+                            ));
+                        }
+                    },
+                    direction: match layout.padding_side {
+                        PaddingSide::Left => PaddingDirection::Left,
+                        PaddingSide::Right => PaddingDirection::Right,
+                    },
+                    pad_to_multiple_of: layout.pad_to_multiple_of.filter(|x| *x > 0),
+                    pad_id,
+                    pad_token: pad_token.to_string(),
+                    ..Default::default()
+                })
+            }
+            None => None,
+        };
+
+        // Both `encode` and `post_process` apply the truncation and padding from tokenizer.json,
+        // which would cut and pad the encodings before the layout is applied
+        let tokenizer = match self.get_truncation().is_some() || self.get_padding().is_some() {
+            true => {
+                let mut tokenizer = self.tokenizer.clone();
+                tokenizer.with_truncation(None).with_padding(None);
+                Cow::Owned(tokenizer)
+            }
+            false => Cow::Borrowed(&self.tokenizer),
+        };
+        // The layout is applied here in the tokenizer's order: truncate, add special tokens, then
+        // pad, so the special tokens are accounted for and the batch is padded as a whole
+        let encode =
+            |text: &str| tokenizer.encode(text, false).map_err(TokenizerError::tokenizer_failed);
+        let mut encodings = texts
+            .iter()
+            .enumerate()
+            .map(|(index, text)| {
+                let encoding = encode(text)?;
+                let pair = pairs.map(|pairs| encode(&pairs[index])).transpose()?;
+                let (encoding, pair) = match &truncation {
+                    Some(truncation) => truncate_encodings(encoding, pair, truncation)
+                        .map_err(TokenizerError::tokenizer_failed)?,
+                    None => (encoding, pair),
+                };
+                tokenizer
+                    .post_process(encoding, pair, special_tokens)
+                    .map_err(TokenizerError::tokenizer_failed)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(padding) = &padding {
+            pad_encodings(&mut encodings, padding).map_err(TokenizerError::tokenizer_failed)?;
+        }
+        Ok(encodings.iter().map(MaskedEncoding::from).collect())
+    }
+}
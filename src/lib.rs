@@ -1,11 +1,35 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
 
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, BytesOrString};
+use serde_with::{serde_as, Bytes, BytesOrString};
 use tokenizers::Tokenizer;
 
+mod chat;
+mod chunk;
+mod decoder;
+mod error;
+mod huggingface;
+mod offsets;
+mod presets;
+mod template;
 mod tiktoken;
+mod tools;
+mod truncate;
+mod vocab;
+use chat::*;
+use chunk::*;
+use decoder::*;
+use error::*;
+use huggingface::*;
+use offsets::*;
+use presets::*;
 use tiktoken::*;
+use tools::*;
+use truncate::*;
+use vocab::*;
 
 wit_bindgen::generate!("tokenizer");
 
@@ -13,7 +37,7 @@ wit_bindgen::generate!("tokenizer");
 #[derive(Debug)]
 enum TokenizerVariant {
     TokenizerTiktoken(CoreBPE),
-    TokenizerHuggingface(Tokenizer),
+    TokenizerHuggingface(HuggingfaceTokenizer),
 }
 
 #[serde_as]
@@ -28,8 +52,43 @@ enum LoadTokenizerVariant {
     },
     LoadTokenizerHuggingface {
         #[serde_as(as = "BytesOrString")]
-        model: Vec<u8>,
+        model:              Vec<u8>,
+        // Contents of `tokenizer_config.json`
+        #[serde_as(as = "Option<BytesOrString>")]
+        config:             Option<Vec<u8>>,
+        // Contents of `special_tokens_map.json`
+        #[serde_as(as = "Option<BytesOrString>")]
+        special_tokens_map: Option<Vec<u8>>,
     },
+    LoadTokenizerPreset {
+        preset: String,
+        #[serde_as(as = "BytesOrString")]
+        bpe:    Vec<u8>,
+    },
+}
+
+/// Refers to a loaded tokenizer by the handle returned from loading it, or by its name.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum TokenizerRef {
+    Handle(u32),
+    Name(#[serde_as(as = "BytesOrString")] Vec<u8>),
+}
+
+impl From<typed::TokenizerRef> for TokenizerRef {
+    fn from(tokenizer: typed::TokenizerRef) -> Self {
+        match tokenizer {
+            typed::TokenizerRef::Handle(handle) => TokenizerRef::Handle(handle),
+            typed::TokenizerRef::Name(name) => TokenizerRef::Name(name.into_bytes()),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct UnloadTokenizerInput {
+    name: TokenizerRef,
 }
 
 #[serde_as]
@@ -40,132 +99,1840 @@ struct LoadTokenizerInput {
     data: LoadTokenizerVariant,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum SpecialTokenSetInput {
+    Keyword(String),
+    Tokens(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "SpecialTokenSetInput", into = "SpecialTokenSetInput")]
+enum SpecialTokenSet {
+    All,
+    Tokens(Vec<String>),
+}
+
+impl TryFrom<SpecialTokenSetInput> for SpecialTokenSet {
+    type Error = String;
+
+    fn try_from(input: SpecialTokenSetInput) -> Result<Self, Self::Error> {
+        match input {
+            SpecialTokenSetInput::Keyword(keyword) if keyword == "all" => Ok(SpecialTokenSet::All),
+            SpecialTokenSetInput::Keyword(keyword) => {
+                Err(format!("Invalid special token set {:?}, expected \"all\" or a list", keyword))
+            }
+            SpecialTokenSetInput::Tokens(tokens) => Ok(SpecialTokenSet::Tokens(tokens)),
+        }
+    }
+}
+
+impl From<SpecialTokenSet> for SpecialTokenSetInput {
+    fn from(set: SpecialTokenSet) -> Self {
+        match set {
+            SpecialTokenSet::All => SpecialTokenSetInput::Keyword("all".to_string()),
+            SpecialTokenSet::Tokens(tokens) => SpecialTokenSetInput::Tokens(tokens),
+        }
+    }
+}
+
+impl From<typed::SpecialTokenSet> for SpecialTokenSet {
+    fn from(set: typed::SpecialTokenSet) -> Self {
+        match set {
+            typed::SpecialTokenSet::All => SpecialTokenSet::All,
+            typed::SpecialTokenSet::Tokens(tokens) => SpecialTokenSet::Tokens(tokens),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct EncodeOptions {
+    special_tokens:     bool,
+    allowed_special:    SpecialTokenSet,
+    disallowed_special: SpecialTokenSet,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            special_tokens:     true,
+            allowed_special:    SpecialTokenSet::All,
+            disallowed_special: SpecialTokenSet::All,
+        }
+    }
+}
+
+impl EncodeOptions {
+    fn new(
+        special_tokens: Option<bool>, allowed_special: Option<SpecialTokenSet>,
+        disallowed_special: Option<SpecialTokenSet>,
+    ) -> Self {
+        Self {
+            special_tokens:     special_tokens.unwrap_or(true),
+            allowed_special:    allowed_special.unwrap_or(SpecialTokenSet::All),
+            disallowed_special: disallowed_special.unwrap_or(SpecialTokenSet::All),
+        }
+    }
+
+    /// Resolves the special tokens that may be matched in `text` with tiktoken semantics:
+    /// special tokens that are neither allowed nor disallowed are encoded as ordinary text,
+    /// and disabling special tokens encodes everything as ordinary text.
+    fn allowed_special<'a>(
+        &'a self, tokenizer: &'a CoreBPE, text: &str,
+    ) -> Result<SpecialSet<'a>, TokenizerError> {
+        if !self.special_tokens {
+            return Ok(SpecialSet::none());
+        }
+        let allowed = match &self.allowed_special {
+            SpecialTokenSet::All => SpecialSet::All,
+            SpecialTokenSet::Tokens(tokens) => {
+                SpecialSet::Tokens(tokens.iter().map(|x| x.as_str()).collect())
+            }
+        };
+        let disallowed = match (&self.disallowed_special, &allowed) {
+            // Nothing is left to disallow when every special token is allowed
+            (SpecialTokenSet::All, SpecialSet::All) => SpecialSet::none(),
+            (SpecialTokenSet::All, SpecialSet::Tokens(allowed)) => SpecialSet::Tokens(
+                tokenizer.special_tokens().difference(allowed).copied().collect(),
+            ),
+            (SpecialTokenSet::Tokens(tokens), _) => {
+                SpecialSet::Tokens(tokens.iter().map(|x| x.as_str()).collect())
+            }
+        };
+        if let Some((token, position)) = tokenizer.find_disallowed(text, &disallowed)? {
+            return Err(TokenizerError::DisallowedSpecialToken {
+                token,
+                position: position as u32,
+            });
+        }
+        Ok(allowed)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct DecodeOptions {
+    // Defaults to rendering special tokens for tiktoken and skipping them for huggingface
+    special_tokens:      Option<bool>,
+    special_placeholder: Option<String>,
+    // Skips unknown token ids, or replaces them with `invalid_replacement`, instead of failing.
+    // Defaults to failing for tiktoken and to skipping them for huggingface, which always did
+    lenient:             Option<bool>,
+    invalid_replacement: Option<String>,
+}
+
+impl DecodeOptions {
+    fn new(
+        special_tokens: Option<bool>, special_placeholder: Option<String>, lenient: Option<bool>,
+        invalid_replacement: Option<String>,
+    ) -> Self {
+        Self {
+            special_tokens,
+            special_placeholder,
+            lenient,
+            invalid_replacement,
+        }
+    }
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 struct EncodeInput {
+    name:               TokenizerRef,
     #[serde_as(as = "BytesOrString")]
-    name:           Vec<u8>,
-    #[serde_as(as = "BytesOrString")]
-    input:          Vec<u8>,
-    special_tokens: Option<bool>,
+    input:              Vec<u8>,
+    special_tokens:     Option<bool>,
+    allowed_special:    Option<SpecialTokenSet>,
+    disallowed_special: Option<SpecialTokenSet>,
 }
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 struct DecodeInput {
+    name:                TokenizerRef,
     #[serde_as(as = "BytesOrString")]
-    name:           Vec<u8>,
+    input:               Vec<u8>,
+    special_tokens:      Option<bool>,
+    special_placeholder: Option<String>,
+    lenient:             Option<bool>,
+    invalid_replacement: Option<String>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct EncodeBatchInput {
+    name:               TokenizerRef,
+    input:              Vec<String>,
+    special_tokens:     Option<bool>,
+    allowed_special:    Option<SpecialTokenSet>,
+    disallowed_special: Option<SpecialTokenSet>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct DecodeBatchInput {
+    name:                TokenizerRef,
+    #[serde_as(as = "Vec<BytesOrString>")]
+    input:               Vec<Vec<u8>>,
+    special_tokens:      Option<bool>,
+    special_placeholder: Option<String>,
+    lenient:             Option<bool>,
+    invalid_replacement: Option<String>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum BatchOutput {
+    Tokens {
+        #[serde_as(as = "Bytes")]
+        tokens: Vec<u8>,
+    },
+    Text {
+        text: String,
+    },
+    Error(ErrorOutput),
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct EncodeOffsetsInput {
+    name:               TokenizerRef,
+    #[serde_as(as = "BytesOrString")]
+    input:              Vec<u8>,
+    special_tokens:     Option<bool>,
+    allowed_special:    Option<SpecialTokenSet>,
+    disallowed_special: Option<SpecialTokenSet>,
+    utf16:              Option<bool>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct EncodeOffsetsOutput {
+    #[serde_as(as = "Bytes")]
+    tokens:        Vec<u8>,
+    #[serde_as(as = "Bytes")]
+    byte_offsets:  Vec<u8>,
+    // Char and UTF-16 spans are widened to whole characters, so tokens splitting a character
+    // overlap and each cover all of it
+    #[serde_as(as = "Bytes")]
+    char_offsets:  Vec<u8>,
+    #[serde_as(as = "Option<Bytes>")]
+    utf16_offsets: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DecoderCreateInput {
+    name:                TokenizerRef,
+    special_tokens:      Option<bool>,
+    special_placeholder: Option<String>,
+    lenient:             Option<bool>,
+    invalid_replacement: Option<String>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct DecoderPushInput {
+    decoder: u32,
     #[serde_as(as = "BytesOrString")]
-    input:          Vec<u8>,
-    special_tokens: Option<bool>,
+    input:   Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DecoderInput {
+    decoder: u32,
 }
 
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct EncodeUnstableOutput {
+    #[serde_as(as = "Bytes")]
+    tokens:      Vec<u8>,
+    #[serde_as(as = "Vec<Bytes>")]
+    completions: Vec<Vec<u8>>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct EncodeHealingOutput {
+    #[serde_as(as = "Bytes")]
+    tokens:  Vec<u8>,
+    #[serde_as(as = "Bytes")]
+    prefix:  Vec<u8>,
+    /// None when nothing needs healing and any token may follow
+    #[serde_as(as = "Option<Bytes>")]
+    allowed: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum TokenizerKind {
+    Tiktoken,
+    Huggingface,
+}
+
+/// Special token with the role it was configured for, like `bos_token`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SpecialTokenRole {
+    role:  String,
+    token: String,
+    // Missing if the token isn't part of the vocabulary
+    id:    Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TokenizerInfo {
+    name:                String,
+    handle:              u32,
+    kind:                TokenizerKind,
+    vocab_size:          u32,
+    special_tokens:      Vec<(String, u32)>,
+    max_token_id:        Option<u32>,
+    regex:               Option<String>,
+    // Approximate size of the vocabulary in bytes
+    memory_usage:        u64,
+    special_token_roles: Vec<SpecialTokenRole>,
+    max_length:          Option<u32>,
+    add_bos_token:       Option<bool>,
+    add_eos_token:       Option<bool>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct TokenLookupInput {
+    name:  TokenizerRef,
+    #[serde_as(as = "BytesOrString")]
+    input: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct IdLookupInput {
+    name:  TokenizerRef,
+    token: u32,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct TruncateInput {
+    name:               TokenizerRef,
+    #[serde_as(as = "BytesOrString")]
+    input:              Vec<u8>,
+    max_tokens:         u32,
+    direction:          Option<TruncationDirection>,
+    ellipsis:           Option<String>,
+    special_tokens:     Option<bool>,
+    allowed_special:    Option<SpecialTokenSet>,
+    disallowed_special: Option<SpecialTokenSet>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct TruncateOutput {
+    #[serde_as(as = "Bytes")]
+    tokens:    Vec<u8>,
+    text:      String,
+    truncated: bool,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct ChunkInput {
+    name:               TokenizerRef,
+    #[serde_as(as = "BytesOrString")]
+    input:              Vec<u8>,
+    max_tokens:         u32,
+    overlap:            Option<u32>,
+    slack:              Option<u32>,
+    special_tokens:     Option<bool>,
+    allowed_special:    Option<SpecialTokenSet>,
+    disallowed_special: Option<SpecialTokenSet>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ChunkOutput {
+    tokens: (u32, u32),
+    bytes:  (u32, u32),
+    chars:  (u32, u32),
+    utf16:  (u32, u32),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ChatInput {
+    name:        TokenizerRef,
+    messages:    Vec<ChatMessage>,
+    format:      Option<ChatFormat>,
+    prime_reply: Option<bool>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct ChatOutput {
+    #[serde_as(as = "Bytes")]
+    tokens:         Vec<u8>,
+    text:           String,
+    message_tokens: Vec<u32>,
+    reply_tokens:   u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ChatTemplateInput {
+    name:                  TokenizerRef,
+    messages:              Vec<ChatMessage>,
+    chat_template:         Option<String>,
+    add_generation_prompt: Option<bool>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct ChatPromptOutput {
+    #[serde_as(as = "Bytes")]
+    tokens: Vec<u8>,
+    text:   String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ToolTokensInput {
+    name:           TokenizerRef,
+    tools:          Vec<ToolDefinition>,
+    system_message: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ToolTokensOutput {
+    text:          String,
+    text_tokens:   u32,
+    prompt_tokens: u32,
+}
+
+impl From<ToolTokens> for ToolTokensOutput {
+    fn from(result: ToolTokens) -> Self {
+        Self {
+            text:          result.text,
+            text_tokens:   result.text_tokens as u32,
+            prompt_tokens: result.prompt_tokens as u32,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EncodeMasksInput {
+    name:               TokenizerRef,
+    input:              Vec<String>,
+    pairs:              Option<Vec<String>>,
+    special_tokens:     Option<bool>,
+    max_length:         Option<u32>,
+    truncation:         Option<TruncationStrategy>,
+    stride:             Option<u32>,
+    padding:            Option<PaddingStrategy>,
+    pad_to_multiple_of: Option<u32>,
+    padding_side:       Option<PaddingSide>,
+    pad_token:          Option<String>,
+}
+
+impl EncodeMasksInput {
+    fn layout(&self) -> EncodingLayout {
+        EncodingLayout {
+            max_length:         self.max_length.map(|x| x as usize),
+            truncation:         self.truncation.unwrap_or_default(),
+            stride:             self.stride.unwrap_or(0) as usize,
+            padding:            self.padding,
+            pad_to_multiple_of: self.pad_to_multiple_of.map(|x| x as usize),
+            padding_side:       self.padding_side.unwrap_or_default(),
+            pad_token:          self.pad_token.clone(),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct MaskedEncodingOutput {
+    #[serde_as(as = "Bytes")]
+    tokens:              Vec<u8>,
+    #[serde_as(as = "Bytes")]
+    type_ids:            Vec<u8>,
+    #[serde_as(as = "Bytes")]
+    attention_mask:      Vec<u8>,
+    #[serde_as(as = "Bytes")]
+    special_tokens_mask: Vec<u8>,
+    overflowing:         Vec<MaskedEncodingOutput>,
+}
+
+impl From<&MaskedEncoding> for MaskedEncodingOutput {
+    fn from(encoding: &MaskedEncoding) -> Self {
+        Self {
+            tokens:              tokens_to_bytes(&encoding.tokens),
+            type_ids:            tokens_to_bytes(&encoding.type_ids),
+            attention_mask:      tokens_to_bytes(&encoding.attention_mask),
+            special_tokens_mask: tokens_to_bytes(&encoding.special_tokens_mask),
+            overflowing:         encoding.overflowing.iter().map(Self::from).collect(),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct TokenBytesOutput(#[serde_as(as = "Option<Bytes>")] Option<Vec<u8>>);
+
 thread_local! {
-    static TOKENIZERS: RefCell<HashMap<String, TokenizerVariant>> = RefCell::new(HashMap::new());
+    static TOKENIZERS: RefCell<HashMap<u32, TokenizerVariant>> = RefCell::new(HashMap::new());
+    static TOKENIZER_NAMES: RefCell<HashMap<String, u32>> = RefCell::new(HashMap::new());
+    static NEXT_TOKENIZER: Cell<u32> = Cell::new(1);
+    static DECODERS: RefCell<HashMap<u32, StreamDecoder>> = RefCell::new(HashMap::new());
+    static NEXT_DECODER: Cell<u32> = Cell::new(1);
 }
 
-fn deserialize<T>(input: &[u8]) -> Result<T, String>
+fn deserialize<T>(input: &[u8]) -> Result<T, TokenizerError>
 where
     T: serde::de::DeserializeOwned, {
-    rmp_serde::from_slice(input).map_err(|e| format!("{:?}", e))
+    rmp_serde::from_slice(input).map_err(TokenizerError::malformed_input)
 }
 
-struct TokenizerImpl;
-impl TokenizerInterface for TokenizerImpl {
-    fn load_tokenizer(input: Vec<u8>) -> Result<u32, String> {
-        let input = deserialize::<LoadTokenizerInput>(&input[..])?;
-        match input.data {
+fn serialize<T>(output: &T) -> Result<Vec<u8>, TokenizerError>
+where
+    T: serde::Serialize, {
+    rmp_serde::to_vec_named(output).map_err(TokenizerError::tokenizer_failed)
+}
+
+fn to_string(input: Vec<u8>, field: &str) -> Result<String, TokenizerError> {
+    String::from_utf8(input).map_err(|_| TokenizerError::InvalidUtf8 {
+        field: field.to_string(),
+    })
+}
+
+fn tokens_to_bytes(tokens: &[u32]) -> Vec<u8> {
+    tokens.iter().map(|x| (*x).to_le_bytes()).flatten().collect()
+}
+
+fn offsets_to_bytes(offsets: &[(usize, usize)]) -> Vec<u8> {
+    offsets
+        .iter()
+        .map(|(start, end)| [(*start as u32).to_le_bytes(), (*end as u32).to_le_bytes()])
+        .flatten()
+        .flatten()
+        .collect()
+}
+
+fn bytes_to_tokens(input: &[u8]) -> Result<Vec<u32>, TokenizerError> {
+    if input.len() % 4 != 0 {
+        return Err(TokenizerError::malformed_input(format!(
+            "Token buffer length {} is not a multiple of 4",
+            input.len()
+        )));
+    }
+    Ok(input
+        .chunks_exact(4)
+        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect())
+}
+
+impl TokenizerVariant {
+    fn load(data: LoadTokenizerVariant) -> Result<Self, TokenizerError> {
+        match data {
             LoadTokenizerVariant::LoadTokenizerTiktoken {
                 bpe,
                 special_bpe,
                 regex,
+            } => Ok(TokenizerVariant::TokenizerTiktoken(CoreBPE::new(
+                load_bpe(&bpe)?,
+                HashMap::from_iter(special_bpe.into_iter()),
+                &regex,
+            )?)),
+            LoadTokenizerVariant::LoadTokenizerHuggingface {
+                model,
+                config,
+                special_tokens_map,
             } => {
-                let tokenizer = CoreBPE::new(
-                    load_bpe(&bpe)?,
-                    HashMap::from_iter(special_bpe.into_iter()),
-                    &regex,
-                )?;
-                TOKENIZERS.with(|map| {
-                    map.borrow_mut().insert(
-                        String::from_utf8(input.name).unwrap(),
-                        TokenizerVariant::TokenizerTiktoken(tokenizer),
-                    )
-                });
+                let mut config = match config {
+                    Some(config) => TokenizerConfig::from_bytes(&config)?,
+                    None => TokenizerConfig::default(),
+                };
+                if let Some(special_tokens_map) = special_tokens_map {
+                    config = config.with_special_tokens_map(&special_tokens_map)?;
+                }
+                Ok(TokenizerVariant::TokenizerHuggingface(HuggingfaceTokenizer::new(
+                    Tokenizer::from_bytes(&model).map_err(TokenizerError::load_failed)?,
+                    config,
+                )))
+            }
+            LoadTokenizerVariant::LoadTokenizerPreset { preset, bpe } => {
+                let preset = find_preset(&preset).ok_or_else(|| {
+                    TokenizerError::load_failed(format!("Unknown encoding preset {:?}", preset))
+                })?;
+                Ok(TokenizerVariant::TokenizerTiktoken(preset.load(load_bpe(&bpe)?)?))
+            }
+        }
+    }
+
+    fn encode(&self, text: &str, options: &EncodeOptions) -> Result<Vec<u32>, TokenizerError> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => {
+                match (
+                    options.special_tokens,
+                    &options.allowed_special,
+                    &options.disallowed_special,
+                ) {
+                    (false, _, _) => tokenizer.encode_ordinary(text),
+                    (true, SpecialTokenSet::All, SpecialTokenSet::All) => tokenizer.encode(text),
+                    _ => {
+                        let allowed_special = options.allowed_special(tokenizer, text)?;
+                        tokenizer.encode_with_allowed(text, &allowed_special)
+                    }
+                }
+            }
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                let result = tokenizer
+                    .encode(text, options.special_tokens)
+                    .map_err(TokenizerError::tokenizer_failed)?;
+                Ok(result.get_ids().to_vec())
+            }
+        }
+    }
+
+    fn info(&self, name: String, handle: u32) -> TokenizerInfo {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => TokenizerInfo {
+                name,
+                handle,
+                kind: TokenizerKind::Tiktoken,
+                vocab_size: tokenizer.vocab_size() as u32,
+                special_tokens: tokenizer
+                    .special_token_ranks()
+                    .into_iter()
+                    .map(|(token, rank)| (token.to_string(), rank))
+                    .collect(),
+                max_token_id: tokenizer.max_token_id(),
+                regex: Some(tokenizer.pattern().to_string()),
+                memory_usage: tokenizer.memory_usage() as u64,
+                special_token_roles: vec![],
+                max_length: None,
+                add_bos_token: None,
+                add_eos_token: None,
+            },
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                let vocab = tokenizer.get_vocab(true);
+                let mut special_tokens = tokenizer
+                    .get_added_tokens_decoder()
+                    .into_iter()
+                    .filter(|(_, token)| token.special)
+                    .map(|(id, token)| (token.content, id))
+                    .collect::<Vec<_>>();
+                special_tokens.sort_by_key(|(_, id)| *id);
+                // The model keeps the vocabulary in both directions
+                let entry = std::mem::size_of::<String>() + std::mem::size_of::<u32>();
+                let memory_usage =
+                    vocab.keys().map(|token| (entry + token.len()) * 2).sum::<usize>();
+                let config = &tokenizer.config;
+                let additional = config
+                    .additional_special_tokens()
+                    .into_iter()
+                    .map(|token| ("additional_special_tokens", token));
+                let special_token_roles = config
+                    .special_tokens()
+                    .into_iter()
+                    .chain(additional)
+                    .map(|(role, token)| SpecialTokenRole {
+                        role:  role.to_string(),
+                        token: token.to_string(),
+                        id:    tokenizer.token_to_id(token),
+                    })
+                    .collect();
+                TokenizerInfo {
+                    name,
+                    handle,
+                    kind: TokenizerKind::Huggingface,
+                    vocab_size: tokenizer.get_vocab_size(true) as u32,
+                    special_tokens,
+                    max_token_id: vocab.values().max().copied(),
+                    regex: None,
+                    memory_usage: memory_usage as u64,
+                    special_token_roles,
+                    max_length: config.max_length(),
+                    add_bos_token: config.add_bos_token,
+                    add_eos_token: config.add_eos_token,
+                }
+            }
+        }
+    }
+
+    fn token_to_id(&self, bytes: &[u8]) -> Option<u32> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => tokenizer.token_to_id(bytes),
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => hf_token_to_id(tokenizer, bytes),
+        }
+    }
+
+    fn id_to_token_bytes(&self, token: u32) -> Option<Vec<u8>> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => {
+                tokenizer.id_to_token_bytes(token).map(<[u8]>::to_vec)
+            }
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                hf_id_to_token_bytes(tokenizer, token)
+            }
+        }
+    }
+
+    fn is_special(&self, token: u32) -> bool {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => tokenizer.is_special(token),
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => hf_is_special(tokenizer, token),
+        }
+    }
+
+    fn special_token_id(&self, name: &str) -> Option<u32> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => tokenizer.special_token_id(name),
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                tokenizer.token_to_id(name).filter(|token| hf_is_special(tokenizer, *token))
+            }
+        }
+    }
+
+    fn tokens_with_prefix(&self, prefix: &[u8]) -> Vec<u32> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => tokenizer.tokens_with_prefix(prefix),
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                hf_tokens_with_prefix(tokenizer.sorted_token_bytes(), prefix)
+            }
+        }
+    }
+
+    fn prefix_tokens(&self, bytes: &[u8]) -> Vec<u32> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => tokenizer.prefix_tokens(bytes),
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => hf_prefix_tokens(tokenizer, bytes),
+        }
+    }
+
+    fn is_valid_token(&self, token: u32) -> bool {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => tokenizer.is_valid_token(token),
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                tokenizer.id_to_token(token).is_some()
+            }
+        }
+    }
+
+    fn decode(&self, tokens: &[u32], options: &DecodeOptions) -> Result<Vec<u8>, TokenizerError> {
+        let error =
+            match TokenizerError::invalid_token_ids(tokens, |token| self.is_valid_token(token)) {
+                Some(error) => error,
+                None => return self.decode_valid(tokens, options),
+            };
+        let lenient = options
+            .lenient
+            .unwrap_or(matches!(self, TokenizerVariant::TokenizerHuggingface(_)));
+        if !lenient {
+            return Err(error);
+        }
+        match &options.invalid_replacement {
+            None => {
+                let tokens = tokens
+                    .iter()
+                    .copied()
+                    .filter(|token| self.is_valid_token(*token))
+                    .collect::<Vec<_>>();
+                self.decode_valid(&tokens, options)
+            }
+            Some(replacement) => {
+                // Every invalid token splits the sequence, so joining the decoded parts inserts
+                // exactly one replacement per invalid token
+                let parts = tokens
+                    .split(|token| !self.is_valid_token(*token))
+                    .map(|part| self.decode_valid(part, options))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(parts.join(replacement.as_bytes()))
+            }
+        }
+    }
+
+    fn decode_valid(
+        &self, tokens: &[u32], options: &DecodeOptions,
+    ) -> Result<Vec<u8>, TokenizerError> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => {
+                match (options.special_tokens.unwrap_or(true), &options.special_placeholder) {
+                    (true, _) => tokenizer.decode(tokens),
+                    (false, placeholder) => tokenizer.decode_with_placeholder(
+                        tokens,
+                        placeholder.as_ref().map(|x| x.as_bytes()),
+                    ),
+                }
+            }
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                let special_tokens = options.special_tokens.unwrap_or(false);
+                if !special_tokens && options.special_placeholder.is_some() {
+                    return Err(TokenizerError::unsupported(
+                        "Special token placeholders are not supported for huggingface tokenizers",
+                    ));
+                }
+                let result = tokenizer
+                    .decode(tokens.to_vec(), !special_tokens, true, true)
+                    .map_err(TokenizerError::tokenizer_failed)?;
+                Ok(result.into_bytes())
+            }
+        }
+    }
+
+    fn count(&self, text: &str, options: &EncodeOptions) -> Result<usize, TokenizerError> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => {
+                let allowed_special = options.allowed_special(tokenizer, text)?;
+                tokenizer.count(text, &allowed_special)
+            }
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                let result = tokenizer
+                    .encode(text, options.special_tokens)
+                    .map_err(TokenizerError::tokenizer_failed)?;
+                Ok(result.len())
+            }
+        }
+    }
+
+    fn encode_with_offsets(
+        &self, text: &str, options: &EncodeOptions,
+    ) -> Result<(Vec<u32>, Vec<(usize, usize)>), TokenizerError> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => {
+                let allowed_special = options.allowed_special(tokenizer, text)?;
+                let (tokens, offsets) = tokenizer.encode_with_offsets(text, &allowed_special)?;
+                Ok((tokens, offsets.into_iter().map(|x| (x.start, x.end)).collect()))
             }
-            LoadTokenizerVariant::LoadTokenizerHuggingface { model } => {
-                let tokenizer = Tokenizer::from_bytes(&model).map_err(|e| format!("{:?}", e))?;
-                TOKENIZERS.with(|map| {
-                    map.borrow_mut().insert(
-                        String::from_utf8(input.name).unwrap(),
-                        TokenizerVariant::TokenizerHuggingface(tokenizer),
-                    )
-                });
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                let result = tokenizer
+                    .encode(text, options.special_tokens)
+                    .map_err(TokenizerError::tokenizer_failed)?;
+                Ok((result.get_ids().to_vec(), result.get_offsets().to_vec()))
             }
         }
-        Ok(0)
+    }
+
+    fn encode_with_unstable(
+        &self, text: &str, options: &EncodeOptions,
+    ) -> Result<(Vec<u32>, Vec<Vec<u32>>), TokenizerError> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => {
+                let allowed_special = options.allowed_special(tokenizer, text)?;
+                let (tokens, completions) =
+                    tokenizer.encode_with_unstable(text, &allowed_special)?;
+                let mut completions = completions.into_iter().collect::<Vec<_>>();
+                completions.sort();
+                Ok((tokens, completions))
+            }
+            TokenizerVariant::TokenizerHuggingface(_) => Err(TokenizerError::unsupported(
+                "Unstable completions are not supported for huggingface tokenizers",
+            )),
+        }
+    }
+
+    fn encode_with_healing(
+        &self, text: &str, options: &EncodeOptions,
+    ) -> Result<(Vec<u32>, Vec<u8>, Option<Vec<u32>>), TokenizerError> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(tokenizer) => {
+                let allowed_special = options.allowed_special(tokenizer, text)?;
+                tokenizer.encode_with_healing(text, &allowed_special)
+            }
+            TokenizerVariant::TokenizerHuggingface(_) => Err(TokenizerError::unsupported(
+                "Token healing is not supported for huggingface tokenizers",
+            )),
+        }
+    }
+
+    fn encode_with_masks(
+        &self, texts: &[String], pairs: Option<&[String]>, special_tokens: bool,
+        layout: &EncodingLayout,
+    ) -> Result<Vec<MaskedEncoding>, TokenizerError> {
+        match self {
+            TokenizerVariant::TokenizerTiktoken(_) => Err(TokenizerError::unsupported(
+                "Attention masks are not supported for tiktoken tokenizers",
+            )),
+            TokenizerVariant::TokenizerHuggingface(tokenizer) => {
+                tokenizer.encode_with_masks(texts, pairs, special_tokens, layout)
+            }
+        }
+    }
+
+    fn encode_batch(
+        &self, texts: &[String], options: &EncodeOptions,
+    ) -> Vec<Result<Vec<u32>, TokenizerError>> {
+        texts.iter().map(|text| self.encode(text, options)).collect()
+    }
+
+    fn decode_batch(
+        &self, tokens: &[Vec<u32>], options: &DecodeOptions,
+    ) -> Vec<Result<Vec<u8>, TokenizerError>> {
+        tokens.iter().map(|tokens| self.decode(tokens, options)).collect()
+    }
+}
+
+fn load_tokenizer(name: String, data: LoadTokenizerVariant) -> Result<u32, TokenizerError> {
+    let tokenizer = TokenizerVariant::load(data)?;
+    let handle = NEXT_TOKENIZER.with(|next| next.replace(next.get() + 1));
+    // Loading a name again replaces the tokenizer, which invalidates the previous handle
+    let previous = TOKENIZER_NAMES.with(|names| names.borrow_mut().insert(name, handle));
+    TOKENIZERS.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(previous) = previous {
+            map.remove(&previous);
+        }
+        map.insert(handle, tokenizer);
+    });
+    if let Some(previous) = previous {
+        drop_decoders(previous);
+    }
+    Ok(handle)
+}
+
+/// Drops the stream decoders of a tokenizer that was unloaded or replaced.
+fn drop_decoders(tokenizer: u32) {
+    DECODERS.with(|map| map.borrow_mut().retain(|_, decoder| decoder.tokenizer != tokenizer));
+}
+
+fn unload_tokenizer(tokenizer: &TokenizerRef) -> Result<u32, TokenizerError> {
+    let handle = match resolve_tokenizer(tokenizer) {
+        Ok(handle) => handle,
+        Err(TokenizerError::TokenizerNotFound { .. }) => return Ok(0),
+        Err(error) => return Err(error),
+    };
+    TOKENIZERS.with(|map| {
+        map.borrow_mut().remove(&handle);
+    });
+    TOKENIZER_NAMES.with(|names| names.borrow_mut().retain(|_, x| *x != handle));
+    drop_decoders(handle);
+    Ok(0)
+}
+
+fn list_tokenizers() -> Vec<TokenizerInfo> {
+    let mut names = TOKENIZER_NAMES.with(|names| {
+        names
+            .borrow()
+            .iter()
+            .map(|(name, handle)| (name.clone(), *handle))
+            .collect::<Vec<_>>()
+    });
+    names.sort_by_key(|(_, handle)| *handle);
+    TOKENIZERS.with(|map| {
+        let map = map.borrow();
+        names
+            .into_iter()
+            .filter_map(|(name, handle)| Some(map.get(&handle)?.info(name, handle)))
+            .collect()
+    })
+}
+
+/// Chunks `text` and converts the byte ranges for callers that index by characters.
+fn chunk_text(
+    tokenizer: &TokenizerVariant, text: &str, max_tokens: u32, overlap: Option<u32>,
+    slack: Option<u32>, options: &EncodeOptions,
+) -> Result<Vec<ChunkOutput>, TokenizerError> {
+    let max_tokens = max_tokens as usize;
+    let overlap = overlap.unwrap_or(0) as usize;
+    let slack = slack.map(|x| x as usize).unwrap_or(max_tokens / 4);
+    let chunks = chunk(tokenizer, text, max_tokens, overlap, slack, options)?;
+    let bytes = chunks.iter().map(|chunk| chunk.bytes).collect::<Vec<_>>();
+    let chars = byte_to_char_offsets(text, &bytes);
+    let utf16 = byte_to_utf16_offsets(text, &bytes);
+    let into_u32 = |(start, end): (usize, usize)| (start as u32, end as u32);
+    Ok(chunks
+        .iter()
+        .zip(chars.into_iter().zip(utf16))
+        .map(|(chunk, (chars, utf16))| ChunkOutput {
+            tokens: into_u32(chunk.tokens),
+            bytes:  into_u32(chunk.bytes),
+            chars:  into_u32(chars),
+            utf16:  into_u32(utf16),
+        })
+        .collect())
+}
+
+fn resolve_tokenizer(tokenizer: &TokenizerRef) -> Result<u32, TokenizerError> {
+    match tokenizer {
+        TokenizerRef::Handle(handle) => Ok(*handle),
+        TokenizerRef::Name(name) => {
+            let name = std::str::from_utf8(name).map_err(|_| TokenizerError::InvalidUtf8 {
+                field: "name".to_string(),
+            })?;
+            TOKENIZER_NAMES.with(|names| {
+                names
+                    .borrow()
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| TokenizerError::TokenizerNotFound {
+                        name: name.to_string(),
+                    })
+            })
+        }
+    }
+}
+
+fn with_handle<T>(
+    handle: u32, f: impl FnOnce(&TokenizerVariant) -> Result<T, TokenizerError>,
+) -> Result<T, TokenizerError> {
+    TOKENIZERS.with(|map| {
+        let map = map.borrow();
+        let tokenizer = map.get(&handle).ok_or(TokenizerError::HandleNotFound { handle })?;
+        f(tokenizer)
+    })
+}
+
+fn with_tokenizer<T>(
+    tokenizer: &TokenizerRef, f: impl FnOnce(&TokenizerVariant) -> Result<T, TokenizerError>,
+) -> Result<T, TokenizerError> {
+    with_handle(resolve_tokenizer(tokenizer)?, f)
+}
+
+fn create_decoder(tokenizer: &TokenizerRef, options: DecodeOptions) -> Result<u32, TokenizerError> {
+    let handle = resolve_tokenizer(tokenizer)?;
+    with_handle(handle, |_| Ok(()))?;
+    let decoder = NEXT_DECODER.with(|next| next.replace(next.get() + 1));
+    DECODERS.with(|map| map.borrow_mut().insert(decoder, StreamDecoder::new(handle, options)));
+    Ok(decoder)
+}
+
+fn drop_decoder(decoder: u32) -> Result<u32, TokenizerError> {
+    DECODERS.with(|map| {
+        map.borrow_mut().remove(&decoder);
+    });
+    Ok(0)
+}
+
+fn with_decoder<T>(
+    decoder: u32,
+    f: impl FnOnce(&TokenizerVariant, &mut StreamDecoder) -> Result<T, TokenizerError>,
+) -> Result<T, TokenizerError> {
+    DECODERS.with(|map| {
+        let mut map = map.borrow_mut();
+        let decoder = map.get_mut(&decoder).ok_or(TokenizerError::DecoderNotFound { decoder })?;
+        with_handle(decoder.tokenizer, |tokenizer| f(tokenizer, decoder))
+    })
+}
+
+fn resolve_encoding(model: &str) -> Result<&'static str, TokenizerError> {
+    encoding_for_model(model).ok_or_else(|| TokenizerError::UnknownModel {
+        model: model.to_string(),
+    })
+}
+
+// Errors of the blob exports are JSON serialized `ErrorOutput`s
+struct TokenizerImpl;
+impl TokenizerInterface for TokenizerImpl {
+    fn load_tokenizer(input: Vec<u8>) -> Result<u32, String> {
+        let input = deserialize::<LoadTokenizerInput>(&input[..])?;
+        Ok(load_tokenizer(to_string(input.name, "name")?, input.data)?)
     }
 
     fn unload_tokenizer(input: Vec<u8>) -> Result<u32, String> {
-        let input = String::from_utf8(input).unwrap();
-        TOKENIZERS.with(|map| {
-            map.borrow_mut().remove(&input);
-        });
-        Ok(0)
+        // The name is passed as is, a msgpack encoded map is needed to pass a handle instead
+        let tokenizer = match String::from_utf8(input) {
+            Ok(name) => TokenizerRef::Name(name.into_bytes()),
+            Err(error) => deserialize::<UnloadTokenizerInput>(error.as_bytes())?.name,
+        };
+        Ok(unload_tokenizer(&tokenizer)?)
+    }
+
+    fn encode(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<EncodeInput>(&input[..])?;
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special,
+            input.disallowed_special,
+        );
+        let text = to_string(input.input, "input")?;
+        let result = with_tokenizer(&input.name, |tokenizer| tokenizer.encode(&text, &options))?;
+        Ok(tokens_to_bytes(&result))
+    }
+
+    fn decode(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<DecodeInput>(&input[..])?;
+        let tokens = bytes_to_tokens(&input.input)?;
+        let options = DecodeOptions::new(
+            input.special_tokens,
+            input.special_placeholder,
+            input.lenient,
+            input.invalid_replacement,
+        );
+        Ok(with_tokenizer(&input.name, |tokenizer| tokenizer.decode(&tokens, &options))?)
+    }
+
+    fn count_tokens(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<EncodeInput>(&input[..])?;
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special,
+            input.disallowed_special,
+        );
+        let text = to_string(input.input, "input")?;
+        let result = with_tokenizer(&input.name, |tokenizer| tokenizer.count(&text, &options))?;
+        Ok(serialize(&result)?)
+    }
+
+    fn truncate(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<TruncateInput>(&input[..])?;
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special,
+            input.disallowed_special,
+        );
+        let text = to_string(input.input, "input")?;
+        let result = with_tokenizer(&input.name, |tokenizer| {
+            truncate(
+                tokenizer,
+                &text,
+                input.max_tokens as usize,
+                input.direction.unwrap_or_default(),
+                input.ellipsis.as_deref().unwrap_or(DEFAULT_ELLIPSIS),
+                &options,
+            )
+        })?;
+        Ok(serialize(&TruncateOutput {
+            tokens:    tokens_to_bytes(&result.tokens),
+            text:      result.text,
+            truncated: result.truncated,
+        })?)
+    }
+
+    fn chunk(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<ChunkInput>(&input[..])?;
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special,
+            input.disallowed_special,
+        );
+        let text = to_string(input.input, "input")?;
+        let result = with_tokenizer(&input.name, |tokenizer| {
+            chunk_text(tokenizer, &text, input.max_tokens, input.overlap, input.slack, &options)
+        })?;
+        Ok(serialize(&result)?)
+    }
+
+    fn render_chat(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<ChatInput>(&input[..])?;
+        let format = input.format.unwrap_or_default();
+        let result = with_tokenizer(&input.name, |tokenizer| {
+            render_chat(tokenizer, &input.messages, &format, input.prime_reply.unwrap_or(true))
+        })?;
+        Ok(serialize(&ChatOutput {
+            tokens:         tokens_to_bytes(&result.tokens),
+            text:           result.text,
+            message_tokens: result.message_tokens.iter().map(|x| *x as u32).collect(),
+            reply_tokens:   result.reply_tokens as u32,
+        })?)
+    }
+
+    fn apply_chat_template(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<ChatTemplateInput>(&input[..])?;
+        let result = with_tokenizer(&input.name, |tokenizer| {
+            apply_chat_template(
+                tokenizer,
+                &input.messages,
+                input.chat_template.as_deref(),
+                input.add_generation_prompt.unwrap_or(false),
+            )
+        })?;
+        Ok(serialize(&ChatPromptOutput {
+            tokens: tokens_to_bytes(&result.tokens),
+            text:   result.text,
+        })?)
+    }
+
+    fn count_tool_tokens(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<ToolTokensInput>(&input[..])?;
+        let result = with_tokenizer(&input.name, |tokenizer| {
+            count_tool_tokens(tokenizer, &input.tools, input.system_message.unwrap_or(false))
+        })?;
+        Ok(serialize(&ToolTokensOutput::from(result))?)
+    }
+
+    fn count_tokens_batch(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<EncodeBatchInput>(&input[..])?;
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special,
+            input.disallowed_special,
+        );
+        let result = with_tokenizer(&input.name, |tokenizer| {
+            input
+                .input
+                .iter()
+                .map(|text| tokenizer.count(text, &options))
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        Ok(serialize(&result)?)
+    }
+
+    fn encode_with_offsets(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<EncodeOffsetsInput>(&input[..])?;
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special,
+            input.disallowed_special,
+        );
+        let text = to_string(input.input, "input")?;
+        let (tokens, offsets) = with_tokenizer(&input.name, |tokenizer| {
+            tokenizer.encode_with_offsets(&text, &options)
+        })?;
+        Ok(serialize(&EncodeOffsetsOutput {
+            tokens:        tokens_to_bytes(&tokens),
+            byte_offsets:  offsets_to_bytes(&offsets),
+            char_offsets:  offsets_to_bytes(&byte_to_char_offsets(&text, &offsets)),
+            utf16_offsets: input
+                .utf16
+                .unwrap_or(false)
+                .then(|| offsets_to_bytes(&byte_to_utf16_offsets(&text, &offsets))),
+        })?)
+    }
+
+    fn encode_with_masks(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<EncodeMasksInput>(&input[..])?;
+        let layout = input.layout();
+        let result = with_tokenizer(&input.name, |tokenizer| {
+            tokenizer.encode_with_masks(
+                &input.input,
+                input.pairs.as_deref(),
+                input.special_tokens.unwrap_or(true),
+                &layout,
+            )
+        })?;
+        Ok(serialize(&result.iter().map(MaskedEncodingOutput::from).collect::<Vec<_>>())?)
+    }
+
+    fn encode_with_unstable(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<EncodeInput>(&input[..])?;
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special,
+            input.disallowed_special,
+        );
+        let text = to_string(input.input, "input")?;
+        let (tokens, completions) = with_tokenizer(&input.name, |tokenizer| {
+            tokenizer.encode_with_unstable(&text, &options)
+        })?;
+        Ok(serialize(&EncodeUnstableOutput {
+            tokens:      tokens_to_bytes(&tokens),
+            completions: completions.iter().map(|x| tokens_to_bytes(x)).collect(),
+        })?)
+    }
+
+    fn encode_with_healing(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<EncodeInput>(&input[..])?;
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special,
+            input.disallowed_special,
+        );
+        let text = to_string(input.input, "input")?;
+        let (tokens, prefix, allowed) = with_tokenizer(&input.name, |tokenizer| {
+            tokenizer.encode_with_healing(&text, &options)
+        })?;
+        Ok(serialize(&EncodeHealingOutput {
+            tokens: tokens_to_bytes(&tokens),
+            prefix,
+            allowed: allowed.as_deref().map(tokens_to_bytes),
+        })?)
+    }
+
+    fn encode_batch(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<EncodeBatchInput>(&input[..])?;
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special,
+            input.disallowed_special,
+        );
+        let result = with_tokenizer(&input.name, |tokenizer| {
+            Ok(tokenizer.encode_batch(&input.input, &options))
+        })?;
+        Ok(serialize(
+            &result
+                .into_iter()
+                .map(|result| match result {
+                    Ok(tokens) => BatchOutput::Tokens {
+                        tokens: tokens_to_bytes(&tokens),
+                    },
+                    Err(error) => BatchOutput::Error(error.into()),
+                })
+                .collect::<Vec<_>>(),
+        )?)
+    }
+
+    fn decode_batch(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<DecodeBatchInput>(&input[..])?;
+        let tokens =
+            input.input.iter().map(|x| bytes_to_tokens(x)).collect::<Result<Vec<_>, _>>()?;
+        let options = DecodeOptions::new(
+            input.special_tokens,
+            input.special_placeholder,
+            input.lenient,
+            input.invalid_replacement,
+        );
+        let result =
+            with_tokenizer(&input.name, |tokenizer| Ok(tokenizer.decode_batch(&tokens, &options)))?;
+        Ok(serialize(
+            &result
+                .into_iter()
+                .map(|result| match result {
+                    Ok(text) => BatchOutput::Text {
+                        text: String::from_utf8_lossy(&text).into_owned(),
+                    },
+                    Err(error) => BatchOutput::Error(error.into()),
+                })
+                .collect::<Vec<_>>(),
+        )?)
+    }
+
+    fn decoder_create(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<DecoderCreateInput>(&input[..])?;
+        let options = DecodeOptions::new(
+            input.special_tokens,
+            input.special_placeholder,
+            input.lenient,
+            input.invalid_replacement,
+        );
+        Ok(serialize(&create_decoder(&input.name, options)?)?)
+    }
+
+    fn decoder_push(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<DecoderPushInput>(&input[..])?;
+        let tokens = bytes_to_tokens(&input.input)?;
+        Ok(with_decoder(input.decoder, |tokenizer, decoder| {
+            Ok(decoder.push(tokenizer, &tokens)?.into_bytes())
+        })?)
+    }
+
+    fn decoder_flush(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<DecoderInput>(&input[..])?;
+        Ok(with_decoder(input.decoder, |tokenizer, decoder| {
+            Ok(decoder.flush(tokenizer)?.into_bytes())
+        })?)
+    }
+
+    fn decoder_drop(input: Vec<u8>) -> Result<u32, String> {
+        let input = deserialize::<DecoderInput>(&input[..])?;
+        Ok(drop_decoder(input.decoder)?)
+    }
+
+    fn encoding_for_model(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let model = to_string(input, "model")?;
+        Ok(serialize(&resolve_encoding(&model)?)?)
+    }
+
+    fn list_tokenizers(_input: Vec<u8>) -> Result<Vec<u8>, String> {
+        Ok(serialize(&list_tokenizers())?)
+    }
+
+    fn token_to_id(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<TokenLookupInput>(&input[..])?;
+        let result =
+            with_tokenizer(&input.name, |tokenizer| Ok(tokenizer.token_to_id(&input.input)))?;
+        Ok(serialize(&result)?)
+    }
+
+    fn id_to_token_bytes(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<IdLookupInput>(&input[..])?;
+        let result =
+            with_tokenizer(&input.name, |tokenizer| Ok(tokenizer.id_to_token_bytes(input.token)))?;
+        Ok(serialize(&TokenBytesOutput(result))?)
+    }
+
+    fn is_special(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<IdLookupInput>(&input[..])?;
+        let result =
+            with_tokenizer(&input.name, |tokenizer| Ok(tokenizer.is_special(input.token)))?;
+        Ok(serialize(&result)?)
+    }
+
+    fn tokens_with_prefix(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<TokenLookupInput>(&input[..])?;
+        let result =
+            with_tokenizer(
+                &input.name,
+                |tokenizer| Ok(tokenizer.tokens_with_prefix(&input.input)),
+            )?;
+        Ok(tokens_to_bytes(&result))
+    }
+
+    fn prefix_tokens(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<TokenLookupInput>(&input[..])?;
+        let result =
+            with_tokenizer(&input.name, |tokenizer| Ok(tokenizer.prefix_tokens(&input.input)))?;
+        Ok(tokens_to_bytes(&result))
+    }
+
+    fn special_token_id(input: Vec<u8>) -> Result<Vec<u8>, String> {
+        let input = deserialize::<TokenLookupInput>(&input[..])?;
+        let name = to_string(input.input, "input")?;
+        let result =
+            with_tokenizer(&input.name, |tokenizer| Ok(tokenizer.special_token_id(&name)))?;
+        Ok(serialize(&result)?)
+    }
+}
+
+impl typed::Typed for TokenizerImpl {
+    fn load_tokenizer(input: typed::LoadTokenizerInput) -> Result<u32, typed::TokenizerError> {
+        let data = match input.data {
+            typed::LoadTokenizerData::Tiktoken(data) => {
+                LoadTokenizerVariant::LoadTokenizerTiktoken {
+                    bpe:         data.bpe,
+                    special_bpe: data.special_bpe,
+                    regex:       data.regex,
+                }
+            }
+            typed::LoadTokenizerData::Huggingface(data) => {
+                LoadTokenizerVariant::LoadTokenizerHuggingface {
+                    model:              data.model,
+                    config:             data.config,
+                    special_tokens_map: data.special_tokens_map,
+                }
+            }
+            typed::LoadTokenizerData::Preset(data) => LoadTokenizerVariant::LoadTokenizerPreset {
+                preset: data.preset,
+                bpe:    data.bpe,
+            },
+        };
+        Ok(load_tokenizer(input.name, data)?)
+    }
+
+    fn unload_tokenizer(tokenizer: typed::TokenizerRef) -> Result<u32, typed::TokenizerError> {
+        Ok(unload_tokenizer(&tokenizer.into())?)
+    }
+
+    fn encode(input: typed::EncodeInput) -> Result<Vec<u32>, typed::TokenizerError> {
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special.map(Into::into),
+            input.disallowed_special.map(Into::into),
+        );
+        Ok(with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            tokenizer.encode(&input.text, &options)
+        })?)
+    }
+
+    fn decode(input: typed::DecodeInput) -> Result<String, typed::TokenizerError> {
+        let options = DecodeOptions::new(
+            input.special_tokens,
+            input.special_placeholder,
+            input.lenient,
+            input.invalid_replacement,
+        );
+        Ok(with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            let result = tokenizer.decode(&input.tokens, &options)?;
+            Ok(String::from_utf8_lossy(&result).into_owned())
+        })?)
+    }
+
+    fn count_tokens(input: typed::EncodeInput) -> Result<u32, typed::TokenizerError> {
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special.map(Into::into),
+            input.disallowed_special.map(Into::into),
+        );
+        Ok(with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            Ok(tokenizer.count(&input.text, &options)? as u32)
+        })?)
+    }
+
+    fn count_tokens_batch(
+        input: typed::EncodeBatchInput,
+    ) -> Result<Vec<u32>, typed::TokenizerError> {
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special.map(Into::into),
+            input.disallowed_special.map(Into::into),
+        );
+        Ok(with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            input
+                .texts
+                .iter()
+                .map(|text| Ok(tokenizer.count(text, &options)? as u32))
+                .collect()
+        })?)
+    }
+
+    fn truncate(input: typed::TruncateInput) -> Result<typed::Truncation, typed::TokenizerError> {
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special.map(Into::into),
+            input.disallowed_special.map(Into::into),
+        );
+        let direction = match input.direction {
+            Some(typed::TruncationDirection::Head) | None => TruncationDirection::Head,
+            Some(typed::TruncationDirection::Tail) => TruncationDirection::Tail,
+            Some(typed::TruncationDirection::Both) => TruncationDirection::Both,
+        };
+        let result = with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            truncate(
+                tokenizer,
+                &input.text,
+                input.max_tokens as usize,
+                direction,
+                input.ellipsis.as_deref().unwrap_or(DEFAULT_ELLIPSIS),
+                &options,
+            )
+        })?;
+        Ok(typed::Truncation {
+            tokens:    result.tokens,
+            text:      result.text,
+            truncated: result.truncated,
+        })
+    }
+
+    fn chunk(input: typed::ChunkInput) -> Result<Vec<typed::TextChunk>, typed::TokenizerError> {
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special.map(Into::into),
+            input.disallowed_special.map(Into::into),
+        );
+        let result = with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            chunk_text(
+                tokenizer,
+                &input.text,
+                input.max_tokens,
+                input.overlap,
+                input.slack,
+                &options,
+            )
+        })?;
+        Ok(result
+            .into_iter()
+            .map(|chunk| typed::TextChunk {
+                tokens: chunk.tokens,
+                bytes:  chunk.bytes,
+                chars:  chunk.chars,
+                utf16:  chunk.utf16,
+            })
+            .collect())
+    }
+
+    fn render_chat(input: typed::ChatInput) -> Result<typed::RenderedChat, typed::TokenizerError> {
+        let messages = input
+            .messages
+            .into_iter()
+            .map(|message| ChatMessage {
+                role:    message.role,
+                name:    message.name,
+                content: message.content,
+            })
+            .collect::<Vec<_>>();
+        let format = input
+            .format
+            .map(|format| ChatFormat {
+                message_prefix:     format.message_prefix,
+                name_separator:     format.name_separator,
+                role_separator:     format.role_separator,
+                message_suffix:     format.message_suffix,
+                reply_prefix:       format.reply_prefix,
+                tokens_per_message: format.tokens_per_message,
+                tokens_per_name:    format.tokens_per_name,
+                reply_tokens:       format.reply_tokens,
+            })
+            .unwrap_or_default();
+        let result = with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            render_chat(tokenizer, &messages, &format, input.prime_reply.unwrap_or(true))
+        })?;
+        Ok(typed::RenderedChat {
+            tokens:         result.tokens,
+            text:           result.text,
+            message_tokens: result.message_tokens.iter().map(|x| *x as u32).collect(),
+            reply_tokens:   result.reply_tokens as u32,
+        })
+    }
+
+    fn apply_chat_template(
+        input: typed::ChatTemplateInput,
+    ) -> Result<typed::ChatPrompt, typed::TokenizerError> {
+        let messages = input
+            .messages
+            .into_iter()
+            .map(|message| ChatMessage {
+                role:    message.role,
+                name:    message.name,
+                content: message.content,
+            })
+            .collect::<Vec<_>>();
+        let result = with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            apply_chat_template(
+                tokenizer,
+                &messages,
+                input.chat_template.as_deref(),
+                input.add_generation_prompt.unwrap_or(false),
+            )
+        })?;
+        Ok(typed::ChatPrompt {
+            text:   result.text,
+            tokens: result.tokens,
+        })
+    }
+
+    fn count_tool_tokens(
+        tokenizer: typed::TokenizerRef, tools: Vec<u8>, system_message: Option<bool>,
+    ) -> Result<typed::ToolTokens, typed::TokenizerError> {
+        let tools = deserialize::<Vec<ToolDefinition>>(&tools)?;
+        let result = with_tokenizer(&tokenizer.into(), |tokenizer| {
+            count_tool_tokens(tokenizer, &tools, system_message.unwrap_or(false))
+        })?;
+        let result = ToolTokensOutput::from(result);
+        Ok(typed::ToolTokens {
+            text:          result.text,
+            text_tokens:   result.text_tokens,
+            prompt_tokens: result.prompt_tokens,
+        })
+    }
+
+    fn encode_with_offsets(
+        input: typed::EncodeOffsetsInput,
+    ) -> Result<typed::EncodingOffsets, typed::TokenizerError> {
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special.map(Into::into),
+            input.disallowed_special.map(Into::into),
+        );
+        let (tokens, offsets) = with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            tokenizer.encode_with_offsets(&input.text, &options)
+        })?;
+        let into_u32 = |offsets: &[(usize, usize)]| {
+            offsets.iter().map(|(start, end)| (*start as u32, *end as u32)).collect()
+        };
+        Ok(typed::EncodingOffsets {
+            tokens,
+            byte_offsets: into_u32(&offsets),
+            char_offsets: into_u32(&byte_to_char_offsets(&input.text, &offsets)),
+            utf16_offsets: input
+                .utf16
+                .unwrap_or(false)
+                .then(|| into_u32(&byte_to_utf16_offsets(&input.text, &offsets))),
+        })
+    }
+
+    fn encode_with_masks(
+        input: typed::EncodeMasksInput,
+    ) -> Result<Vec<typed::MaskedEncoding>, typed::TokenizerError> {
+        let layout = EncodingLayout {
+            max_length:         input.max_length.map(|x| x as usize),
+            truncation:         match input.truncation {
+                Some(typed::TruncationStrategy::LongestFirst) | None => {
+                    TruncationStrategy::LongestFirst
+                }
+                Some(typed::TruncationStrategy::OnlyFirst) => TruncationStrategy::OnlyFirst,
+                Some(typed::TruncationStrategy::OnlySecond) => TruncationStrategy::OnlySecond,
+            },
+            stride:             input.stride.unwrap_or(0) as usize,
+            padding:            input.padding.map(|x| match x {
+                typed::PaddingStrategy::Longest => PaddingStrategy::Longest,
+                typed::PaddingStrategy::MaxLength => PaddingStrategy::MaxLength,
+            }),
+            pad_to_multiple_of: input.pad_to_multiple_of.map(|x| x as usize),
+            padding_side:       match input.padding_side {
+                Some(typed::PaddingSide::Left) => PaddingSide::Left,
+                Some(typed::PaddingSide::Right) | None => PaddingSide::Right,
+            },
+            pad_token:          input.pad_token,
+        };
+        let result = with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            tokenizer.encode_with_masks(
+                &input.texts,
+                input.pairs.as_deref(),
+                input.special_tokens.unwrap_or(true),
+                &layout,
+            )
+        })?;
+        Ok(result
+            .into_iter()
+            .map(|encoding| typed::MaskedEncoding {
+                tokens:              encoding.tokens,
+                type_ids:            encoding.type_ids,
+                attention_mask:      encoding.attention_mask,
+                special_tokens_mask: encoding.special_tokens_mask,
+                overflowing:         encoding
+                    .overflowing
+                    .into_iter()
+                    .map(|window| typed::EncodingWindow {
+                        tokens:              window.tokens,
+                        type_ids:            window.type_ids,
+                        attention_mask:      window.attention_mask,
+                        special_tokens_mask: window.special_tokens_mask,
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+
+    fn encode_with_unstable(
+        input: typed::EncodeInput,
+    ) -> Result<typed::UnstableEncoding, typed::TokenizerError> {
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special.map(Into::into),
+            input.disallowed_special.map(Into::into),
+        );
+        let (tokens, completions) = with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            tokenizer.encode_with_unstable(&input.text, &options)
+        })?;
+        Ok(typed::UnstableEncoding {
+            tokens,
+            completions,
+        })
+    }
+
+    fn encode_with_healing(
+        input: typed::EncodeInput,
+    ) -> Result<typed::HealedEncoding, typed::TokenizerError> {
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special.map(Into::into),
+            input.disallowed_special.map(Into::into),
+        );
+        let (tokens, prefix, allowed) = with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            tokenizer.encode_with_healing(&input.text, &options)
+        })?;
+        Ok(typed::HealedEncoding {
+            tokens,
+            prefix,
+            allowed,
+        })
+    }
+
+    fn encode_batch(
+        input: typed::EncodeBatchInput,
+    ) -> Result<Vec<Result<Vec<u32>, typed::TokenizerError>>, typed::TokenizerError> {
+        let options = EncodeOptions::new(
+            input.special_tokens,
+            input.allowed_special.map(Into::into),
+            input.disallowed_special.map(Into::into),
+        );
+        Ok(with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            Ok(tokenizer
+                .encode_batch(&input.texts, &options)
+                .into_iter()
+                .map(|result| result.map_err(Into::into))
+                .collect())
+        })?)
+    }
+
+    fn decode_batch(
+        input: typed::DecodeBatchInput,
+    ) -> Result<Vec<Result<String, typed::TokenizerError>>, typed::TokenizerError> {
+        let options = DecodeOptions::new(
+            input.special_tokens,
+            input.special_placeholder,
+            input.lenient,
+            input.invalid_replacement,
+        );
+        Ok(with_tokenizer(&input.tokenizer.into(), |tokenizer| {
+            Ok(tokenizer
+                .decode_batch(&input.tokens, &options)
+                .into_iter()
+                .map(|result| {
+                    result
+                        .map(|text| String::from_utf8_lossy(&text).into_owned())
+                        .map_err(Into::into)
+                })
+                .collect())
+        })?)
+    }
+
+    fn decoder_create(input: typed::DecoderCreateInput) -> Result<u32, typed::TokenizerError> {
+        Ok(create_decoder(
+            &input.tokenizer.into(),
+            DecodeOptions::new(
+                input.special_tokens,
+                input.special_placeholder,
+                input.lenient,
+                input.invalid_replacement,
+            ),
+        )?)
+    }
+
+    fn decoder_push(decoder: u32, tokens: Vec<u32>) -> Result<String, typed::TokenizerError> {
+        Ok(with_decoder(decoder, |tokenizer, decoder| decoder.push(tokenizer, &tokens))?)
+    }
+
+    fn decoder_flush(decoder: u32) -> Result<String, typed::TokenizerError> {
+        Ok(with_decoder(decoder, |tokenizer, decoder| decoder.flush(tokenizer))?)
+    }
+
+    fn decoder_drop(decoder: u32) -> Result<u32, typed::TokenizerError> {
+        Ok(drop_decoder(decoder)?)
+    }
+
+    fn encoding_for_model(model: String) -> Result<String, typed::TokenizerError> {
+        Ok(resolve_encoding(&model)?.to_string())
+    }
+
+    fn list_tokenizers() -> Result<Vec<typed::TokenizerInfo>, typed::TokenizerError> {
+        Ok(list_tokenizers()
+            .into_iter()
+            .map(|info| typed::TokenizerInfo {
+                name:                info.name,
+                handle:              info.handle,
+                kind:                match info.kind {
+                    TokenizerKind::Tiktoken => typed::TokenizerKind::Tiktoken,
+                    TokenizerKind::Huggingface => typed::TokenizerKind::Huggingface,
+                },
+                vocab_size:          info.vocab_size,
+                special_tokens:      info.special_tokens,
+                max_token_id:        info.max_token_id,
+                regex:               info.regex,
+                memory_usage:        info.memory_usage,
+                special_token_roles: info
+                    .special_token_roles
+                    .into_iter()
+                    .map(|x| typed::SpecialTokenRole {
+                        role:  x.role,
+                        token: x.token,
+                        id:    x.id,
+                    })
+                    .collect(),
+                max_length:          info.max_length,
+                add_bos_token:       info.add_bos_token,
+                add_eos_token:       info.add_eos_token,
+            })
+            .collect())
+    }
+
+    fn token_to_id(
+        tokenizer: typed::TokenizerRef, token: Vec<u8>,
+    ) -> Result<Option<u32>, typed::TokenizerError> {
+        Ok(with_tokenizer(
+            &tokenizer.into(),
+            |tokenizer| Ok(tokenizer.token_to_id(&token)),
+        )?)
+    }
+
+    fn id_to_token_bytes(
+        tokenizer: typed::TokenizerRef, token: u32,
+    ) -> Result<Option<Vec<u8>>, typed::TokenizerError> {
+        Ok(with_tokenizer(&tokenizer.into(), |tokenizer| {
+            Ok(tokenizer.id_to_token_bytes(token))
+        })?)
+    }
+
+    fn is_special(
+        tokenizer: typed::TokenizerRef, token: u32,
+    ) -> Result<bool, typed::TokenizerError> {
+        Ok(with_tokenizer(&tokenizer.into(), |tokenizer| Ok(tokenizer.is_special(token)))?)
+    }
+
+    fn tokens_with_prefix(
+        tokenizer: typed::TokenizerRef, prefix: Vec<u8>,
+    ) -> Result<Vec<u32>, typed::TokenizerError> {
+        Ok(with_tokenizer(&tokenizer.into(), |tokenizer| {
+            Ok(tokenizer.tokens_with_prefix(&prefix))
+        })?)
     }
 
-    fn encode(input: Vec<u8>) -> Result<Vec<u8>, String> {
-        let input = deserialize::<EncodeInput>(&input[..])?;
-        TOKENIZERS.with(|map| {
-            let map = map.borrow();
-            let tokenizer =
-                map.get(&String::from_utf8(input.name).unwrap()).ok_or("Tokenizer not found")?;
-            match tokenizer {
-                TokenizerVariant::TokenizerTiktoken(tokenizer) => {
-                    let result = tokenizer.encode(&String::from_utf8(input.input).unwrap());
-                    Ok(result.iter().map(|x| (*x).to_le_bytes()).flatten().collect())
-                }
-                TokenizerVariant::TokenizerHuggingface(tokenizer) => {
-                    let result = tokenizer
-                        .encode(
-                            String::from_utf8(input.input).unwrap(),
-                            input.special_tokens.unwrap_or(true),
-                        )
-                        .map_err(|e| format!("{:?}", e))?;
-                    Ok(result.get_ids().iter().map(|x| (*x).to_le_bytes()).flatten().collect())
-                }
-            }
-        })
+    fn prefix_tokens(
+        tokenizer: typed::TokenizerRef, bytes: Vec<u8>,
+    ) -> Result<Vec<u32>, typed::TokenizerError> {
+        Ok(with_tokenizer(&tokenizer.into(), |tokenizer| {
+            Ok(tokenizer.prefix_tokens(&bytes))
+        })?)
     }
 
-    fn decode(input: Vec<u8>) -> Result<Vec<u8>, String> {
-        let input = deserialize::<DecodeInput>(&input[..])?;
-        TOKENIZERS.with(|map| {
-            let map = map.borrow();
-            let tokenizer =
-                map.get(&String::from_utf8(input.name).unwrap()).ok_or("Tokenizer not found")?;
-            match tokenizer {
-                TokenizerVariant::TokenizerTiktoken(tokenizer) => {
-                    let tokens = input
-                        .input
-                        .chunks(4)
-                        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
-                        .collect::<Vec<_>>();
-                    let result = tokenizer.decode(&tokens);
-                    Ok(result)
-                }
-                TokenizerVariant::TokenizerHuggingface(tokenizer) => {
-                    let tokens = input
-                        .input
-                        .chunks(4)
-                        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
-                        .collect::<Vec<_>>();
-                    let result = tokenizer
-                        .decode(tokens, !input.special_tokens.unwrap_or(false), true, true)
-                        .map_err(|e| format!("{:?}", e))?;
-                    Ok(result.into_bytes())
-                }
-            }
-        })
+    fn special_token_id(
+        tokenizer: typed::TokenizerRef, name: String,
+    ) -> Result<Option<u32>, typed::TokenizerError> {
+        Ok(with_tokenizer(&tokenizer.into(), |tokenizer| {
+            Ok(tokenizer.special_token_id(&name))
+        })?)
     }
 }
 
@@ -181,6 +1948,13 @@ pub mod test {
     static CL100K: &[u8] = include_bytes!("../tests/cl100k_base.tiktoken");
     static NEOX20B: &[u8] = include_bytes!("../tests/neox_20b_tokenizer.json");
 
+    /// cl100k_base with the regex and special tokens of its preset.
+    fn cl100k() -> Result<CoreBPE, String> {
+        Ok(crate::presets::find_preset("cl100k_base").unwrap().load(load_bpe(CL100K)?)?)
+    }
+
+    fn neox() -> Tokenizer { Tokenizer::from_str(std::str::from_utf8(NEOX20B).unwrap()).unwrap() }
+
     #[test]
     fn test_encode_tt() -> Result<(), String> {
         pub fn load_special_bpe() -> HashMap<String, u32> {
@@ -200,12 +1974,12 @@ pub mod test {
             r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+",
         )?;
 
-        let result1 = tokenizer.encode("Hello World!");
+        let result1 = tokenizer.encode("Hello World!")?;
         let tokens1 = result1.iter().map(|x| (*x as u32)).collect::<Vec<_>>();
         println!("Tokens: {:?}", tokens1);
         assert_eq!(tokens1, &[9906, 4435, 0], "Tokens should be [9906, 4435, 0]");
 
-        let result2 = tokenizer.encode("hello <|endoftext|>");
+        let result2 = tokenizer.encode("hello <|endoftext|>")?;
         let tokens2 = result2.iter().map(|x| (*x as u32)).collect::<Vec<_>>();
         println!("Tokens: {:?}", tokens2);
         assert_eq!(tokens2, &[15339, 220, 100257], "Tokens should be [15339, 220, 100257]");
@@ -232,12 +2006,12 @@ pub mod test {
             r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+",
         )?;
 
-        let result1 = tokenizer.decode(&[9906, 4435, 0]);
+        let result1 = tokenizer.decode(&[9906, 4435, 0])?;
         let string1 = String::from_utf8_lossy(&result1);
         println!("String: {:?}", string1);
         assert_eq!(string1, "Hello World!", "String should be \"Hello World!\"");
 
-        let result2 = tokenizer.decode(&[15339, 220, 100257]);
+        let result2 = tokenizer.decode(&[15339, 220, 100257])?;
         let string2 = String::from_utf8_lossy(&result2);
         println!("String: {:?}", string2);
         assert_eq!(string2, "hello <|endoftext|>", "String should be \"hello <|endoftext|>\"");
@@ -245,6 +2019,95 @@ pub mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_count_tt() -> Result<(), String> {
+        let tokenizer = cl100k()?;
+
+        let text = "Hello World! This is a longer text with some tokens <|endoftext|>";
+        let count = tokenizer.count(text, &SpecialSet::All)?;
+        println!("Count: {:?}", count);
+        assert_eq!(count, tokenizer.encode(text)?.len(), "Count should match the number of tokens");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unstable_tt() -> Result<(), String> {
+        let tokenizer = cl100k()?;
+
+        let (tokens, completions) =
+            tokenizer.encode_with_unstable("hello fanta", &SpecialSet::All)?;
+        println!("Tokens: {:?}", tokens);
+        assert_eq!(tokens, &[15339], "Tokens should be [15339]");
+        assert!(!completions.is_empty(), "Completions should not be empty");
+        for completion in completions {
+            let text = tokenizer.decode(&[tokens.clone(), completion].concat())?;
+            assert!(text.starts_with(b"hello fanta"), "Completion should extend the input");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_healing_tt() -> Result<(), String> {
+        let tokenizer = cl100k()?;
+
+        let (tokens, prefix, allowed) =
+            tokenizer.encode_with_healing("hello fanta", &SpecialSet::All)?;
+        println!("Tokens: {:?}", tokens);
+        assert_eq!(tokens, &[15339], "Tokens should be [15339]");
+        assert_eq!(prefix, b" fanta", "Prefix should be \" fanta\"");
+        let allowed = allowed.expect("Allowed tokens should be restricted");
+        assert!(!allowed.is_empty(), "Allowed tokens should not be empty");
+        for token in allowed {
+            assert!(tokenizer.decode(&[token])?.starts_with(b" fanta"));
+        }
+
+        // No single token spans the whole word, so only its last token is healed
+        let text = "hello antidisestablishmentarianism";
+        let full = tokenizer.encode(text)?;
+        let (tokens, prefix, allowed) = tokenizer.encode_with_healing(text, &SpecialSet::All)?;
+        assert_eq!(tokens, &full[..full.len() - 1], "Only the last token should be removed");
+        assert_eq!(prefix, tokenizer.decode(&full[full.len() - 1..])?);
+        let allowed = allowed.expect("Allowed tokens should be restricted");
+        assert!(allowed.contains(&full[full.len() - 1]), "Removed token should be allowed");
+
+        // Nothing to heal after a special token, so any token may follow
+        let (tokens, prefix, allowed) =
+            tokenizer.encode_with_healing("hello<|endoftext|>", &SpecialSet::All)?;
+        assert_eq!(tokens, &[15339, 100257]);
+        assert!(prefix.is_empty(), "Prefix should be empty");
+        assert_eq!(allowed, None, "Allowed tokens should be unrestricted");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_special_tt() -> Result<(), String> {
+        use crate::{DecodeOptions, TokenizerVariant};
+
+        let tokenizer = TokenizerVariant::TokenizerTiktoken(cl100k()?);
+        let decode = |special_tokens: Option<bool>, placeholder: Option<&str>| {
+            let options =
+                DecodeOptions::new(special_tokens, placeholder.map(str::to_string), None, None);
+            let result = tokenizer.decode(&[15339, 220, 100257], &options)?;
+            Ok::<_, String>(String::from_utf8(result).unwrap())
+        };
+        // Special tokens are rendered unless skipping them is asked for
+        assert_eq!(decode(None, None)?, "hello <|endoftext|>");
+        assert_eq!(decode(Some(true), None)?, "hello <|endoftext|>");
+        assert_eq!(decode(Some(false), None)?, "hello ");
+        assert_eq!(decode(Some(false), Some("[SPECIAL]"))?, "hello [SPECIAL]");
+
+        let bpe = cl100k()?;
+        let result = bpe.decode_with_placeholder(&[100257, 15339, 100257], None)?;
+        assert_eq!(result, b"hello", "Special tokens should be skipped");
+        let result = bpe.decode_with_placeholder(&[15339, 220, 100257], Some(b"[SPECIAL]"))?;
+        assert_eq!(result, b"hello [SPECIAL]", "Special tokens should be replaced");
+
+        Ok(())
+    }
+
     #[test]
     fn test_encode_hf() -> Result<(), String> {
         let tokenizer = Tokenizer::from_str(&String::from_utf8(NEOX20B.to_vec()).unwrap()).unwrap();
@@ -276,4 +2139,1345 @@ pub mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_typed() -> Result<(), crate::typed::TokenizerError> {
+        use crate::{typed, TokenizerImpl};
+
+        let handle = <TokenizerImpl as typed::Typed>::load_tokenizer(typed::LoadTokenizerInput {
+            name: "neox20b".to_string(),
+            data: typed::LoadTokenizerData::Huggingface(typed::LoadHuggingface {
+                model:              NEOX20B.to_vec(),
+                config:             None,
+                special_tokens_map: None,
+            }),
+        })?;
+
+        let tokens = <TokenizerImpl as typed::Typed>::encode(typed::EncodeInput {
+            tokenizer:          typed::TokenizerRef::Handle(handle),
+            text:               "Hello World!".to_string(),
+            special_tokens:     None,
+            allowed_special:    None,
+            disallowed_special: None,
+        })?;
+        println!("Tokens: {:?}", tokens);
+        assert_eq!(tokens, &[12092, 3645, 2], "Tokens should be [12092, 3645, 2]");
+
+        let string = <TokenizerImpl as typed::Typed>::decode(typed::DecodeInput {
+            tokenizer: typed::TokenizerRef::Name("neox20b".to_string()),
+            tokens,
+            special_tokens: None,
+            special_placeholder: None,
+            lenient: None,
+            invalid_replacement: None,
+        })?;
+        println!("String: {:?}", string);
+        assert_eq!(string, "Hello World!", "String should be \"Hello World!\"");
+
+        <TokenizerImpl as typed::Typed>::unload_tokenizer(typed::TokenizerRef::Handle(handle))?;
+
+        let result = <TokenizerImpl as typed::Typed>::count_tokens(typed::EncodeInput {
+            tokenizer:          typed::TokenizerRef::Handle(handle),
+            text:               "Hello World!".to_string(),
+            special_tokens:     None,
+            allowed_special:    None,
+            disallowed_special: None,
+        });
+        assert!(
+            matches!(result, Err(typed::TokenizerError::HandleNotFound(x)) if x == handle),
+            "Unloaded handle should not be found"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handles() -> Result<(), String> {
+        use crate::{
+            DECODERS, DecodeOptions, ErrorOutput, LoadTokenizerInput, LoadTokenizerVariant,
+            TokenizerError, TokenizerImpl, TokenizerInterface, TokenizerRef, UnloadTokenizerInput,
+            create_decoder, with_tokenizer,
+        };
+
+        let load = |name: &str| {
+            <TokenizerImpl as TokenizerInterface>::load_tokenizer(
+                rmp_serde::to_vec_named(&LoadTokenizerInput {
+                    name: name.as_bytes().to_vec(),
+                    data: LoadTokenizerVariant::LoadTokenizerHuggingface {
+                        model:              NEOX20B.to_vec(),
+                        config:             None,
+                        special_tokens_map: None,
+                    },
+                })
+                .unwrap(),
+            )
+        };
+        let decoders = || DECODERS.with(|map| map.borrow().len());
+        let options = || DecodeOptions::new(None, None, None, None);
+
+        let first = load("neox20b")?;
+        create_decoder(&TokenizerRef::Handle(first), options())?;
+        assert_eq!(decoders(), 1);
+        // Loading the name again drops the decoders of the replaced tokenizer
+        let second = load("neox20b")?;
+        assert_ne!(first, second);
+        assert_eq!(decoders(), 0);
+        create_decoder(&TokenizerRef::Name(b"neox20b".to_vec()), options())?;
+
+        // Names are passed to unload as is
+        assert_eq!(
+            <TokenizerImpl as TokenizerInterface>::unload_tokenizer(b"neox20b".to_vec())?,
+            0
+        );
+        assert_eq!(decoders(), 0);
+        let result = with_tokenizer(&TokenizerRef::Handle(second), |_| Ok(()));
+        assert!(matches!(result, Err(TokenizerError::HandleNotFound { .. })));
+
+        let handle = load("other")?;
+        let input = rmp_serde::to_vec_named(&UnloadTokenizerInput {
+            name: TokenizerRef::Handle(handle),
+        })
+        .unwrap();
+        <TokenizerImpl as TokenizerInterface>::unload_tokenizer(input)?;
+        let result = with_tokenizer(&TokenizerRef::Name(b"other".to_vec()), |_| Ok(()));
+        assert!(matches!(result, Err(TokenizerError::TokenizerNotFound { .. })));
+
+        // Blob errors stay strings, holding the serialized error
+        let error = <TokenizerImpl as TokenizerInterface>::load_tokenizer(b"other".to_vec())
+            .expect_err("Loading without msgpack input should fail");
+        let output = serde_json::from_str::<ErrorOutput>(&error).map_err(|e| e.to_string())?;
+        assert!(matches!(output.error, TokenizerError::MalformedInput { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_hf() -> Result<(), String> {
+        use crate::{DecodeOptions, EncodeOptions, TokenizerVariant};
+
+        let tokenizer = TokenizerVariant::TokenizerHuggingface(neox().into());
+
+        let result1 = tokenizer
+            .encode_batch(
+                &[
+                    "Hello World!".to_string(),
+                    "hello <|endoftext|>".to_string(),
+                ],
+                &EncodeOptions::default(),
+            )
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        println!("Tokens: {:?}", result1);
+        assert_eq!(result1, &[vec![12092, 3645, 2], vec![25521, 209, 0]]);
+
+        let result2 = tokenizer
+            .decode_batch(&result1, &DecodeOptions::new(Some(true), None, None, None))
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        println!("Strings: {:?}", result2);
+        assert_eq!(result2, &[b"Hello World!".to_vec(), b"hello <|endoftext|>".to_vec()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_offsets_tt() -> Result<(), String> {
+        let tokenizer = cl100k()?;
+
+        let text = "Hällo 🚀<|endoftext|>";
+        let (tokens, offsets) = tokenizer.encode_with_offsets(text, &SpecialSet::All)?;
+        let offsets = offsets.into_iter().map(|x| (x.start, x.end)).collect::<Vec<_>>();
+        println!("Tokens: {:?}", tokens);
+        println!("Offsets: {:?}", offsets);
+        for (token, (start, end)) in tokens.iter().zip(offsets.iter()) {
+            assert_eq!(tokenizer.decode(&[*token])?, text.as_bytes()[*start..*end].to_vec());
+        }
+
+        let char_offsets = crate::offsets::byte_to_char_offsets(text, &offsets);
+        println!("Char offsets: {:?}", char_offsets);
+        assert_eq!(char_offsets, &[(0, 1), (1, 3), (3, 5), (5, 7), (6, 7), (6, 7), (7, 20)]);
+
+        let utf16_offsets = crate::offsets::byte_to_utf16_offsets(text, &offsets);
+        println!("UTF-16 offsets: {:?}", utf16_offsets);
+        assert_eq!(utf16_offsets, &[(0, 1), (1, 3), (3, 5), (5, 8), (6, 8), (6, 8), (8, 21)]);
+
+        // 🚀 is split across three tokens, which overlap and each cover the whole character
+        // rather than a lone surrogate
+        let rocket = text.find('🚀').unwrap();
+        let split = [
+            (rocket - 1, rocket + 2),
+            (rocket + 2, rocket + 3),
+            (rocket + 3, rocket + 4),
+        ];
+        assert_eq!(offsets[3..6], split);
+        assert_eq!(char_offsets[3..6], [(5, 7), (6, 7), (6, 7)]);
+        assert_eq!(utf16_offsets[3..6], [(5, 8), (6, 8), (6, 8)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_decoder() -> Result<(), String> {
+        use crate::{decoder::StreamDecoder, DecodeOptions, EncodeOptions, TokenizerVariant};
+
+        let tokenizer = TokenizerVariant::TokenizerTiktoken(cl100k()?);
+        let mut decoder = StreamDecoder::new(0, DecodeOptions::new(Some(true), None, None, None));
+        let parts = [39, 30902, 385, 11410, 248, 222, 100257]
+            .iter()
+            .map(|token| decoder.push(&tokenizer, &[*token]))
+            .collect::<Result<Vec<_>, _>>()?;
+        println!("Parts: {:?}", parts);
+        assert_eq!(parts, &["H", "äl", "lo", " ", "", "🚀", "<|endoftext|>"]);
+        assert_eq!(decoder.flush(&tokenizer)?, "");
+
+        let tokenizer = TokenizerVariant::TokenizerHuggingface(neox().into());
+        let tokens = tokenizer.encode("Hello 🚀 World!", &EncodeOptions::default())?;
+        let mut decoder = StreamDecoder::new(1, DecodeOptions::new(Some(true), None, None, None));
+        let mut parts = tokens
+            .iter()
+            .map(|token| decoder.push(&tokenizer, &[*token]))
+            .collect::<Result<Vec<_>, _>>()?;
+        parts.push(decoder.flush(&tokenizer)?);
+        println!("Parts: {:?}", parts);
+        assert!(parts.iter().all(|part| !part.contains(char::REPLACEMENT_CHARACTER)));
+        assert_eq!(parts.concat(), "Hello 🚀 World!");
+
+        // A decoder rewriting "ab" or "ac" changes text that was already returned, which fails
+        // once and then decodes the following tokens as before
+        let json = r#"{
+            "version": "1.0",
+            "model": {"type": "BPE", "vocab": {"a": 0, "b": 1, "c": 2}, "merges": []},
+            "decoder": {"type": "Sequence", "decoders": [
+                {"type": "Fuse"},
+                {"type": "Replace", "pattern": {"String": "ab"}, "content": "XYZ"},
+                {"type": "Replace", "pattern": {"String": "ac"}, "content": "Q"}
+            ]}
+        }"#;
+        let tokenizer = TokenizerVariant::TokenizerHuggingface(
+            Tokenizer::from_str(json).map_err(|e| e.to_string())?.into(),
+        );
+        let mut decoder = StreamDecoder::new(2, DecodeOptions::new(Some(true), None, None, None));
+        assert_eq!(decoder.push(&tokenizer, &[0])?, "a");
+        assert!(decoder.push(&tokenizer, &[1]).is_err());
+        assert_eq!(decoder.push(&tokenizer, &[2])?, "c");
+        assert_eq!(decoder.flush(&tokenizer)?, "");
+
+        assert_eq!(decoder.push(&tokenizer, &[0])?, "a");
+        assert_eq!(decoder.push(&tokenizer, &[2])?, "");
+        assert!(decoder.flush(&tokenizer).is_err());
+        assert_eq!(decoder.push(&tokenizer, &[2])?, "c");
+        assert_eq!(decoder.flush(&tokenizer)?, "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_special_tt() -> Result<(), String> {
+        use crate::{error::TokenizerError, EncodeOptions, SpecialTokenSet, TokenizerVariant};
+
+        let tokenizer = TokenizerVariant::TokenizerTiktoken(cl100k()?);
+
+        let ordinary = EncodeOptions::new(Some(false), None, None);
+        let tokens1 = tokenizer.encode("hello <|endoftext|>", &ordinary)?;
+        println!("Tokens: {:?}", tokens1);
+        assert_eq!(tokens1, &[15339, 83739, 8862, 728, 428, 91, 29], "Tokens should be ordinary");
+
+        let allowed = EncodeOptions::new(
+            None,
+            Some(SpecialTokenSet::Tokens(vec!["<|endoftext|>".to_string()])),
+            None,
+        );
+        let tokens2 = tokenizer.encode("hello <|endoftext|>", &allowed)?;
+        println!("Tokens: {:?}", tokens2);
+        assert_eq!(tokens2, &[15339, 220, 100257], "Tokens should be [15339, 220, 100257]");
+
+        let error = tokenizer.encode("hello <|endofprompt|>", &allowed).unwrap_err();
+        println!("Error: {:?}", error);
+        assert_eq!(error, TokenizerError::DisallowedSpecialToken {
+            token:    "<|endofprompt|>".to_string(),
+            position: 6,
+        });
+
+        let neither = EncodeOptions::new(
+            None,
+            Some(SpecialTokenSet::Tokens(vec![])),
+            Some(SpecialTokenSet::Tokens(vec![])),
+        );
+        let tokens3 = tokenizer.encode("hello <|endoftext|>", &neither)?;
+        assert_eq!(tokens3, tokens1, "Tokens should be ordinary");
+
+        // Unstable completions and token healing follow the same rules
+        let (tokens4, _) =
+            tokenizer.encode_with_unstable("hello <|endoftext|> fanta", &ordinary)?;
+        assert!(!tokens4.contains(&100257), "Tokens should be ordinary");
+        let (tokens5, _, _) =
+            tokenizer.encode_with_healing("hello <|endoftext|> fanta", &allowed)?;
+        assert!(tokens5.contains(&100257));
+        let error = tokenizer
+            .encode_with_healing("hello <|endofprompt|> fanta", &allowed)
+            .unwrap_err();
+        assert!(matches!(error, TokenizerError::DisallowedSpecialToken { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_tokens() -> Result<(), String> {
+        use crate::{
+            DecodeOptions, TokenizerVariant,
+            error::{InvalidToken, TokenizerError},
+        };
+
+        let tiktoken = TokenizerVariant::TokenizerTiktoken(cl100k()?);
+        let huggingface = TokenizerVariant::TokenizerHuggingface(neox().into());
+
+        for (tokenizer, tokens) in [
+            (tiktoken, [9906, 999999, 4435]),
+            (huggingface, [12092, 999999, 3645]),
+        ] {
+            let strict = DecodeOptions::new(None, None, Some(false), None);
+            let error = tokenizer.decode(&tokens, &strict).unwrap_err();
+            println!("Error: {:?}", error);
+            assert_eq!(error, TokenizerError::InvalidTokenIds {
+                tokens: vec![InvalidToken {
+                    position: 1,
+                    token:    999999,
+                }],
+            });
+
+            let skip = DecodeOptions::new(None, None, Some(true), None);
+            let string1 = String::from_utf8_lossy(&tokenizer.decode(&tokens, &skip)?).into_owned();
+            println!("String: {:?}", string1);
+            assert_eq!(string1, "Hello World", "String should be \"Hello World\"");
+
+            let replace = DecodeOptions::new(None, None, Some(true), Some("\u{FFFD}".to_string()));
+            let string2 =
+                String::from_utf8_lossy(&tokenizer.decode(&tokens, &replace)?).into_owned();
+            println!("String: {:?}", string2);
+            assert_eq!(string2, "Hello\u{FFFD} World", "String should be \"Hello\u{FFFD} World\"");
+        }
+
+        // Huggingface tokenizers always skipped unknown ids, which stays the default
+        let tokenizer = neox();
+        let tokens = [12092, 999999, 3645];
+        let default = DecodeOptions::new(None, None, None, None);
+        let result =
+            TokenizerVariant::TokenizerHuggingface(neox().into()).decode(&tokens, &default)?;
+        let expected =
+            tokenizer.decode(tokens.to_vec(), true, true, true).map_err(|e| e.to_string())?;
+        assert_eq!(String::from_utf8_lossy(&result), expected);
+        assert_eq!(expected, "Hello World");
+        let result =
+            TokenizerVariant::TokenizerTiktoken(cl100k()?).decode(&[9906, 999999], &default);
+        assert!(matches!(result, Err(TokenizerError::InvalidTokenIds { .. })));
+
+        let error = cl100k()?.decode(&[15339, 999999]).unwrap_err();
+        assert_eq!(error, TokenizerError::InvalidTokenIds {
+            tokens: vec![InvalidToken {
+                position: 1,
+                token:    999999,
+            }],
+        });
+
+        // Vocabularies without a token for every byte load, and only fail on bytes they lack
+        let encoder = HashMap::from([(b"a".to_vec(), 0), (b"b".to_vec(), 1), (b"ab".to_vec(), 2)]);
+        let partial = CoreBPE::new(encoder, HashMap::new(), r"\S+|\s+")?;
+        assert_eq!(partial.encode("abba")?, &[2, 1, 0]);
+        let error = partial.encode("abc").unwrap_err();
+        assert!(matches!(error, TokenizerError::TokenizerFailed { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preset_tt() -> Result<(), String> {
+        use crate::{error::TokenizerError, EncodeOptions, LoadTokenizerVariant, TokenizerVariant};
+
+        let tokenizer = TokenizerVariant::load(LoadTokenizerVariant::LoadTokenizerPreset {
+            preset: "cl100k_base".to_string(),
+            bpe:    CL100K.to_vec(),
+        })?;
+        let tokens = tokenizer.encode("hello <|endofprompt|>", &EncodeOptions::default())?;
+        println!("Tokens: {:?}", tokens);
+        assert_eq!(tokens, &[15339, 220, 100276], "Tokens should be [15339, 220, 100276]");
+
+        let error = TokenizerVariant::load(LoadTokenizerVariant::LoadTokenizerPreset {
+            preset: "r50k_base".to_string(),
+            bpe:    CL100K.to_vec(),
+        })
+        .unwrap_err();
+        println!("Error: {:?}", error);
+        assert!(matches!(error, TokenizerError::LoadFailed { .. }));
+
+        for preset in crate::presets::PRESETS {
+            assert!(
+                fancy_regex::Regex::new(preset.pattern).is_ok(),
+                "{} should compile",
+                preset.name
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoding_for_model() {
+        use crate::presets::encoding_for_model;
+
+        assert_eq!(encoding_for_model("gpt-4o"), Some("o200k_base"));
+        assert_eq!(encoding_for_model("gpt-4o-2024-05-13"), Some("o200k_base"));
+        assert_eq!(encoding_for_model("gpt-4"), Some("cl100k_base"));
+        assert_eq!(encoding_for_model("gpt-4-0613"), Some("cl100k_base"));
+        assert_eq!(encoding_for_model("gpt-3.5-turbo"), Some("cl100k_base"));
+        assert_eq!(encoding_for_model("ft:gpt-4o-mini:org::id"), Some("o200k_base"));
+        assert_eq!(encoding_for_model("text-davinci-003"), Some("p50k_base"));
+        assert_eq!(encoding_for_model("code-davinci-002"), Some("p50k_base"));
+        assert_eq!(encoding_for_model("text-davinci-edit-001"), Some("p50k_edit"));
+        assert_eq!(encoding_for_model("davinci"), Some("r50k_base"));
+        assert_eq!(encoding_for_model("llama-3"), None);
+    }
+
+    #[test]
+    fn test_list_tokenizers() -> Result<(), String> {
+        use crate::{list_tokenizers, load_tokenizer, LoadTokenizerVariant, TokenizerKind};
+
+        let cl100k =
+            load_tokenizer("cl100k".to_string(), LoadTokenizerVariant::LoadTokenizerPreset {
+                preset: "cl100k_base".to_string(),
+                bpe:    CL100K.to_vec(),
+            })?;
+        let neox20b = load_tokenizer(
+            "neox20b".to_string(),
+            LoadTokenizerVariant::LoadTokenizerHuggingface {
+                model:              NEOX20B.to_vec(),
+                config:             None,
+                special_tokens_map: None,
+            },
+        )?;
+
+        let infos = list_tokenizers();
+        println!("Infos: {:?}", infos.iter().map(|x| (&x.name, x.vocab_size)).collect::<Vec<_>>());
+        assert_eq!(infos.len(), 2, "Both tokenizers should be listed");
+
+        assert_eq!(infos[0].name, "cl100k");
+        assert_eq!(infos[0].handle, cl100k);
+        assert_eq!(infos[0].kind, TokenizerKind::Tiktoken);
+        assert_eq!(infos[0].vocab_size, 100256 + 5);
+        assert_eq!(infos[0].max_token_id, Some(100276));
+        assert_eq!(infos[0].special_tokens[0], ("<|endoftext|>".to_string(), 100257));
+        assert!(infos[0].regex.is_some());
+        assert!(infos[0].memory_usage > 0);
+
+        assert_eq!(infos[1].name, "neox20b");
+        assert_eq!(infos[1].handle, neox20b);
+        assert_eq!(infos[1].kind, TokenizerKind::Huggingface);
+        assert_eq!(infos[1].max_token_id, Some(infos[1].vocab_size - 1));
+        assert!(infos[1].special_tokens.iter().any(|(token, _)| token == "<|endoftext|>"));
+        assert!(infos[1].regex.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vocab() -> Result<(), String> {
+        use crate::TokenizerVariant;
+
+        let tokenizer = TokenizerVariant::TokenizerTiktoken(cl100k()?);
+        assert_eq!(tokenizer.token_to_id(b"Hello"), Some(9906));
+        assert_eq!(tokenizer.token_to_id(b"<|endoftext|>"), Some(100257));
+        assert_eq!(tokenizer.id_to_token_bytes(4435), Some(b" World".to_vec()));
+        assert_eq!(tokenizer.id_to_token_bytes(100257), Some(b"<|endoftext|>".to_vec()));
+        assert_eq!(tokenizer.id_to_token_bytes(100261), None);
+        assert!(tokenizer.is_special(100257) && !tokenizer.is_special(9906));
+        assert_eq!(tokenizer.special_token_id("<|endofprompt|>"), Some(100276));
+        assert_eq!(tokenizer.special_token_id("Hello"), None);
+
+        let tokenizer = TokenizerVariant::TokenizerHuggingface(neox().into());
+        // Byte-level tokens are returned as raw bytes, " World" is spelled "ĠWorld" in the vocab
+        assert_eq!(tokenizer.id_to_token_bytes(3645), Some(b" World".to_vec()));
+        assert_eq!(tokenizer.token_to_id(b" World"), Some(3645));
+        assert_eq!(tokenizer.token_to_id("🚀".as_bytes()), None);
+        let rocket = "🚀".as_bytes();
+        let first = tokenizer.token_to_id(&rocket[..1]).ok_or("Byte tokens should exist")?;
+        assert_eq!(tokenizer.id_to_token_bytes(first), Some(rocket[..1].to_vec()));
+        // Not every byte has a token, as some never occur in UTF-8
+        let bytes = (0..=u8::MAX)
+            .filter_map(|byte| Some((byte, tokenizer.token_to_id(&[byte])?)))
+            .collect::<Vec<_>>();
+        assert!(bytes.len() > 200, "Most bytes should have a token");
+        for (byte, token) in bytes {
+            assert_eq!(tokenizer.id_to_token_bytes(token), Some(vec![byte]));
+        }
+        assert_eq!(tokenizer.id_to_token_bytes(0), Some(b"<|endoftext|>".to_vec()));
+        assert!(tokenizer.is_special(0) && !tokenizer.is_special(3645));
+        assert_eq!(tokenizer.special_token_id("<|endoftext|>"), Some(0));
+        assert_eq!(tokenizer.special_token_id("World"), None);
+        assert_eq!(tokenizer.id_to_token_bytes(u32::MAX), None);
+
+        // SentencePiece style vocabularies spell spaces as "▁" and raw bytes as "<0xXX>"
+        let json = r#"{
+            "version": "1.0",
+            "model": {
+                "type": "BPE",
+                "vocab": {"<unk>": 0, "<0x0A>": 1, "▁": 2, "▁World": 3},
+                "merges": [],
+                "unk_token": "<unk>",
+                "byte_fallback": true
+            },
+            "pre_tokenizer": {"type": "Metaspace", "replacement": "▁", "prepend_scheme": "always"},
+            "decoder": {"type": "Sequence", "decoders": [
+                {"type": "ByteFallback"},
+                {"type": "Metaspace", "replacement": "▁", "prepend_scheme": "always"}
+            ]}
+        }"#;
+        let tokenizer = TokenizerVariant::TokenizerHuggingface(
+            Tokenizer::from_str(json).map_err(|e| e.to_string())?.into(),
+        );
+        assert_eq!(tokenizer.id_to_token_bytes(3), Some(b" World".to_vec()));
+        assert_eq!(tokenizer.id_to_token_bytes(2), Some(b" ".to_vec()));
+        assert_eq!(tokenizer.id_to_token_bytes(1), Some(b"\n".to_vec()));
+        assert_eq!(tokenizer.token_to_id(b" World"), Some(3));
+        assert_eq!(tokenizer.token_to_id(b"\n"), Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_search() -> Result<(), String> {
+        use crate::TokenizerVariant;
+
+        let tokenizers = [
+            TokenizerVariant::TokenizerTiktoken(cl100k()?),
+            TokenizerVariant::TokenizerHuggingface(neox().into()),
+        ];
+        for tokenizer in tokenizers {
+            let world = tokenizer.token_to_id(b" World").ok_or("Token should exist")?;
+            let tokens = tokenizer.tokens_with_prefix(b" Wor");
+            println!("Tokens: {}", tokens.len());
+            assert!(tokens.contains(&world), "Tokens should contain \" World\"");
+            assert!(tokens.windows(2).all(|x| x[0] < x[1]), "Tokens should be sorted");
+            for token in &tokens {
+                assert!(tokenizer.id_to_token_bytes(*token).unwrap().starts_with(b" Wor"));
+            }
+
+            let prefixes = tokenizer.prefix_tokens(b" Worldwide");
+            println!("Prefixes: {:?}", prefixes);
+            assert_eq!(prefixes.first(), tokenizer.token_to_id(b" ").as_ref());
+            assert!(prefixes.contains(&world), "Prefixes should contain \" World\"");
+            assert!(
+                tokenizer
+                    .prefix_tokens(b"<|endoftext|>")
+                    .iter()
+                    .all(|x| !tokenizer.is_special(*x))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate() -> Result<(), String> {
+        use crate::{
+            EncodeOptions, TokenizerVariant,
+            truncate::{TruncationDirection, truncate},
+        };
+
+        let tokenizers = [
+            TokenizerVariant::TokenizerTiktoken(cl100k()?),
+            TokenizerVariant::TokenizerHuggingface(neox().into()),
+        ];
+        let options = EncodeOptions::default();
+        let text = "Hello 🚀🚀🚀 World! This is a longer sentence that needs to be truncated.";
+        for tokenizer in tokenizers {
+            let total = tokenizer.count(text, &options)?;
+            let result =
+                truncate(&tokenizer, text, total, TruncationDirection::Head, "…", &options)?;
+            assert!(!result.truncated && result.text == text, "Text that fits should be kept");
+
+            for direction in [
+                TruncationDirection::Head,
+                TruncationDirection::Tail,
+                TruncationDirection::Both,
+            ] {
+                for max_tokens in 0..total {
+                    let result = truncate(&tokenizer, text, max_tokens, direction, "…", &options)?;
+                    assert!(result.truncated);
+                    assert!(
+                        result.tokens.len() <= max_tokens,
+                        "{:?} should fit {}",
+                        result,
+                        max_tokens
+                    );
+                    assert_eq!(tokenizer.encode(&result.text, &options)?, result.tokens);
+                    match direction {
+                        TruncationDirection::Head => assert!(text.starts_with(&result.text)),
+                        TruncationDirection::Tail => assert!(text.ends_with(&result.text)),
+                        TruncationDirection::Both if max_tokens > 2 => {
+                            assert!(
+                                result.text.contains('…'),
+                                "{:?} should contain the ellipsis",
+                                result
+                            )
+                        }
+                        TruncationDirection::Both => {}
+                    }
+                }
+            }
+            let result = truncate(&tokenizer, text, 8, TruncationDirection::Both, "…", &options)?;
+            println!("Truncated: {:?}", result.text);
+            assert!(result.text.starts_with("Hello") && result.text.ends_with("truncated."));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk() -> Result<(), String> {
+        use crate::{chunk::chunk, EncodeOptions, TokenizerVariant};
+
+        let tokenizers = [
+            TokenizerVariant::TokenizerTiktoken(cl100k()?),
+            TokenizerVariant::TokenizerHuggingface(neox().into()),
+        ];
+        let options = EncodeOptions::default();
+        let text = "The first paragraph has two sentences. This is the second one.\n\n\
+                    The second paragraph talks about rockets 🚀🚀 and stars. It also has a \
+                    second sentence! And a third one, which is quite a bit longer than the others.";
+        for tokenizer in tokenizers {
+            let total = tokenizer.count(text, &options)?;
+            for (max_tokens, overlap) in [(8, 0), (8, 3), (16, 4), (30, 0), (1000, 10)] {
+                let chunks =
+                    chunk(&tokenizer, text, max_tokens, overlap, max_tokens / 4, &options)?;
+                assert_eq!(chunks.first().map(|x| x.tokens.0), Some(0));
+                assert_eq!(chunks.last().map(|x| x.tokens.1), Some(total));
+                for chunk in &chunks {
+                    assert!(
+                        chunk.tokens.1 - chunk.tokens.0 <= max_tokens,
+                        "{:?} is too long",
+                        chunk
+                    );
+                    assert!(
+                        text.is_char_boundary(chunk.bytes.0)
+                            && text.is_char_boundary(chunk.bytes.1)
+                    );
+                }
+                for pair in chunks.windows(2) {
+                    assert!(pair[1].tokens.0 > pair[0].tokens.0, "Chunks should advance");
+                    assert!(
+                        pair[1].tokens.0 + overlap >= pair[0].tokens.1,
+                        "Chunks should be connected"
+                    );
+                    assert!(pair[1].tokens.0 <= pair[0].tokens.1, "Chunks should not leave gaps");
+                }
+            }
+
+            let chunks = chunk(&tokenizer, text, 20, 0, 10, &options)?;
+            let parts = chunks.iter().map(|x| &text[x.bytes.0..x.bytes.1]).collect::<Vec<_>>();
+            println!("Chunks: {:?}", parts);
+            assert!(
+                parts[0].trim_end().ends_with("second one."),
+                "First chunk should end with the paragraph"
+            );
+            assert!(parts[1].trim_start().starts_with("The second"));
+            assert!(chunk(&tokenizer, text, 4, 4, 0, &options).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chat() -> Result<(), String> {
+        use crate::{
+            TokenizerVariant,
+            chat::{ChatFormat, ChatMessage, render_chat},
+        };
+
+        // The API renders the markers with ChatML tokens the preset doesn't include
+        let tokenizer = TokenizerVariant::TokenizerTiktoken(CoreBPE::new(
+            load_bpe(CL100K)?,
+            HashMap::from([
+                ("<|endoftext|>".to_string(), 100257),
+                ("<|im_start|>".to_string(), 100264),
+                ("<|im_end|>".to_string(), 100265),
+            ]),
+            crate::presets::find_preset("cl100k_base").unwrap().pattern,
+        )?);
+        let message = |role: &str, name: Option<&str>, content: &str| ChatMessage {
+            role:    role.to_string(),
+            name:    name.map(str::to_string),
+            content: content.to_string(),
+        };
+        let messages = [
+            message("system", None, "You are a helpful assistant."),
+            message("user", Some("example_user"), "Hello <|im_end|> World!"),
+        ];
+        let rendered = render_chat(&tokenizer, &messages, &ChatFormat::default(), true)?;
+        println!("Text: {:?}", rendered.text);
+        println!("Counts: {:?} {}", rendered.message_tokens, rendered.reply_tokens);
+        assert_eq!(
+            rendered.text,
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\
+             <|im_start|>user:example_user\nHello <|im_end|> World!<|im_end|>\
+             <|im_start|>assistant\n"
+        );
+
+        // Matches the billing rule of 3 tokens per message, 1 per name and 3 for the reply
+        let count =
+            |text: &str| tokenizer.count(text, &crate::EncodeOptions::new(Some(false), None, None));
+        assert_eq!(
+            rendered.message_tokens[0],
+            3 + count("system")? + count("You are a helpful assistant.")?
+        );
+        assert_eq!(
+            rendered.message_tokens[1],
+            4 + count("user")? + count("example_user")? + count("Hello <|im_end|> World!")?
+        );
+        assert_eq!(rendered.reply_tokens, 3);
+        assert_eq!(rendered.tokens.len(), rendered.message_tokens.iter().sum::<usize>() + 3);
+        assert_eq!(
+            rendered.tokens.iter().filter(|x| **x == 100265).count(),
+            2,
+            "Content should stay text"
+        );
+
+        let rendered = render_chat(&tokenizer, &messages, &ChatFormat::default(), false)?;
+        assert_eq!(rendered.reply_tokens, 0);
+        assert!(!rendered.text.ends_with("assistant\n"));
+
+        // The preset has no ChatML tokens, the markers are rendered as text but billed the same
+        let preset = TokenizerVariant::TokenizerTiktoken(cl100k()?);
+        let rendered = render_chat(&preset, &messages, &ChatFormat::default(), true)?;
+        println!("Preset counts: {:?} {}", rendered.message_tokens, rendered.reply_tokens);
+        assert_eq!(
+            rendered.message_tokens[0],
+            3 + count("system")? + count("You are a helpful assistant.")?
+        );
+        assert_eq!(
+            rendered.message_tokens[1],
+            4 + count("user")? + count("example_user")? + count("Hello <|im_end|> World!")?
+        );
+        assert_eq!(rendered.reply_tokens, 3);
+        assert!(rendered.tokens.len() > rendered.message_tokens.iter().sum::<usize>() + 3);
+        let format = ChatFormat {
+            tokens_per_message: 4,
+            tokens_per_name: 0,
+            reply_tokens: 2,
+            ..Default::default()
+        };
+        let rendered = render_chat(&preset, &messages, &format, true)?;
+        assert_eq!(
+            rendered.message_tokens[1],
+            4 + count("user")? + count("example_user")? + count("Hello <|im_end|> World!")?
+        );
+        assert_eq!(rendered.reply_tokens, 2);
+
+        // Hugging Face tokenizers match added tokens in the content as well
+        let huggingface = TokenizerVariant::TokenizerHuggingface(neox().into());
+        let messages = [message("user", None, "hello <|endoftext|>")];
+        let rendered = render_chat(&huggingface, &messages, &ChatFormat::default(), false)?;
+        println!("Tokens: {:?}", rendered.tokens);
+        assert_eq!(rendered.tokens.iter().filter(|x| **x == 0).count(), 1);
+        assert_eq!(rendered.message_tokens[0], 3 + 1 + 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tools() -> Result<(), String> {
+        use crate::{
+            TokenizerVariant,
+            chat::{ChatFormat, ChatMessage, render_chat},
+            tools::{
+                EnumValue, FunctionDefinition, JsonSchema, SchemaType, ToolDefinition,
+                count_tool_tokens,
+            },
+        };
+
+        let tokenizer = TokenizerVariant::TokenizerTiktoken(cl100k()?);
+        let schema = |kind: &str| JsonSchema {
+            kind: Some(SchemaType::Single(kind.to_string())),
+            ..Default::default()
+        };
+        let parameters = JsonSchema {
+            properties: Some(vec![
+                ("location".to_string(), JsonSchema {
+                    description: Some("The city and state, e.g. San Francisco, CA".to_string()),
+                    ..schema("string")
+                }),
+                ("unit".to_string(), JsonSchema {
+                    values: Some(vec![
+                        EnumValue::String("celsius".to_string()),
+                        EnumValue::String("fahrenheit".to_string()),
+                    ]),
+                    ..schema("string")
+                }),
+                ("options".to_string(), JsonSchema {
+                    description: Some("Extra options".to_string()),
+                    properties: Some(vec![("days".to_string(), JsonSchema {
+                        description: Some("Not rendered this deep".to_string()),
+                        items: Some(Box::new(schema("integer"))),
+                        ..schema("array")
+                    })]),
+                    required: Some(vec!["days".to_string()]),
+                    ..schema("object")
+                }),
+            ]),
+            required: Some(vec!["location".to_string()]),
+            ..schema("object")
+        };
+        let tools = [
+            ToolDefinition::Tool {
+                function: FunctionDefinition {
+                    name:        "get_current_weather".to_string(),
+                    description: Some("Get the current weather in a given location".to_string()),
+                    parameters:  Some(parameters),
+                },
+            },
+            ToolDefinition::Function(FunctionDefinition {
+                name:        "get_time".to_string(),
+                description: None,
+                parameters:  None,
+            }),
+        ];
+        let result = count_tool_tokens(&tokenizer, &tools, false)?;
+        println!("Text: {}", result.text);
+        assert_eq!(
+            result.text,
+            "namespace functions {\n\n\
+             // Get the current weather in a given location\n\
+             type get_current_weather = (_: {\n\
+             // The city and state, e.g. San Francisco, CA\n\
+             location: string,\n\
+             unit?: \"celsius\" | \"fahrenheit\",\n\
+             // Extra options\n\
+             options?: {\n  \
+               days: number[],\n\
+             },\n\
+             }) => any;\n\n\
+             type get_time = () => any;\n\n\
+             } // namespace functions"
+        );
+        assert_eq!(
+            result.text_tokens,
+            tokenizer.count(&result.text, &crate::EncodeOptions::new(Some(false), None, None))?
+        );
+        assert_eq!(result.prompt_tokens, result.text_tokens + 9);
+        let result = count_tool_tokens(&tokenizer, &tools, true)?;
+        assert_eq!(result.prompt_tokens, result.text_tokens + 5);
+        let result = count_tool_tokens(&tokenizer, &[], false)?;
+        assert_eq!(result.prompt_tokens, 0);
+
+        // Hosts send the properties as an object, in msgpack like they do in JSON
+        let json = r#"[
+            {
+                "type": "function",
+                "function": {
+                    "name": "get_current_weather",
+                    "description": "Get the current weather in a given location",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "location": {
+                                "type": "string",
+                                "description": "The city and state, e.g. San Francisco, CA"
+                            },
+                            "unit": {"type": "string", "enum": ["celsius", "fahrenheit"]},
+                            "options": {
+                                "type": "object",
+                                "description": "Extra options",
+                                "properties": {
+                                    "days": {
+                                        "type": "array",
+                                        "description": "Not rendered this deep",
+                                        "items": {"type": "integer"}
+                                    }
+                                },
+                                "required": ["days"]
+                            }
+                        },
+                        "required": ["location"]
+                    }
+                }
+            },
+            {"name": "get_time"}
+        ]"#;
+        let parsed =
+            serde_json::from_str::<Vec<ToolDefinition>>(json).map_err(|x| x.to_string())?;
+        assert_eq!(parsed, tools);
+        let packed = rmp_serde::to_vec_named(&parsed).map_err(|x| x.to_string())?;
+        assert_eq!(crate::deserialize::<Vec<ToolDefinition>>(&packed)?, tools);
+
+        // Type lists, null and escaped enum values, unions and references
+        let json = r##"[{
+            "name": "search",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "query": {"type": ["string", "null"]},
+                    "mode": {"enum": ["say \"hi\"", 1, null]},
+                    "limit": {"anyOf": [{"type": "integer"}, {"type": "string", "enum": ["all"]}]},
+                    "sort": {"oneOf": [{"$ref": "#/$defs/Order"}, {"type": "boolean"}]},
+                    "filter": {"$ref": "#/$defs/Filter"},
+                    "extra": {}
+                },
+                "required": ["query"]
+            }
+        }]"##;
+        let parsed =
+            serde_json::from_str::<Vec<ToolDefinition>>(json).map_err(|x| x.to_string())?;
+        let result = count_tool_tokens(&tokenizer, &parsed, false)?;
+        println!("Text: {}", result.text);
+        assert_eq!(
+            result.text,
+            "namespace functions {\n\n\
+             type search = (_: {\n\
+             query: string | null,\n\
+             mode?: \"say \\\"hi\\\"\" | 1 | null,\n\
+             limit?: number | \"all\",\n\
+             sort?: Order | boolean,\n\
+             filter?: Filter,\n\
+             extra?: any,\n\
+             }) => any;\n\n\
+             } // namespace functions"
+        );
+
+        // Prompt token counts the API reports for a user message "hello" with these functions
+        let messages = [ChatMessage {
+            role:    "user".to_string(),
+            name:    None,
+            content: "hello".to_string(),
+        }];
+        let chat = render_chat(&tokenizer, &messages, &ChatFormat::default(), true)?;
+        let chat_tokens = chat.message_tokens.iter().sum::<usize>() + chat.reply_tokens;
+        for (json, expected) in [
+            (r#"{"name": "foo", "parameters": {"type": "object", "properties": {}}}"#, 31),
+            (
+                r#"{"name": "foo", "description": "Do a foo",
+                    "parameters": {"type": "object", "properties": {}}}"#,
+                36,
+            ),
+            (
+                r#"{"name": "bing_bong", "description": "Do a bing bong",
+                    "parameters": {"type": "object", "properties": {"foo": {"type": "string"}}}}"#,
+                49,
+            ),
+        ] {
+            let tool = serde_json::from_str::<ToolDefinition>(json).map_err(|x| x.to_string())?;
+            let result = count_tool_tokens(&tokenizer, &[tool], false)?;
+            assert_eq!(chat_tokens + result.prompt_tokens, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_template() -> Result<(), String> {
+        use crate::template::{Template, Value};
+
+        let render = |source: &str, variables: Vec<(String, Value)>| -> Result<String, String> {
+            Ok(Template::parse(source)?.render(variables)?)
+        };
+        let items = || {
+            vec![(
+                "items".to_string(),
+                Value::from(vec![Value::from("a"), Value::from("b"), Value::from("c")]),
+            )]
+        };
+
+        // Blocks on their own line leave neither indentation nor newline behind
+        assert_eq!(
+            render(
+                "{% for x in items %}\n  {% if not loop.last %}\n{{ x }},{% endif %}\n{% endfor %}",
+                items()
+            )?,
+            "a,b,"
+        );
+        assert_eq!(render("{{- ' a ' -}}  \n  {{ 'b' }}", vec![])?, " a b");
+        assert_eq!(render("{# comment #}{{ items[1:] | join('-') }}", items())?, "b-c");
+        assert_eq!(
+            render("{{ items[::-1] }} {{ items[-1] | upper }}", items())?,
+            "['c', 'b', 'a'] C"
+        );
+        assert_eq!(
+            render(
+                "{% set ns = namespace(found=false) %}{% for x in items if x != 'a' %}\
+                 {% set ns.found = true %}{% set local = x %}{% endfor %}{{ ns.found }} {{ local is defined }}",
+                items()
+            )?,
+            "True False"
+        );
+        assert_eq!(
+            render(
+                "{{ 7 // 2 }} {{ -7 // 2 }} {{ -7 % 3 }} {{ 2 ** 3 }} {{ 1 / 2 }} {{ 'ab' * 2 }}",
+                vec![]
+            )?,
+            "3 -4 2 8 0.5 abab"
+        );
+        assert_eq!(
+            render(
+                "{{ {'a': [1, 'x', none]} | tojson }} {{ ' x '.strip() ~ 'y' if 'b' in items else 'z' }}",
+                items()
+            )?,
+            "{\"a\": [1, \"x\", null]} xy"
+        );
+        assert!(
+            render("{{ raise_exception('Roles must alternate') }}", vec![])
+                .unwrap_err()
+                .contains("Roles must alternate")
+        );
+        assert!(render("{% if true %}", vec![]).is_err());
+
+        // Integer division overflows and templates that would exhaust memory or the stack fail
+        let min = || vec![("min".to_string(), Value::Int(i64::MIN))];
+        assert_eq!(
+            render("{{ 7 // -3 }} {{ 7 % -3 }} {{ min // 1 }}", min())?,
+            "-3 -2 -9223372036854775808"
+        );
+        assert!(render("{{ min // -1 }}", min()).unwrap_err().contains("overflow"));
+        assert!(render("{{ min % -1 }}", min()).unwrap_err().contains("overflow"));
+        assert!(render("{{ 'ab' * 100000000 }}", vec![]).is_err());
+        assert_eq!(
+            render("{{ range(5, -5, -3) | join(',') }} {{ range(3, 1) | length }}", vec![])?,
+            "5,2,-1,-4 0"
+        );
+        assert!(render("{{ range(100000000) | length }}", vec![]).is_err());
+        assert!(render("{{ range(-9223372036854775807, 9223372036854775807) }}", vec![]).is_err());
+        let nested = |open: &str, close: &str, depth: usize| {
+            render(&format!("{{{{ {}1{} }}}}", open.repeat(depth), close.repeat(depth)), vec![])
+        };
+        assert_eq!(nested("(", ")", 32)?, "1");
+        assert_eq!(nested("[", "]", 32)?.len(), 65);
+        assert!(nested("(", ")", 1000).unwrap_err().contains("nested"));
+        assert!(nested("[", "]", 1000).unwrap_err().contains("nested"));
+        assert!(nested("not ", "", 1000).unwrap_err().contains("nested"));
+        assert!(nested("-", "", 1000).unwrap_err().contains("nested"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chat_template() -> Result<(), String> {
+        use crate::{
+            TokenizerConfig, TokenizerVariant,
+            chat::{ChatMessage, apply_chat_template},
+            huggingface::HuggingfaceTokenizer,
+        };
+
+        // The Llama 2 and Qwen 2 templates as published in their tokenizer configs
+        let config = TokenizerConfig::from_bytes(
+            br#"{
+                "bos_token": {"__type": "AddedToken", "content": "<s>", "special": true},
+                "eos_token": "</s>",
+                "chat_template": [
+                    {"name": "default", "template": "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = messages[0]['content'] %}{% else %}{% set loop_messages = messages %}{% set system_message = false %}{% endif %}{% for message in loop_messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if loop.index0 == 0 and system_message != false %}{% set content = '<<SYS>>\\n' + system_message + '\\n<</SYS>>\\n\\n' + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content.strip() + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' '  + content.strip() + ' ' + eos_token }}{% endif %}{% endfor %}"},
+                    {"name": "qwen", "template": "{% for message in messages %}{% if loop.first and messages[0]['role'] != 'system' %}{{ '<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n' }}{% endif %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}"}
+                ]
+            }"#,
+        )?;
+        let tokenizer =
+            TokenizerVariant::TokenizerHuggingface(HuggingfaceTokenizer::new(neox(), config));
+        let message = |role: &str, content: &str| ChatMessage {
+            role:    role.to_string(),
+            name:    None,
+            content: content.to_string(),
+        };
+        let messages = [
+            message("system", "Be brief."),
+            message("user", "Hello!"),
+            message("assistant", " Hi. "),
+            message("user", "Bye!"),
+        ];
+
+        let prompt = apply_chat_template(&tokenizer, &messages, None, false)?;
+        println!("Prompt: {:?}", prompt.text);
+        assert_eq!(
+            prompt.text,
+            "<s>[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHello! [/INST] Hi. </s><s>[INST] Bye! [/INST]"
+        );
+        assert_eq!(
+            prompt.tokens,
+            tokenizer.encode(&prompt.text, &crate::EncodeOptions::default())?
+        );
+
+        let prompt = apply_chat_template(&tokenizer, &messages[1..2], Some("qwen"), true)?;
+        assert_eq!(
+            prompt.text,
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n\
+             <|im_start|>user\nHello!<|im_end|>\n<|im_start|>assistant\n"
+        );
+        let prompt = apply_chat_template(
+            &tokenizer,
+            &messages[1..2],
+            Some("{{ messages | length }}"),
+            true,
+        )?;
+        assert_eq!(prompt.text, "1");
+
+        assert!(apply_chat_template(&tokenizer, &messages[1..], Some("default"), false).is_ok());
+        let error = apply_chat_template(&tokenizer, &messages[2..], None, false).unwrap_err();
+        assert!(error.to_string().contains("Conversation roles must alternate"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundle() -> Result<(), String> {
+        use crate::{list_tokenizers, load_tokenizer, LoadTokenizerVariant, TokenizerError};
+
+        let handle = load_tokenizer(
+            "neox20b".to_string(),
+            LoadTokenizerVariant::LoadTokenizerHuggingface {
+                model:              NEOX20B.to_vec(),
+                config:             Some(
+                    br#"{
+                        "add_bos_token": false,
+                        "add_eos_token": false,
+                        "bos_token": "<|endoftext|>",
+                        "eos_token": {"__type": "AddedToken", "content": "<|endoftext|>"},
+                        "unk_token": "<unk>",
+                        "model_max_length": 2048
+                    }"#
+                    .to_vec(),
+                ),
+                special_tokens_map: Some(
+                    br#"{
+                        "unk_token": "<|endoftext|>",
+                        "pad_token": {"content": "<|padding|>", "lstrip": false},
+                        "additional_special_tokens": ["<|extra|>"]
+                    }"#
+                    .to_vec(),
+                ),
+            },
+        )?;
+
+        let info = list_tokenizers().into_iter().find(|x| x.handle == handle).unwrap();
+        let roles = info
+            .special_token_roles
+            .iter()
+            .map(|x| (x.role.as_str(), x.token.as_str(), x.id))
+            .collect::<Vec<_>>();
+        println!("Roles: {:?}", roles);
+        assert_eq!(roles, [
+            ("bos_token", "<|endoftext|>", Some(0)),
+            ("eos_token", "<|endoftext|>", Some(0)),
+            ("unk_token", "<|endoftext|>", Some(0)),
+            ("pad_token", "<|padding|>", Some(1)),
+            ("additional_special_tokens", "<|extra|>", None),
+        ]);
+        assert_eq!(info.max_length, Some(2048));
+        assert_eq!(info.add_bos_token, Some(false));
+        assert_eq!(info.add_eos_token, Some(false));
+
+        // Configs without a bounded length store `int(1e30)` instead
+        let handle = load_tokenizer(
+            "unbounded".to_string(),
+            LoadTokenizerVariant::LoadTokenizerHuggingface {
+                model:              NEOX20B.to_vec(),
+                config:             Some(
+                    br#"{"model_max_length": 1000000000000000019884624838656}"#.to_vec(),
+                ),
+                special_tokens_map: None,
+            },
+        )?;
+        let info = list_tokenizers().into_iter().find(|x| x.handle == handle).unwrap();
+        assert_eq!(info.max_length, None);
+        assert!(info.special_token_roles.is_empty());
+
+        let broken =
+            load_tokenizer("broken".to_string(), LoadTokenizerVariant::LoadTokenizerHuggingface {
+                model:              NEOX20B.to_vec(),
+                config:             None,
+                special_tokens_map: Some(b"{".to_vec()),
+            });
+        assert!(matches!(broken, Err(TokenizerError::LoadFailed { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_masks() -> Result<(), String> {
+        use crate::{
+            EncodeOptions, TokenizerError, TokenizerVariant,
+            huggingface::{
+                EncodingLayout, HuggingfaceTokenizer, PaddingSide, PaddingStrategy,
+                TokenizerConfig, TruncationStrategy,
+            },
+        };
+        use tokenizers::{
+            PaddingParams, PostProcessorWrapper, TruncationParams,
+            processors::template::TemplateProcessing,
+        };
+
+        let config = TokenizerConfig::from_bytes(br#"{"pad_token": "<|padding|>"}"#)?;
+        let tokenizer =
+            TokenizerVariant::TokenizerHuggingface(HuggingfaceTokenizer::new(neox(), config));
+        let texts = [
+            "Hello World".to_string(),
+            "This is a longer sentence".to_string(),
+        ];
+        let short = tokenizer.encode("Hello World", &EncodeOptions::default())?;
+        let long = tokenizer.encode("This is a longer sentence", &EncodeOptions::default())?;
+
+        // Without a layout every encoding is left as is
+        let result = tokenizer.encode_with_masks(&texts, None, true, &EncodingLayout::default())?;
+        assert_eq!(result[0].tokens, short);
+        assert_eq!(result[1].tokens, long);
+        assert!(result.iter().all(|x| x.attention_mask.iter().all(|x| *x == 1)));
+        assert!(result.iter().all(|x| x.overflowing.is_empty()));
+
+        for (side, padded) in [
+            (PaddingSide::Right, short.len()..long.len()),
+            (PaddingSide::Left, 0..long.len() - short.len()),
+        ] {
+            let layout = EncodingLayout {
+                padding: Some(PaddingStrategy::Longest),
+                padding_side: side,
+                ..Default::default()
+            };
+            let result = tokenizer.encode_with_masks(&texts, None, true, &layout)?;
+            println!("Padded {:?}: {:?}", side, result[0]);
+            assert!(result.iter().all(|x| x.tokens.len() == long.len()));
+            assert_eq!(result[1].tokens, long);
+            for (i, (token, mask)) in
+                result[0].tokens.iter().zip(&result[0].attention_mask).enumerate()
+            {
+                assert_eq!(*mask == 0, padded.contains(&i));
+                assert_eq!(*token == 1, padded.contains(&i));
+            }
+        }
+
+        let layout = EncodingLayout {
+            padding: Some(PaddingStrategy::Longest),
+            pad_to_multiple_of: Some(8),
+            ..Default::default()
+        };
+        let result = tokenizer.encode_with_masks(&texts, None, true, &layout)?;
+        assert!(result.iter().all(|x| x.tokens.len() == long.len().next_multiple_of(8)));
+
+        // Truncation keeps the cut off tokens in overflowing windows overlapping by the stride
+        let layout = EncodingLayout {
+            max_length: Some(3),
+            stride: 1,
+            padding: Some(PaddingStrategy::MaxLength),
+            ..Default::default()
+        };
+        let result = tokenizer.encode_with_masks(&texts[1..], None, true, &layout)?;
+        println!("Truncated: {:?}", result[0]);
+        assert_eq!(result[0].tokens, long[..3]);
+        assert!(!result[0].overflowing.is_empty());
+        let mut windows = vec![result[0].tokens.clone()];
+        windows.extend(result[0].overflowing.iter().map(|x| x.tokens.clone()));
+        for window in windows.windows(2) {
+            assert_eq!(window[0].last(), window[1].first());
+        }
+        assert!(result[0].overflowing.iter().all(|x| x.tokens.len() == 3));
+
+        // Pairs are told apart by their type ids
+        let pairs = ["Hi".to_string(), "Hi".to_string()];
+        let result =
+            tokenizer.encode_with_masks(&texts, Some(&pairs), true, &EncodingLayout::default())?;
+        println!("Pair: {:?}", result[0]);
+        assert_eq!(result[0].tokens.len(), short.len() + 1);
+        assert_eq!(result[0].type_ids[..short.len()], vec![0; short.len()]);
+        assert_eq!(result[0].type_ids[short.len()..], [1]);
+        let layout = EncodingLayout {
+            max_length: Some(4),
+            truncation: TruncationStrategy::OnlyFirst,
+            ..Default::default()
+        };
+        let result = tokenizer.encode_with_masks(&texts[1..], Some(&pairs[1..]), true, &layout)?;
+        assert_eq!(result[0].tokens, [&long[..3], &[result[0].tokens[3]]].concat());
+
+        assert!(matches!(
+            tokenizer.encode_with_masks(
+                &texts,
+                Some(&pairs[..1]),
+                true,
+                &EncodingLayout::default()
+            ),
+            Err(TokenizerError::MalformedInput { .. })
+        ));
+        let layout = EncodingLayout {
+            max_length: Some(2),
+            stride: 2,
+            ..Default::default()
+        };
+        assert!(matches!(
+            tokenizer.encode_with_masks(&texts, None, true, &layout),
+            Err(TokenizerError::MalformedInput { .. })
+        ));
+        let layout = EncodingLayout {
+            padding: Some(PaddingStrategy::MaxLength),
+            ..Default::default()
+        };
+        assert!(matches!(
+            tokenizer.encode_with_masks(&texts, None, true, &layout),
+            Err(TokenizerError::MalformedInput { .. })
+        ));
+        let layout = EncodingLayout {
+            padding: Some(PaddingStrategy::Longest),
+            pad_token: Some("<|missing|>".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            tokenizer.encode_with_masks(&texts, None, true, &layout),
+            Err(TokenizerError::MalformedInput { .. })
+        ));
+
+        // Tokens added by the post-processor count towards the max length only when added
+        let mut processed = neox();
+        processed.with_post_processor(PostProcessorWrapper::from(
+            TemplateProcessing::builder()
+                .try_single("<|endoftext|> $A <|endoftext|>")
+                .map_err(|e| e.to_string())?
+                .special_tokens(vec![("<|endoftext|>", 0)])
+                .build()
+                .map_err(|e| e.to_string())?,
+        ));
+        let tokenizer = TokenizerVariant::TokenizerHuggingface(processed.into());
+        let layout = |max_length| EncodingLayout {
+            max_length: Some(max_length),
+            ..Default::default()
+        };
+        let result = tokenizer.encode_with_masks(&texts[1..], None, true, &layout(3))?;
+        assert_eq!(result[0].tokens, [0, long[0], 0]);
+        assert_eq!(result[0].special_tokens_mask, [1, 0, 1]);
+        assert!(matches!(
+            tokenizer.encode_with_masks(&texts[1..], None, true, &layout(2)),
+            Err(TokenizerError::MalformedInput { .. })
+        ));
+        let result = tokenizer.encode_with_masks(&texts[1..], None, false, &layout(2))?;
+        assert_eq!(result[0].tokens, long[..2]);
+
+        // Truncation and padding from tokenizer.json are replaced by the layout, not applied twice
+        let mut configured = neox();
+        configured
+            .with_truncation(Some(TruncationParams {
+                max_length: 2,
+                ..Default::default()
+            }))
+            .with_padding(Some(PaddingParams {
+                strategy: tokenizers::PaddingStrategy::Fixed(16),
+                pad_id: 1,
+                pad_token: "<|padding|>".to_string(),
+                ..Default::default()
+            }));
+        let json = configured.to_string(false).map_err(|e| e.to_string())?;
+        let loaded = Tokenizer::from_str(&json).map_err(|e| e.to_string())?;
+        assert_eq!(loaded.encode("This is a longer sentence", false).unwrap().get_ids().len(), 16);
+        let tokenizer = TokenizerVariant::TokenizerHuggingface(loaded.into());
+        let result = tokenizer.encode_with_masks(&texts[1..], None, true, &layout(4))?;
+        assert_eq!(result[0].tokens, long[..4]);
+        let result = tokenizer.encode_with_masks(&texts, None, true, &EncodingLayout::default())?;
+        assert_eq!(result[0].tokens, short);
+        assert_eq!(result[1].tokens, long);
+
+        let tokenizer = TokenizerVariant::TokenizerTiktoken(cl100k()?);
+        assert!(matches!(
+            tokenizer.encode_with_masks(&texts, None, true, &EncodingLayout::default()),
+            Err(TokenizerError::Unsupported { .. })
+        ));
+
+        Ok(())
+    }
 }
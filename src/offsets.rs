@@ -0,0 +1,44 @@
+/// Converts byte offsets into `text` to offsets counted in Unicode scalar values.
+///
+/// Offsets that fall inside of a character are widened to cover the whole character. Tokens that
+/// split a character therefore overlap: every token holding some of its bytes spans all of it.
+/// In `"Hällo 🚀"`, where `ä` takes two bytes and `🚀` four, cl100k splits `" 🚀"` into the byte
+/// offsets `(6,9),(9,10),(10,11)`, which become the char offsets `(5,7),(6,7),(6,7)`.
+pub fn byte_to_char_offsets(text: &str, offsets: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    convert_offsets(text, offsets, |_| 1)
+}
+
+/// Converts byte offsets into `text` to offsets counted in UTF-16 code units.
+///
+/// Offsets that fall inside of a character are widened to cover the whole character, so a
+/// character outside of the BMP that is split across tokens never yields a lone surrogate. The
+/// tokens overlap instead: the byte offsets `(6,9),(9,10),(10,11)` of `" 🚀"` in `"Hällo 🚀"`
+/// become the UTF-16 offsets `(5,8),(6,8),(6,8)`, as `🚀` takes two code units.
+pub fn byte_to_utf16_offsets(text: &str, offsets: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    convert_offsets(text, offsets, char::len_utf16)
+}
+
+fn convert_offsets(
+    text: &str, offsets: &[(usize, usize)], unit_len: impl Fn(char) -> usize,
+) -> Vec<(usize, usize)> {
+    // Maps every byte position to the number of units preceding the character it belongs to
+    let mut lookup = Vec::with_capacity(text.len() + 1);
+    let mut units = 0;
+    for c in text.chars() {
+        lookup.extend(std::iter::repeat(units).take(c.len_utf8()));
+        units += unit_len(c);
+    }
+    lookup.push(units);
+
+    let ceil_boundary = |mut index: usize| {
+        index = index.min(text.len());
+        while !text.is_char_boundary(index) {
+            index += 1;
+        }
+        index
+    };
+    offsets
+        .iter()
+        .map(|&(start, end)| (lookup[start.min(text.len())], lookup[ceil_boundary(end)]))
+        .collect()
+}
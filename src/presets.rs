@@ -0,0 +1,179 @@
+// Encoding definitions adopted from https://github.com/openai/tiktoken
+// Adopted parts: Copyright (c) 2022 OpenAI, Shantanu Jain, MIT License
+
+use std::collections::HashMap;
+
+use crate::{error::TokenizerError, tiktoken::CoreBPE};
+
+const R50K_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+const O200K_PATTERN: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}",
+    r"| ?[^\s\p{L}\p{N}]+[\r\n/]*",
+    r"|\s*[\r\n]+",
+    r"|\s+(?!\S)",
+    r"|\s+",
+);
+
+/// Canonical regex and special tokens of a known tiktoken encoding.
+#[derive(Debug)]
+pub struct EncodingPreset {
+    pub name:           &'static str,
+    pub pattern:        &'static str,
+    pub special_tokens: &'static [(&'static str, u32)],
+    // Total number of tokens, if the encoding defines it
+    pub n_vocab:        Option<usize>,
+}
+
+pub static PRESETS: &[EncodingPreset] = &[
+    EncodingPreset {
+        name:           "r50k_base",
+        pattern:        R50K_PATTERN,
+        special_tokens: &[("<|endoftext|>", 50256)],
+        n_vocab:        Some(50257),
+    },
+    EncodingPreset {
+        name:           "p50k_base",
+        pattern:        R50K_PATTERN,
+        special_tokens: &[("<|endoftext|>", 50256)],
+        n_vocab:        Some(50281),
+    },
+    EncodingPreset {
+        name:           "p50k_edit",
+        pattern:        R50K_PATTERN,
+        special_tokens: &[
+            ("<|endoftext|>", 50256),
+            ("<|fim_prefix|>", 50281),
+            ("<|fim_middle|>", 50282),
+            ("<|fim_suffix|>", 50283),
+        ],
+        n_vocab:        None,
+    },
+    EncodingPreset {
+        name:           "cl100k_base",
+        pattern:        CL100K_PATTERN,
+        special_tokens: &[
+            ("<|endoftext|>", 100257),
+            ("<|fim_prefix|>", 100258),
+            ("<|fim_middle|>", 100259),
+            ("<|fim_suffix|>", 100260),
+            ("<|endofprompt|>", 100276),
+        ],
+        n_vocab:        None,
+    },
+    EncodingPreset {
+        name:           "o200k_base",
+        pattern:        O200K_PATTERN,
+        special_tokens: &[("<|endoftext|>", 199999), ("<|endofprompt|>", 200018)],
+        n_vocab:        None,
+    },
+];
+
+pub fn find_preset(name: &str) -> Option<&'static EncodingPreset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
+
+impl EncodingPreset {
+    /// Builds the encoding from the rank file, which is the only part that isn't bundled.
+    pub fn load(&self, ranks: HashMap<Vec<u8>, u32>) -> Result<CoreBPE, TokenizerError> {
+        if let Some(n_vocab) = self.n_vocab {
+            if ranks.len() + self.special_tokens.len() != n_vocab {
+                return Err(TokenizerError::load_failed(format!(
+                    "Rank file has {} tokens, but {} expects {}",
+                    ranks.len(),
+                    self.name,
+                    n_vocab - self.special_tokens.len()
+                )));
+            }
+        }
+        let special_tokens = self
+            .special_tokens
+            .iter()
+            .map(|(token, rank)| (token.to_string(), *rank))
+            .collect::<HashMap<_, _>>();
+        CoreBPE::new(ranks, special_tokens, self.pattern)
+    }
+}
+
+static MODEL_TO_ENCODING: &[(&str, &str)] = &[
+    // reasoning
+    ("o1", "o200k_base"),
+    ("o3", "o200k_base"),
+    // chat
+    ("gpt-4o", "o200k_base"),
+    ("gpt-4", "cl100k_base"),
+    ("gpt-3.5-turbo", "cl100k_base"),
+    ("gpt-3.5", "cl100k_base"),
+    ("gpt-35-turbo", "cl100k_base"),
+    // base
+    ("davinci-002", "cl100k_base"),
+    ("babbage-002", "cl100k_base"),
+    // embeddings
+    ("text-embedding-ada-002", "cl100k_base"),
+    ("text-embedding-3-small", "cl100k_base"),
+    ("text-embedding-3-large", "cl100k_base"),
+    // text
+    ("text-davinci-003", "p50k_base"),
+    ("text-davinci-002", "p50k_base"),
+    ("text-davinci-001", "r50k_base"),
+    ("text-curie-001", "r50k_base"),
+    ("text-babbage-001", "r50k_base"),
+    ("text-ada-001", "r50k_base"),
+    ("davinci", "r50k_base"),
+    ("curie", "r50k_base"),
+    ("babbage", "r50k_base"),
+    ("ada", "r50k_base"),
+    // code
+    ("code-davinci-002", "p50k_base"),
+    ("code-davinci-001", "p50k_base"),
+    ("code-cushman-002", "p50k_base"),
+    ("code-cushman-001", "p50k_base"),
+    ("davinci-codex", "p50k_base"),
+    ("cushman-codex", "p50k_base"),
+    // edit
+    ("text-davinci-edit-001", "p50k_edit"),
+    ("code-davinci-edit-001", "p50k_edit"),
+    // old embeddings
+    ("text-similarity-davinci-001", "r50k_base"),
+    ("text-similarity-curie-001", "r50k_base"),
+    ("text-similarity-babbage-001", "r50k_base"),
+    ("text-similarity-ada-001", "r50k_base"),
+    ("text-search-davinci-doc-001", "r50k_base"),
+    ("text-search-curie-doc-001", "r50k_base"),
+    ("text-search-babbage-doc-001", "r50k_base"),
+    ("text-search-ada-doc-001", "r50k_base"),
+    ("code-search-babbage-code-001", "r50k_base"),
+    ("code-search-ada-code-001", "r50k_base"),
+];
+
+// Checked in order, so longer prefixes have to come before their own prefixes
+static MODEL_PREFIX_TO_ENCODING: &[(&str, &str)] = &[
+    ("o1-", "o200k_base"),
+    ("o3-", "o200k_base"),
+    // chat
+    ("chatgpt-4o-", "o200k_base"),
+    ("gpt-4o-", "o200k_base"),
+    ("gpt-4-", "cl100k_base"),
+    ("gpt-3.5-turbo-", "cl100k_base"),
+    ("gpt-35-turbo-", "cl100k_base"),
+    // fine-tuned
+    ("ft:gpt-4o", "o200k_base"),
+    ("ft:gpt-4", "cl100k_base"),
+    ("ft:gpt-3.5-turbo", "cl100k_base"),
+    ("ft:davinci-002", "cl100k_base"),
+    ("ft:babbage-002", "cl100k_base"),
+];
+
+/// Resolves the encoding used by an OpenAI model, like tiktoken's `encoding_for_model`.
+pub fn encoding_for_model(model: &str) -> Option<&'static str> {
+    MODEL_TO_ENCODING
+        .iter()
+        .find(|(name, _)| *name == model)
+        .or_else(|| MODEL_PREFIX_TO_ENCODING.iter().find(|(prefix, _)| model.starts_with(prefix)))
+        .map(|(_, encoding)| *encoding)
+}
@@ -0,0 +1,1725 @@
+use std::{cell::RefCell, cmp::Ordering, collections::HashMap, rc::Rc};
+
+use crate::error::TokenizerError;
+
+/// Value of a template variable, modelled after the Python values Jinja operates on.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Undefined,
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(Rc<str>),
+    List(Rc<Vec<Value>>),
+    Map(Rc<Vec<(String, Value)>>),
+    // Created by `namespace()`, the only value that can be modified from an inner scope
+    Namespace(Rc<RefCell<Vec<(String, Value)>>>),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self { Value::String(value.into()) }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self { Value::String(value.into()) }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self { Value::Bool(value) }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self { Value::List(Rc::new(value)) }
+}
+
+impl From<Vec<(String, Value)>> for Value {
+    fn from(value: Vec<(String, Value)>) -> Self { Value::Map(Rc::new(value)) }
+}
+
+impl Value {
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Undefined | Value::None => false,
+            Value::Bool(value) => *value,
+            Value::Int(value) => *value != 0,
+            Value::Float(value) => *value != 0.0,
+            Value::String(value) => !value.is_empty(),
+            Value::List(value) => !value.is_empty(),
+            Value::Map(value) => !value.is_empty(),
+            Value::Namespace(_) => true,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Undefined => "undefined",
+            Value::None => "none",
+            Value::Bool(_) => "boolean",
+            Value::Int(_) => "integer",
+            Value::Float(_) => "float",
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "mapping",
+            Value::Namespace(_) => "namespace",
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Int(value) => Some(*value as f64),
+            Value::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value as printed by `{{ }}`, following Python's `str`.
+    fn to_text(&self) -> String {
+        match self {
+            Value::Undefined => String::new(),
+            Value::String(value) => value.to_string(),
+            value => value.repr(),
+        }
+    }
+
+    /// The value as nested in lists and dicts, following Python's `repr`.
+    fn repr(&self) -> String {
+        match self {
+            Value::Undefined => String::new(),
+            Value::None => "None".to_string(),
+            Value::Bool(true) => "True".to_string(),
+            Value::Bool(false) => "False".to_string(),
+            Value::Int(value) => value.to_string(),
+            Value::Float(value) if value.fract() == 0.0 && value.abs() < 1e16 => {
+                format!("{:.1}", value)
+            }
+            Value::Float(value) => value.to_string(),
+            Value::String(value) => {
+                let quote = if value.contains('\'') && !value.contains('"') {
+                    '"'
+                } else {
+                    '\''
+                };
+                let mut repr = String::from(quote);
+                for c in value.chars() {
+                    match c {
+                        '\\' => repr.push_str("\\\\"),
+                        '\n' => repr.push_str("\\n"),
+                        '\r' => repr.push_str("\\r"),
+                        '\t' => repr.push_str("\\t"),
+                        c if c == quote => {
+                            repr.push('\\');
+                            repr.push(c);
+                        }
+                        c => repr.push(c),
+                    }
+                }
+                repr.push(quote);
+                repr
+            }
+            Value::List(items) => {
+                format!("[{}]", items.iter().map(Value::repr).collect::<Vec<_>>().join(", "))
+            }
+            Value::Map(items) => format!(
+                "{{{}}}",
+                items
+                    .iter()
+                    .map(|(key, value)| format!("'{}': {}", key, value.repr()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Namespace(_) => "<Namespace>".to_string(),
+        }
+    }
+
+    /// The value serialized like Python's `json.dumps`, the way Hugging Face's `tojson` does.
+    fn to_json(&self, indent: Option<usize>, depth: usize) -> Result<String, TokenizerError> {
+        let join = |items: Vec<String>| match indent {
+            Some(indent) if !items.is_empty() => {
+                let open = format!("\n{}", " ".repeat(indent * (depth + 1)));
+                let close = format!("\n{}", " ".repeat(indent * depth));
+                format!("{}{}{}", open, items.join(&format!(",{}", open)), close)
+            }
+            _ => items.join(", "),
+        };
+        Ok(match self {
+            Value::Undefined => {
+                return Err(TokenizerError::template_error("Undefined value in tojson"));
+            }
+            Value::None => "null".to_string(),
+            Value::Bool(value) => value.to_string(),
+            Value::Int(_) | Value::Float(_) => self.repr(),
+            Value::String(value) => json_string(value),
+            Value::List(items) => format!(
+                "[{}]",
+                join(items.iter().map(|x| x.to_json(indent, depth + 1)).collect::<Result<_, _>>()?)
+            ),
+            Value::Map(items) => format!(
+                "{{{}}}",
+                join(
+                    items
+                        .iter()
+                        .map(|(key, value)| {
+                            Ok(format!(
+                                "{}: {}",
+                                json_string(key),
+                                value.to_json(indent, depth + 1)?
+                            ))
+                        })
+                        .collect::<Result<_, TokenizerError>>()?
+                )
+            ),
+            Value::Namespace(_) => {
+                return Err(TokenizerError::template_error("Namespaces can't be serialized"));
+            }
+        })
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            '\u{8}' => json.push_str("\\b"),
+            '\u{c}' => json.push_str("\\f"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn equals(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Undefined, Value::Undefined) | (Value::None, Value::None) => true,
+        (Value::Bool(left), Value::Bool(right)) => left == right,
+        (Value::String(left), Value::String(right)) => left == right,
+        (Value::List(left), Value::List(right)) => {
+            left.len() == right.len() && left.iter().zip(right.iter()).all(|(x, y)| equals(x, y))
+        }
+        (Value::Map(left), Value::Map(right)) => {
+            left.len() == right.len()
+                && left
+                    .iter()
+                    .all(|(key, x)| right.iter().any(|(other, y)| key == other && equals(x, y)))
+        }
+        (Value::Int(left), Value::Int(right)) => left == right,
+        (left, right) => match (left.as_float(), right.as_float()) {
+            (Some(left), Some(right)) => left == right,
+            _ => false,
+        },
+    }
+}
+
+fn compare(left: &Value, right: &Value) -> Result<Ordering, TokenizerError> {
+    let ordering = match (left, right) {
+        (Value::Int(left), Value::Int(right)) => Some(left.cmp(right)),
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        (left, right) => match (left.as_float(), right.as_float()) {
+            (Some(left), Some(right)) => left.partial_cmp(&right),
+            _ => None,
+        },
+    };
+    ordering.ok_or_else(|| {
+        TokenizerError::template_error(format!(
+            "Can't compare {} with {}",
+            left.type_name(),
+            right.type_name()
+        ))
+    })
+}
+
+fn contains(container: &Value, item: &Value) -> Result<bool, TokenizerError> {
+    match (container, item) {
+        (Value::String(container), Value::String(item)) => Ok(container.contains(&**item)),
+        (Value::List(items), item) => Ok(items.iter().any(|x| equals(x, item))),
+        (Value::Map(items), Value::String(key)) => Ok(items.iter().any(|(x, _)| **x == **key)),
+        (Value::Undefined, _) => Ok(false),
+        (container, _) => Err(TokenizerError::template_error(format!(
+            "Can't test membership in {}",
+            container.type_name()
+        ))),
+    }
+}
+
+fn get_attr(value: &Value, name: &str) -> Result<Value, TokenizerError> {
+    match value {
+        Value::Map(items) => Ok(items
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, x)| x.clone())
+            .unwrap_or(Value::Undefined)),
+        Value::Namespace(items) => Ok(items
+            .borrow()
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, x)| x.clone())
+            .unwrap_or(Value::Undefined)),
+        Value::Undefined | Value::None => Err(TokenizerError::template_error(format!(
+            "Can't get attribute {:?} of {}",
+            name,
+            value.type_name()
+        ))),
+        _ => Ok(Value::Undefined),
+    }
+}
+
+fn get_item(value: &Value, key: &Value) -> Result<Value, TokenizerError> {
+    let index = |len: usize, index: i64| {
+        let index = if index < 0 { index + len as i64 } else { index };
+        (0..len as i64).contains(&index).then_some(index as usize)
+    };
+    match (value, key) {
+        (_, Value::String(key)) => get_attr(value, key),
+        (Value::List(items), Value::Int(key)) => {
+            Ok(index(items.len(), *key).map(|i| items[i].clone()).unwrap_or(Value::Undefined))
+        }
+        (Value::String(text), Value::Int(key)) => {
+            let chars = text.chars().collect::<Vec<_>>();
+            Ok(index(chars.len(), *key)
+                .map(|i| Value::from(chars[i].to_string()))
+                .unwrap_or(Value::Undefined))
+        }
+        (Value::Undefined | Value::None, _) => Err(TokenizerError::template_error(format!(
+            "Can't get item {} of {}",
+            key.repr(),
+            value.type_name()
+        ))),
+        _ => Ok(Value::Undefined),
+    }
+}
+
+/// Python slice semantics including negative indices and steps.
+fn slice(
+    value: &Value, start: Option<i64>, stop: Option<i64>, step: Option<i64>,
+) -> Result<Value, TokenizerError> {
+    let step = step.unwrap_or(1);
+    if step == 0 {
+        return Err(TokenizerError::template_error("Slice step can't be zero"));
+    }
+    let select = |len: usize| -> Vec<usize> {
+        let len = len as i64;
+        let clamp = |index: i64, low: i64, high: i64| {
+            let index = if index < 0 { index + len } else { index };
+            index.clamp(low, high)
+        };
+        if step > 0 {
+            let start = start.map(|x| clamp(x, 0, len)).unwrap_or(0);
+            let stop = stop.map(|x| clamp(x, 0, len)).unwrap_or(len);
+            (start..stop.max(start)).step_by(step as usize).map(|x| x as usize).collect()
+        } else {
+            let start = start.map(|x| clamp(x, -1, len - 1)).unwrap_or(len - 1);
+            let stop = stop.map(|x| clamp(x, -1, len - 1)).unwrap_or(-1);
+            let mut indices = vec![];
+            let mut index = start;
+            while index > stop {
+                indices.push(index as usize);
+                index += step;
+            }
+            indices
+        }
+    };
+    match value {
+        Value::List(items) => Ok(Value::from(
+            select(items.len()).into_iter().map(|i| items[i].clone()).collect::<Vec<_>>(),
+        )),
+        Value::String(text) => {
+            let chars = text.chars().collect::<Vec<_>>();
+            Ok(Value::from(
+                select(chars.len()).into_iter().map(|i| chars[i]).collect::<String>(),
+            ))
+        }
+        value => Err(TokenizerError::template_error(format!("Can't slice {}", value.type_name()))),
+    }
+}
+
+fn iterate(value: &Value) -> Result<Vec<Value>, TokenizerError> {
+    match value {
+        Value::Undefined => Ok(vec![]),
+        Value::List(items) => Ok(items.to_vec()),
+        Value::Map(items) => Ok(items.iter().map(|(key, _)| Value::from(key.as_str())).collect()),
+        Value::String(text) => Ok(text.chars().map(|c| Value::from(c.to_string())).collect()),
+        value => Err(TokenizerError::template_error(format!(
+            "Can't iterate over {}",
+            value.type_name()
+        ))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    String(String),
+    Int(i64),
+    Float(f64),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 25] = [
+    "//", "**", "==", "!=", "<=", ">=", "(", ")", "[", "]", "{", "}", ".", ",", ":", "|", "~", "+",
+    "-", "*", "/", "%", "<", ">", "=",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, TokenizerError> {
+    let mut tokens = vec![];
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '\'' || c == '"' {
+            let mut value = String::new();
+            let mut chars = rest.char_indices().skip(1);
+            let end = loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, 't')) => value.push('\t'),
+                        Some((_, 'r')) => value.push('\r'),
+                        Some((_, c @ ('\\' | '\'' | '"'))) => value.push(c),
+                        Some((_, c)) => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                        None => break None,
+                    },
+                    Some((i, x)) if x == c => break Some(i),
+                    Some((_, x)) => value.push(x),
+                    None => break None,
+                }
+            };
+            let end = end.ok_or_else(|| {
+                TokenizerError::template_error(format!("Unterminated string in {:?}", source))
+            })?;
+            tokens.push(Token::String(value));
+            rest = &rest[end + 1..];
+        } else if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let fraction = rest[end..]
+                .strip_prefix('.')
+                .filter(|x| x.starts_with(|c: char| c.is_ascii_digit()))
+                .map(|x| x.find(|c: char| !c.is_ascii_digit()).unwrap_or(x.len()) + 1);
+            match fraction {
+                Some(fraction) => {
+                    let number = &rest[..end + fraction];
+                    tokens.push(Token::Float(
+                        number.parse().map_err(TokenizerError::template_error)?,
+                    ));
+                    rest = &rest[end + fraction..];
+                }
+                None => {
+                    tokens.push(Token::Int(
+                        rest[..end].parse().map_err(TokenizerError::template_error)?,
+                    ));
+                    rest = &rest[end..];
+                }
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if let Some(symbol) = SYMBOLS.iter().find(|x| rest.starts_with(**x)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            return Err(TokenizerError::template_error(format!(
+                "Unexpected character {:?} in {:?}",
+                c, source
+            )));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Name(String),
+    List(Vec<Expr>),
+    Dict(Vec<(Expr, Expr)>),
+    Attr(Box<Expr>, String),
+    Item(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, [Option<Box<Expr>>; 3]),
+    Call(Box<Expr>, Arguments),
+    Filter(Box<Expr>, String, Arguments),
+    Test(Box<Expr>, String, Arguments, bool),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Arguments {
+    positional: Vec<Expr>,
+    keyword:    Vec<(String, Expr)>,
+}
+
+/// Evaluated call arguments.
+struct Args {
+    positional: Vec<Value>,
+    keyword:    Vec<(String, Value)>,
+}
+
+impl Args {
+    /// Argument at `index`, or passed by `name`.
+    fn get(&self, index: usize, name: &str) -> Option<&Value> {
+        self.positional.get(index).or_else(|| self.keyword(name))
+    }
+
+    fn keyword(&self, name: &str) -> Option<&Value> {
+        self.keyword.iter().find(|(key, _)| key == name).map(|(_, x)| x)
+    }
+
+    fn string(&self, index: usize, name: &str) -> Result<Option<&str>, TokenizerError> {
+        match self.get(index, name) {
+            None | Some(Value::None) | Some(Value::Undefined) => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(value) => Err(TokenizerError::template_error(format!(
+                "Expected a string for {}, got {}",
+                name,
+                value.type_name()
+            ))),
+        }
+    }
+
+    fn int(&self, index: usize, name: &str) -> Result<Option<i64>, TokenizerError> {
+        match self.get(index, name) {
+            None | Some(Value::None) | Some(Value::Undefined) => Ok(None),
+            Some(Value::Int(value)) => Ok(Some(*value)),
+            Some(value) => Err(TokenizerError::template_error(format!(
+                "Expected an integer for {}, got {}",
+                name,
+                value.type_name()
+            ))),
+        }
+    }
+}
+
+// Deepest nesting of expressions, so templates can't overflow the stack of the parser
+const MAX_DEPTH: usize = 64;
+// Longest string a repetition and longest list `range()` may create
+const MAX_LENGTH: usize = 1 << 20;
+
+struct Parser {
+    tokens:   Vec<Token>,
+    position: usize,
+    depth:    usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Self, TokenizerError> {
+        Ok(Self {
+            tokens:   tokenize(source)?,
+            position: 0,
+            depth:    0,
+        })
+    }
+
+    /// Runs `parse` one level of nesting deeper.
+    fn nested<T>(
+        &mut self, parse: impl FnOnce(&mut Self) -> Result<T, TokenizerError>,
+    ) -> Result<T, TokenizerError> {
+        if self.depth == MAX_DEPTH {
+            return Err(TokenizerError::template_error(format!(
+                "Expressions can't be nested deeper than {} levels",
+                MAX_DEPTH
+            )));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn peek(&self) -> Option<&Token> { self.tokens.get(self.position) }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn unexpected(&self) -> TokenizerError {
+        match self.peek() {
+            Some(token) => TokenizerError::template_error(format!("Unexpected {:?}", token)),
+            None => TokenizerError::template_error("Unexpected end of expression"),
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(x)) if *x == symbol);
+        self.position += found as usize;
+        found
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Name(x)) if x == keyword);
+        self.position += found as usize;
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), TokenizerError> {
+        match self.eat_symbol(symbol) {
+            true => Ok(()),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), TokenizerError> {
+        match self.eat_keyword(keyword) {
+            true => Ok(()),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String, TokenizerError> {
+        match self.peek() {
+            Some(Token::Name(name)) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn finish(&self) -> Result<(), TokenizerError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    fn parse_expression(&mut self) -> Result<Expr, TokenizerError> {
+        self.nested(|parser| {
+            let expr = parser.parse_or()?;
+            if parser.eat_keyword("if") {
+                let condition = parser.parse_or()?;
+                let otherwise = match parser.eat_keyword("else") {
+                    true => Some(Box::new(parser.parse_expression()?)),
+                    false => None,
+                };
+                return Ok(Expr::Conditional(Box::new(condition), Box::new(expr), otherwise));
+            }
+            Ok(expr)
+        })
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, TokenizerError> {
+        let mut expr = self.parse_and()?;
+        while self.eat_keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, TokenizerError> {
+        let mut expr = self.parse_not()?;
+        while self.eat_keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, TokenizerError> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.nested(Self::parse_not)?)));
+        }
+        self.parse_compare()
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr, TokenizerError> {
+        let mut expr = self.parse_binary(0)?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol(x @ ("==" | "!=" | "<" | ">" | "<=" | ">="))) => *x,
+                Some(Token::Name(x)) if x == "in" => "in",
+                Some(Token::Name(x))
+                    if x == "not"
+                        && matches!(self.tokens.get(self.position + 1), Some(Token::Name(x)) if x == "in") =>
+                {
+                    self.position += 1;
+                    "not in"
+                }
+                _ => return Ok(expr),
+            };
+            self.position += 1;
+            expr = Expr::Binary(operator, Box::new(expr), Box::new(self.parse_binary(0)?));
+        }
+    }
+
+    /// Arithmetic and concatenation, with precedence levels as in Jinja.
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, TokenizerError> {
+        const LEVELS: [&[&str]; 4] = [&["+", "-"], &["~"], &["*", "/", "//", "%"], &["**"]];
+        if level == LEVELS.len() {
+            return self.parse_unary();
+        }
+        let mut expr = self.parse_binary(level + 1)?;
+        while let Some(Token::Symbol(operator)) = self.peek() {
+            let operator = *operator;
+            if !LEVELS[level].contains(&operator) {
+                break;
+            }
+            self.position += 1;
+            expr = Expr::Binary(operator, Box::new(expr), Box::new(self.parse_binary(level + 1)?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, TokenizerError> {
+        let expr = if self.eat_symbol("-") {
+            Expr::Neg(Box::new(self.nested(Self::parse_unary)?))
+        } else if self.eat_symbol("+") {
+            self.nested(Self::parse_unary)?
+        } else {
+            let primary = self.parse_primary()?;
+            self.parse_postfix(primary)?
+        };
+        self.parse_filters(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, TokenizerError> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(match name.as_str() {
+                "true" | "True" => Expr::Literal(Value::Bool(true)),
+                "false" | "False" => Expr::Literal(Value::Bool(false)),
+                "none" | "None" => Expr::Literal(Value::None),
+                _ => Expr::Name(name),
+            }),
+            Some(Token::String(mut value)) => {
+                // Adjacent string literals are joined like in Python
+                while let Some(Token::String(next)) = self.peek() {
+                    value.push_str(next);
+                    self.position += 1;
+                }
+                Ok(Expr::Literal(Value::from(value)))
+            }
+            Some(Token::Int(value)) => Ok(Expr::Literal(Value::Int(value))),
+            Some(Token::Float(value)) => Ok(Expr::Literal(Value::Float(value))),
+            Some(Token::Symbol("(")) => {
+                let expr = self.parse_expression()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Some(Token::Symbol("[")) => {
+                let mut items = vec![];
+                while !self.eat_symbol("]") {
+                    items.push(self.parse_expression()?);
+                    if !self.eat_symbol(",") {
+                        self.expect_symbol("]")?;
+                        break;
+                    }
+                }
+                Ok(Expr::List(items))
+            }
+            Some(Token::Symbol("{")) => {
+                let mut items = vec![];
+                while !self.eat_symbol("}") {
+                    let key = self.parse_expression()?;
+                    self.expect_symbol(":")?;
+                    items.push((key, self.parse_expression()?));
+                    if !self.eat_symbol(",") {
+                        self.expect_symbol("}")?;
+                        break;
+                    }
+                }
+                Ok(Expr::Dict(items))
+            }
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected())
+            }
+        }
+    }
+
+    fn parse_postfix(&mut self, mut expr: Expr) -> Result<Expr, TokenizerError> {
+        loop {
+            if self.eat_symbol(".") {
+                expr = Expr::Attr(Box::new(expr), self.expect_name()?);
+            } else if self.eat_symbol("[") {
+                let mut parts = [None, None, None];
+                let mut part = 0;
+                loop {
+                    match self.peek() {
+                        Some(Token::Symbol(":")) if part < 2 => {
+                            self.position += 1;
+                            part += 1;
+                        }
+                        Some(Token::Symbol("]")) => {
+                            self.position += 1;
+                            break;
+                        }
+                        _ if parts[part].is_none() => {
+                            parts[part] = Some(Box::new(self.parse_expression()?));
+                        }
+                        _ => return Err(self.unexpected()),
+                    }
+                }
+                expr = match (part, parts) {
+                    (0, [Some(index), None, None]) => Expr::Item(Box::new(expr), index),
+                    (0, _) => return Err(self.unexpected()),
+                    (_, parts) => Expr::Slice(Box::new(expr), parts),
+                };
+            } else if self.eat_symbol("(") {
+                expr = Expr::Call(Box::new(expr), self.parse_arguments()?);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    /// Arguments after the opening parenthesis, up to and including the closing one.
+    fn parse_arguments(&mut self) -> Result<Arguments, TokenizerError> {
+        let mut arguments = Arguments::default();
+        while !self.eat_symbol(")") {
+            match (self.peek(), self.tokens.get(self.position + 1)) {
+                (Some(Token::Name(name)), Some(Token::Symbol("="))) => {
+                    let name = name.clone();
+                    self.position += 2;
+                    arguments.keyword.push((name, self.parse_expression()?));
+                }
+                _ => arguments.positional.push(self.parse_expression()?),
+            }
+            if !self.eat_symbol(",") {
+                self.expect_symbol(")")?;
+                break;
+            }
+        }
+        Ok(arguments)
+    }
+
+    fn parse_filters(&mut self, mut expr: Expr) -> Result<Expr, TokenizerError> {
+        loop {
+            if self.eat_symbol("|") {
+                let name = self.expect_name()?;
+                let arguments = match self.eat_symbol("(") {
+                    true => self.parse_arguments()?,
+                    false => Arguments::default(),
+                };
+                expr = Expr::Filter(Box::new(expr), name, arguments);
+            } else if self.eat_keyword("is") {
+                let negated = self.eat_keyword("not");
+                let name = self.expect_name()?;
+                let arguments = match self.eat_symbol("(") {
+                    true => self.parse_arguments()?,
+                    false => Arguments::default(),
+                };
+                expr = Expr::Test(Box::new(expr), name, arguments, negated);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Output(Expr),
+    If(Vec<(Expr, Vec<Node>)>, Vec<Node>),
+    For {
+        targets:   Vec<String>,
+        iterable:  Expr,
+        condition: Option<Expr>,
+        body:      Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Set(String, Option<String>, Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SegmentKind {
+    Text,
+    Output,
+    Tag,
+}
+
+/// Splits a template into text, `{{ }}` and `{% %}` segments, dropping comments and applying
+/// whitespace control with the `trim_blocks` and `lstrip_blocks` behaviour Hugging Face enables.
+fn segments(source: &str) -> Result<Vec<(SegmentKind, &str)>, TokenizerError> {
+    let mut segments = vec![];
+    let mut position = 0;
+    let mut strip_start = false;
+    let mut trim_newline = false;
+    loop {
+        let open = ["{{", "{%", "{#"]
+            .iter()
+            .filter_map(|x| source[position..].find(x).map(|i| position + i))
+            .min();
+        let mut text = &source[position..open.unwrap_or(source.len())];
+        if strip_start {
+            text = text.trim_start();
+        } else if trim_newline {
+            text = text.strip_prefix("\r\n").or_else(|| text.strip_prefix('\n')).unwrap_or(text);
+        }
+        let Some(open) = open else {
+            segments.push((SegmentKind::Text, text));
+            return Ok(segments);
+        };
+        let (kind, close) = match &source[open..open + 2] {
+            "{{" => (SegmentKind::Output, "}}"),
+            "{%" => (SegmentKind::Tag, "%}"),
+            _ => (SegmentKind::Text, "#}"),
+        };
+        let inner_start = open + 2;
+        let modifier = source[inner_start..].chars().next();
+        if modifier == Some('-') {
+            text = text.trim_end();
+        } else if kind != SegmentKind::Output && modifier != Some('+') {
+            // Blocks on a line of their own don't leave the indentation before them behind
+            let line = &source[source[..open].rfind('\n').map(|x| x + 1).unwrap_or(0)..open];
+            if line.chars().all(|c| c == ' ' || c == '\t') && line.len() <= text.len() {
+                text = &text[..text.len() - line.len()];
+            }
+        }
+        segments.push((SegmentKind::Text, text));
+
+        let inner_start = inner_start + matches!(modifier, Some('-' | '+')) as usize;
+        let inner_end = match close {
+            "#}" => source[inner_start..].find(close).map(|x| inner_start + x),
+            close => find_close(&source[inner_start..], close).map(|x| inner_start + x),
+        }
+        .ok_or_else(|| {
+            TokenizerError::template_error(format!(
+                "Unclosed {:?} at position {}",
+                &source[open..open + 2],
+                open
+            ))
+        })?;
+        let mut inner = &source[inner_start..inner_end];
+        let close_modifier = inner.chars().last().filter(|c| matches!(c, '-' | '+'));
+        if close_modifier.is_some() {
+            inner = &inner[..inner.len() - 1];
+        }
+        strip_start = close_modifier == Some('-');
+        trim_newline = kind != SegmentKind::Output && close_modifier.is_none();
+        if kind != SegmentKind::Text {
+            segments.push((kind, inner.trim()));
+        }
+        position = inner_end + 2;
+    }
+}
+
+/// Position of `close` in `source`, skipping over string literals.
+fn find_close(source: &str, close: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut chars = source.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match quote {
+            Some(_) if c == '\\' => {
+                chars.next();
+            }
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if source[i..].starts_with(close) => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+/// A parsed Jinja template, supporting the subset used by common chat templates.
+///
+/// Supported are `if`/`elif`/`else`, `for` with `loop` variables and conditions, `set` including
+/// attributes of namespaces, the usual operators, tests, string methods and filters, as well as
+/// the `raise_exception` function Hugging Face provides.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, TokenizerError> {
+        let segments = segments(source)?;
+        let mut segments = segments.into_iter();
+        let (nodes, end) = parse_nodes(&mut segments, &[])?;
+        match end {
+            None => Ok(Self { nodes }),
+            Some((keyword, _)) => {
+                Err(TokenizerError::template_error(format!("Unexpected {:?} tag", keyword)))
+            }
+        }
+    }
+
+    /// Renders the template with `variables` as the global scope.
+    pub fn render(&self, variables: Vec<(String, Value)>) -> Result<String, TokenizerError> {
+        let mut context = Context {
+            scopes: vec![variables.into_iter().collect()],
+        };
+        let mut output = String::new();
+        context.render(&self.nodes, &mut output)?;
+        Ok(output)
+    }
+}
+
+type Segments<'a> = std::vec::IntoIter<(SegmentKind, &'a str)>;
+// Keyword of the tag that ended a block, with a parser positioned after it
+type EndTag = Option<(String, Parser)>;
+
+/// Parses nodes until one of the `end` tags, which is returned along with the rest of its tag.
+fn parse_nodes(
+    segments: &mut Segments, end: &[&str],
+) -> Result<(Vec<Node>, EndTag), TokenizerError> {
+    let mut nodes = vec![];
+    while let Some((kind, source)) = segments.next() {
+        match kind {
+            SegmentKind::Text if !source.is_empty() => nodes.push(Node::Text(source.to_string())),
+            SegmentKind::Text => {}
+            SegmentKind::Output => {
+                let mut parser = Parser::new(source)?;
+                nodes.push(Node::Output(parser.parse_expression()?));
+                parser.finish()?;
+            }
+            SegmentKind::Tag => {
+                let mut parser = Parser::new(source)?;
+                let keyword = parser.expect_name()?;
+                if end.contains(&keyword.as_str()) {
+                    return Ok((nodes, Some((keyword, parser))));
+                }
+                nodes.push(parse_tag(segments, &keyword, parser)?);
+            }
+        }
+    }
+    match end.is_empty() {
+        true => Ok((nodes, None)),
+        false => {
+            Err(TokenizerError::template_error(format!("Missing {:?} tag", end[end.len() - 1])))
+        }
+    }
+}
+
+fn parse_tag(
+    segments: &mut Segments, keyword: &str, mut parser: Parser,
+) -> Result<Node, TokenizerError> {
+    match keyword {
+        "if" => {
+            let mut condition = parser.parse_expression()?;
+            parser.finish()?;
+            let mut branches = vec![];
+            loop {
+                let (body, end) = parse_nodes(segments, &["elif", "else", "endif"])?;
+                branches.push((condition, body));
+                let Some((keyword, mut parser)) = end else {
+                    unreachable!()
+                };
+                match keyword.as_str() {
+                    "elif" => {
+                        condition = parser.parse_expression()?;
+                        parser.finish()?;
+                    }
+                    "else" => {
+                        parser.finish()?;
+                        let (otherwise, _) = parse_nodes(segments, &["endif"])?;
+                        return Ok(Node::If(branches, otherwise));
+                    }
+                    _ => {
+                        parser.finish()?;
+                        return Ok(Node::If(branches, vec![]));
+                    }
+                }
+            }
+        }
+        "for" => {
+            let mut targets = vec![parser.expect_name()?];
+            while parser.eat_symbol(",") {
+                targets.push(parser.expect_name()?);
+            }
+            parser.expect_keyword("in")?;
+            let iterable = parser.parse_or()?;
+            let condition = match parser.eat_keyword("if") {
+                true => Some(parser.parse_or()?),
+                false => None,
+            };
+            parser.finish()?;
+            let (body, end) = parse_nodes(segments, &["else", "endfor"])?;
+            let otherwise = match end {
+                Some((keyword, parser)) if keyword == "else" => {
+                    parser.finish()?;
+                    parse_nodes(segments, &["endfor"])?.0
+                }
+                _ => vec![],
+            };
+            Ok(Node::For {
+                targets,
+                iterable,
+                condition,
+                body,
+                otherwise,
+            })
+        }
+        "set" => {
+            let target = parser.expect_name()?;
+            let attribute = match parser.eat_symbol(".") {
+                true => Some(parser.expect_name()?),
+                false => None,
+            };
+            parser.expect_symbol("=")?;
+            let value = parser.parse_expression()?;
+            parser.finish()?;
+            Ok(Node::Set(target, attribute, value))
+        }
+        keyword => Err(TokenizerError::template_error(format!("Unsupported tag {:?}", keyword))),
+    }
+}
+
+struct Context {
+    scopes: Vec<HashMap<String, Value>>,
+}
+
+impl Context {
+    fn lookup(&self, name: &str) -> Value {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .cloned()
+            .unwrap_or(Value::Undefined)
+    }
+
+    fn assign(&mut self, name: &str, value: Value) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), value);
+        }
+    }
+
+    fn render(&mut self, nodes: &[Node], output: &mut String) -> Result<(), TokenizerError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => output.push_str(text),
+                Node::Output(expr) => output.push_str(&self.eval(expr)?.to_text()),
+                Node::If(branches, otherwise) => {
+                    let mut body = otherwise;
+                    for (condition, branch) in branches {
+                        if self.eval(condition)?.is_true() {
+                            body = branch;
+                            break;
+                        }
+                    }
+                    self.render(body, output)?;
+                }
+                Node::For {
+                    targets,
+                    iterable,
+                    condition,
+                    body,
+                    otherwise,
+                } => {
+                    let mut items = vec![];
+                    for item in iterate(&self.eval(iterable)?)? {
+                        let scope = bind(targets, item.clone())?;
+                        let keep = match condition {
+                            Some(condition) => {
+                                self.scopes.push(scope);
+                                let keep = self.eval(condition);
+                                self.scopes.pop();
+                                keep?.is_true()
+                            }
+                            None => true,
+                        };
+                        if keep {
+                            items.push(item);
+                        }
+                    }
+                    if items.is_empty() {
+                        self.render(otherwise, output)?;
+                    }
+                    for (index, item) in items.iter().enumerate() {
+                        let mut scope = bind(targets, item.clone())?;
+                        let length = items.len() as i64;
+                        let index = index as i64;
+                        let mut variables = vec![
+                            ("index".to_string(), Value::Int(index + 1)),
+                            ("index0".to_string(), Value::Int(index)),
+                            ("revindex".to_string(), Value::Int(length - index)),
+                            ("revindex0".to_string(), Value::Int(length - index - 1)),
+                            ("first".to_string(), Value::Bool(index == 0)),
+                            ("last".to_string(), Value::Bool(index == length - 1)),
+                            ("length".to_string(), Value::Int(length)),
+                        ];
+                        if index > 0 {
+                            variables
+                                .push(("previtem".to_string(), items[index as usize - 1].clone()));
+                        }
+                        if index < length - 1 {
+                            variables
+                                .push(("nextitem".to_string(), items[index as usize + 1].clone()));
+                        }
+                        scope.insert("loop".to_string(), Value::from(variables));
+                        self.scopes.push(scope);
+                        let result = self.render(body, output);
+                        self.scopes.pop();
+                        result?;
+                    }
+                }
+                Node::Set(target, None, value) => {
+                    let value = self.eval(value)?;
+                    self.assign(target, value);
+                }
+                Node::Set(target, Some(attribute), value) => {
+                    let value = self.eval(value)?;
+                    match self.lookup(target) {
+                        Value::Namespace(namespace) => {
+                            let mut namespace = namespace.borrow_mut();
+                            match namespace.iter_mut().find(|(key, _)| key == attribute) {
+                                Some((_, x)) => *x = value,
+                                None => namespace.push((attribute.clone(), value)),
+                            }
+                        }
+                        other => {
+                            return Err(TokenizerError::template_error(format!(
+                                "Can't set attribute {:?} of {}",
+                                attribute,
+                                other.type_name()
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn eval_arguments(&self, arguments: &Arguments) -> Result<Args, TokenizerError> {
+        Ok(Args {
+            positional: arguments
+                .positional
+                .iter()
+                .map(|x| self.eval(x))
+                .collect::<Result<_, _>>()?,
+            keyword:    arguments
+                .keyword
+                .iter()
+                .map(|(name, x)| Ok((name.clone(), self.eval(x)?)))
+                .collect::<Result<_, TokenizerError>>()?,
+        })
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Value, TokenizerError> {
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Name(name) => Ok(self.lookup(name)),
+            Expr::List(items) => {
+                Ok(Value::from(items.iter().map(|x| self.eval(x)).collect::<Result<Vec<_>, _>>()?))
+            }
+            Expr::Dict(items) => {
+                let mut map = vec![];
+                for (key, value) in items {
+                    let key = self.eval(key)?;
+                    let key = key.as_str().map(str::to_string).unwrap_or_else(|| key.repr());
+                    map.push((key, self.eval(value)?));
+                }
+                Ok(Value::from(map))
+            }
+            Expr::Attr(value, name) => get_attr(&self.eval(value)?, name),
+            Expr::Item(value, key) => {
+                let value = self.eval(value)?;
+                get_item(&value, &self.eval(key)?)
+            }
+            Expr::Slice(value, parts) => {
+                let value = self.eval(value)?;
+                let mut bounds = [None; 3];
+                for (bound, part) in bounds.iter_mut().zip(parts) {
+                    *bound = match part {
+                        None => None,
+                        Some(part) => match self.eval(part)? {
+                            Value::Int(x) => Some(x),
+                            Value::None => None,
+                            other => {
+                                return Err(TokenizerError::template_error(format!(
+                                    "Slice indices must be integers, got {}",
+                                    other.type_name()
+                                )));
+                            }
+                        },
+                    };
+                }
+                slice(&value, bounds[0], bounds[1], bounds[2])
+            }
+            Expr::Call(callee, arguments) => match &**callee {
+                Expr::Attr(value, method) => {
+                    let value = self.eval(value)?;
+                    let args = self.eval_arguments(arguments)?;
+                    call_method(&value, method, &args)
+                }
+                Expr::Name(function) => {
+                    let args = self.eval_arguments(arguments)?;
+                    call_function(function, &args)
+                }
+                _ => {
+                    Err(TokenizerError::template_error("Only functions and methods can be called"))
+                }
+            },
+            Expr::Filter(value, name, arguments) => {
+                let value = self.eval(value)?;
+                let args = self.eval_arguments(arguments)?;
+                apply_filter(name, value, &args)
+            }
+            Expr::Test(value, name, arguments, negated) => {
+                let value = self.eval(value)?;
+                let args = self.eval_arguments(arguments)?;
+                Ok(Value::Bool(run_test(name, &value, &args.positional)? != *negated))
+            }
+            Expr::Not(value) => Ok(Value::Bool(!self.eval(value)?.is_true())),
+            Expr::Neg(value) => match self.eval(value)? {
+                Value::Int(x) => Ok(Value::Int(-x)),
+                Value::Float(x) => Ok(Value::Float(-x)),
+                other => Err(TokenizerError::template_error(format!(
+                    "Can't negate {}",
+                    other.type_name()
+                ))),
+            },
+            Expr::And(left, right) => {
+                let left = self.eval(left)?;
+                if !left.is_true() {
+                    return Ok(left);
+                }
+                self.eval(right)
+            }
+            Expr::Or(left, right) => {
+                let left = self.eval(left)?;
+                if left.is_true() {
+                    return Ok(left);
+                }
+                self.eval(right)
+            }
+            Expr::Conditional(condition, value, otherwise) => {
+                if self.eval(condition)?.is_true() {
+                    self.eval(value)
+                } else {
+                    match otherwise {
+                        Some(otherwise) => self.eval(otherwise),
+                        None => Ok(Value::Undefined),
+                    }
+                }
+            }
+            Expr::Binary(operator, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                binary(operator, &left, &right)
+            }
+        }
+    }
+}
+
+fn bind(targets: &[String], item: Value) -> Result<HashMap<String, Value>, TokenizerError> {
+    if let [target] = targets {
+        return Ok(HashMap::from([(target.clone(), item)]));
+    }
+    match &item {
+        Value::List(items) if items.len() == targets.len() => {
+            Ok(targets.iter().cloned().zip(items.iter().cloned()).collect())
+        }
+        _ => Err(TokenizerError::template_error(format!(
+            "Can't unpack {} into {} variables",
+            item.repr(),
+            targets.len()
+        ))),
+    }
+}
+
+fn binary(operator: &str, left: &Value, right: &Value) -> Result<Value, TokenizerError> {
+    let error = || {
+        TokenizerError::template_error(format!(
+            "Unsupported operand types for {}: {} and {}",
+            operator,
+            left.type_name(),
+            right.type_name()
+        ))
+    };
+    Ok(match (operator, left, right) {
+        ("==", left, right) => Value::Bool(equals(left, right)),
+        ("!=", left, right) => Value::Bool(!equals(left, right)),
+        ("<", left, right) => Value::Bool(compare(left, right)? == Ordering::Less),
+        (">", left, right) => Value::Bool(compare(left, right)? == Ordering::Greater),
+        ("<=", left, right) => Value::Bool(compare(left, right)? != Ordering::Greater),
+        (">=", left, right) => Value::Bool(compare(left, right)? != Ordering::Less),
+        ("in", left, right) => Value::Bool(contains(right, left)?),
+        ("not in", left, right) => Value::Bool(!contains(right, left)?),
+        ("~", left, right) => Value::from(left.to_text() + &right.to_text()),
+        ("+", Value::String(left), Value::String(right)) => {
+            Value::from([&**left, &**right].concat())
+        }
+        ("+", Value::List(left), Value::List(right)) => {
+            Value::from(left.iter().chain(right.iter()).cloned().collect::<Vec<_>>())
+        }
+        ("*", Value::String(text), Value::Int(count))
+        | ("*", Value::Int(count), Value::String(text)) => {
+            let count = (*count).max(0) as usize;
+            if text.len().saturating_mul(count) > MAX_LENGTH {
+                return Err(TokenizerError::template_error(format!(
+                    "Repeated strings can't be longer than {} bytes",
+                    MAX_LENGTH
+                )));
+            }
+            Value::from(text.repeat(count))
+        }
+        (operator, Value::Int(left), Value::Int(right)) if operator != "/" => {
+            let (left, right) = (*left, *right);
+            if matches!(operator, "//" | "%") && right == 0 {
+                return Err(TokenizerError::template_error("Division by zero"));
+            }
+            Value::Int(match operator {
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                "//" | "%" => {
+                    // Only `i64::MIN // -1` overflows once zero is ruled out
+                    let (Some(quotient), Some(rest)) =
+                        (left.checked_div(right), left.checked_rem(right))
+                    else {
+                        return Err(TokenizerError::template_error(format!(
+                            "Integer overflow in {} {} {}",
+                            left, operator, right
+                        )));
+                    };
+                    // Python rounds towards negative infinity and takes the sign of the divisor
+                    let floor = rest != 0 && (rest < 0) != (right < 0);
+                    match operator {
+                        "//" => quotient - floor as i64,
+                        _ if floor => rest + right,
+                        _ => rest,
+                    }
+                }
+                "**" if right >= 0 => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+                "**" => return Ok(Value::Float((left as f64).powf(right as f64))),
+                _ => return Err(error()),
+            })
+        }
+        (operator, left, right) => {
+            let (Some(left), Some(right)) = (left.as_float(), right.as_float()) else {
+                return Err(error());
+            };
+            Value::Float(match operator {
+                "+" => left + right,
+                "-" => left - right,
+                "*" => left * right,
+                "/" | "//" | "%" if right == 0.0 => {
+                    return Err(TokenizerError::template_error("Division by zero"));
+                }
+                "/" => left / right,
+                "//" => (left / right).floor(),
+                "%" => left - right * (left / right).floor(),
+                "**" => left.powf(right),
+                _ => return Err(error()),
+            })
+        }
+    })
+}
+
+fn call_function(name: &str, args: &Args) -> Result<Value, TokenizerError> {
+    match name {
+        "raise_exception" => Err(TokenizerError::template_error(
+            args.get(0, "message").map(Value::to_text).unwrap_or_default(),
+        )),
+        "namespace" => Ok(Value::Namespace(Rc::new(RefCell::new(args.keyword.clone())))),
+        "range" => {
+            let numbers = args
+                .positional
+                .iter()
+                .map(|x| match x {
+                    Value::Int(x) => Ok(*x),
+                    x => Err(TokenizerError::template_error(format!(
+                        "range() expects integers, got {}",
+                        x.type_name()
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let (start, stop, step) = match numbers[..] {
+                [stop] => (0, stop, 1),
+                [start, stop] => (start, stop, 1),
+                [start, stop, step] if step != 0 => (start, stop, step),
+                _ => return Err(TokenizerError::template_error("Invalid arguments for range()")),
+            };
+            // Counted in a wider type, as the distance between the bounds can overflow
+            let (start, stop, step) = (start as i128, stop as i128, step as i128);
+            let length = match step > 0 {
+                true => (stop - start + step - 1) / step,
+                false => (start - stop - step - 1) / -step,
+            };
+            if length > MAX_LENGTH as i128 {
+                return Err(TokenizerError::template_error(format!(
+                    "range() can't create more than {} values",
+                    MAX_LENGTH
+                )));
+            }
+            let values = (0..length.max(0)).map(|i| Value::Int((start + i * step) as i64));
+            Ok(Value::from(values.collect::<Vec<_>>()))
+        }
+        name => Err(TokenizerError::template_error(format!("Unknown function {:?}", name))),
+    }
+}
+
+fn call_method(value: &Value, method: &str, args: &Args) -> Result<Value, TokenizerError> {
+    let unknown = || {
+        TokenizerError::template_error(format!(
+            "Unknown method {:?} of {}",
+            method,
+            value.type_name()
+        ))
+    };
+    match value {
+        Value::String(text) => {
+            let chars = args.string(0, "chars")?;
+            let matches = |c: char| match chars {
+                Some(chars) => chars.contains(c),
+                None => c.is_whitespace(),
+            };
+            Ok(match method {
+                "strip" => Value::from(text.trim_matches(matches)),
+                "lstrip" => Value::from(text.trim_start_matches(matches)),
+                "rstrip" => Value::from(text.trim_end_matches(matches)),
+                "upper" => Value::from(text.to_uppercase()),
+                "lower" => Value::from(text.to_lowercase()),
+                "title" => Value::from(title(text)),
+                "capitalize" => Value::from(capitalize(text)),
+                "startswith" => {
+                    Value::Bool(text.starts_with(args.string(0, "prefix")?.unwrap_or("")))
+                }
+                "endswith" => Value::Bool(text.ends_with(args.string(0, "suffix")?.unwrap_or(""))),
+                "split" => {
+                    let parts: Vec<Value> = match args.string(0, "sep")? {
+                        Some(separator) => text.split(separator).map(Value::from).collect(),
+                        None => text.split_whitespace().map(Value::from).collect(),
+                    };
+                    Value::from(parts)
+                }
+                "replace" => Value::from(text.replace(
+                    args.string(0, "old")?.unwrap_or(""),
+                    args.string(1, "new")?.unwrap_or(""),
+                )),
+                _ => return Err(unknown()),
+            })
+        }
+        Value::Map(items) => Ok(match method {
+            "items" => Value::from(
+                items
+                    .iter()
+                    .map(|(key, value)| Value::from(vec![Value::from(key.as_str()), value.clone()]))
+                    .collect::<Vec<_>>(),
+            ),
+            "keys" => Value::from(
+                items.iter().map(|(key, _)| Value::from(key.as_str())).collect::<Vec<_>>(),
+            ),
+            "values" => {
+                Value::from(items.iter().map(|(_, value)| value.clone()).collect::<Vec<_>>())
+            }
+            "get" => match get_attr(value, args.string(0, "key")?.unwrap_or(""))? {
+                Value::Undefined => args.get(1, "default").cloned().unwrap_or(Value::None),
+                value => value,
+            },
+            _ => return Err(unknown()),
+        }),
+        _ => Err(unknown()),
+    }
+}
+
+fn title(text: &str) -> String {
+    let mut previous = None;
+    text.chars()
+        .map(|c| {
+            let upper = !previous.is_some_and(char::is_alphanumeric);
+            previous = Some(c);
+            match upper {
+                true => c.to_uppercase().collect::<String>(),
+                false => c.to_lowercase().collect::<String>(),
+            }
+        })
+        .collect()
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars.as_str().to_lowercase().chars()).collect(),
+        None => String::new(),
+    }
+}
+
+fn apply_filter(name: &str, value: Value, args: &Args) -> Result<Value, TokenizerError> {
+    let text = || match &value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Undefined => Ok("".into()),
+        value => Err(TokenizerError::template_error(format!(
+            "Filter {:?} expects a string, got {}",
+            name,
+            value.type_name()
+        ))),
+    };
+    Ok(match name {
+        "trim" => {
+            let chars = args.string(0, "chars")?;
+            Value::from(text()?.trim_matches(|c: char| match chars {
+                Some(chars) => chars.contains(c),
+                None => c.is_whitespace(),
+            }))
+        }
+        "upper" => Value::from(text()?.to_uppercase()),
+        "lower" => Value::from(text()?.to_lowercase()),
+        "title" => Value::from(title(&text()?)),
+        "capitalize" => Value::from(capitalize(&text()?)),
+        "replace" => {
+            Value::from(text()?.replace(
+                args.string(0, "old")?.unwrap_or(""),
+                args.string(1, "new")?.unwrap_or(""),
+            ))
+        }
+        "string" => Value::from(value.to_text()),
+        "safe" | "e" | "escape" => value,
+        "length" | "count" => Value::Int(match &value {
+            Value::String(text) => text.chars().count(),
+            Value::List(items) => items.len(),
+            Value::Map(items) => items.len(),
+            Value::Undefined => 0,
+            value => {
+                return Err(TokenizerError::template_error(format!(
+                    "{} has no length",
+                    value.type_name()
+                )));
+            }
+        } as i64),
+        "first" => iterate(&value)?.into_iter().next().unwrap_or(Value::Undefined),
+        "last" => iterate(&value)?.pop().unwrap_or(Value::Undefined),
+        "list" => Value::from(iterate(&value)?),
+        "reverse" => match &value {
+            Value::String(text) => Value::from(text.chars().rev().collect::<String>()),
+            value => Value::from(iterate(value)?.into_iter().rev().collect::<Vec<_>>()),
+        },
+        "join" => {
+            let separator = args.string(0, "d")?.unwrap_or("");
+            let attribute = args.string(1, "attribute")?;
+            let mut parts = vec![];
+            for item in iterate(&value)? {
+                let item = match attribute {
+                    Some(attribute) => get_attr(&item, attribute)?,
+                    None => item,
+                };
+                parts.push(item.to_text());
+            }
+            Value::from(parts.join(separator))
+        }
+        "default" | "d" => {
+            let boolean = args.get(1, "boolean").map(Value::is_true).unwrap_or(false);
+            match (&value, boolean) {
+                (Value::Undefined, _) => {
+                    args.get(0, "default_value").cloned().unwrap_or(Value::from(""))
+                }
+                (value, true) if !value.is_true() => {
+                    args.get(0, "default_value").cloned().unwrap_or(Value::from(""))
+                }
+                _ => value,
+            }
+        }
+        "int" => match &value {
+            Value::Int(_) => value,
+            Value::Float(x) => Value::Int(x.trunc() as i64),
+            Value::Bool(x) => Value::Int(*x as i64),
+            Value::String(x) => Value::Int(x.trim().parse().unwrap_or(0)),
+            _ => Value::Int(0),
+        },
+        "float" => match &value {
+            Value::String(x) => Value::Float(x.trim().parse().unwrap_or(0.0)),
+            value => Value::Float(value.as_float().unwrap_or(0.0)),
+        },
+        "abs" => match value {
+            Value::Int(x) => Value::Int(x.abs()),
+            Value::Float(x) => Value::Float(x.abs()),
+            value => {
+                return Err(TokenizerError::template_error(format!(
+                    "Can't take abs of {}",
+                    value.type_name()
+                )));
+            }
+        },
+        "items" => match &value {
+            Value::Undefined => Value::from(Vec::<Value>::new()),
+            value => call_method(value, "items", args)?,
+        },
+        "tojson" => {
+            Value::from(value.to_json(args.int(0, "indent")?.map(|x| x.max(0) as usize), 0)?)
+        }
+        "selectattr" | "rejectattr" => {
+            let attribute = args.string(0, "attr")?.unwrap_or("");
+            let test = args.string(1, "test")?;
+            let mut items = vec![];
+            for item in iterate(&value)? {
+                let value = get_attr(&item, attribute)?;
+                let passed = match test {
+                    Some(test) => run_test(test, &value, &args.positional[2..])?,
+                    None => value.is_true(),
+                };
+                if passed == (name == "selectattr") {
+                    items.push(item);
+                }
+            }
+            Value::from(items)
+        }
+        "map" => {
+            let attribute = args.keyword("attribute").and_then(Value::as_str);
+            let mut items = vec![];
+            for item in iterate(&value)? {
+                items.push(match (attribute, args.string(0, "filter")?) {
+                    (Some(attribute), _) => match get_attr(&item, attribute)? {
+                        Value::Undefined => {
+                            args.keyword("default").cloned().unwrap_or(Value::Undefined)
+                        }
+                        value => value,
+                    },
+                    (None, Some(filter)) => apply_filter(filter, item, &Args {
+                        positional: args.positional[1..].to_vec(),
+                        keyword:    vec![],
+                    })?,
+                    (None, None) => item,
+                });
+            }
+            Value::from(items)
+        }
+        name => return Err(TokenizerError::template_error(format!("Unknown filter {:?}", name))),
+    })
+}
+
+fn run_test(name: &str, value: &Value, args: &[Value]) -> Result<bool, TokenizerError> {
+    let argument = || {
+        args.first().ok_or_else(|| {
+            TokenizerError::template_error(format!("Test {:?} expects an argument", name))
+        })
+    };
+    Ok(match name {
+        "defined" => !matches!(value, Value::Undefined),
+        "undefined" => matches!(value, Value::Undefined),
+        "none" => matches!(value, Value::None),
+        "boolean" => matches!(value, Value::Bool(_)),
+        "true" => matches!(value, Value::Bool(true)),
+        "false" => matches!(value, Value::Bool(false)),
+        "string" => matches!(value, Value::String(_)),
+        "number" => matches!(value, Value::Int(_) | Value::Float(_)),
+        "integer" => matches!(value, Value::Int(_)),
+        "float" => matches!(value, Value::Float(_)),
+        "mapping" => matches!(value, Value::Map(_)),
+        "sequence" | "iterable" => {
+            matches!(value, Value::List(_) | Value::String(_) | Value::Map(_))
+        }
+        "odd" => matches!(value, Value::Int(x) if x % 2 != 0),
+        "even" => matches!(value, Value::Int(x) if x % 2 == 0),
+        "lower" => value.as_str().is_some_and(|x| x.to_lowercase() == x),
+        "upper" => value.as_str().is_some_and(|x| x.to_uppercase() == x),
+        "equalto" | "eq" | "==" | "sameas" => equals(value, argument()?),
+        "ne" | "!=" => !equals(value, argument()?),
+        "in" => contains(argument()?, value)?,
+        name => return Err(TokenizerError::template_error(format!("Unknown test {:?}", name))),
+    })
+}
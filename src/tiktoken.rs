@@ -3,6 +3,7 @@
 
 use std::{
     collections::{HashMap, HashSet},
+    mem::size_of,
     ops::Range,
     vec::Vec,
 };
@@ -11,28 +12,52 @@ use base64::{alphabet, engine, Engine};
 use bstr::ByteSlice;
 use fancy_regex::Regex;
 
+use crate::error::TokenizerError;
+
 static BASE64: engine::GeneralPurpose =
     engine::GeneralPurpose::new(&alphabet::STANDARD, engine::general_purpose::PAD);
 
-pub fn load_bpe(bpe: &[u8]) -> Result<HashMap<Vec<u8>, u32>, String> {
+pub fn load_bpe(bpe: &[u8]) -> Result<HashMap<Vec<u8>, u32>, TokenizerError> {
     let mut tokens: HashMap<Vec<u8>, u32> = HashMap::new();
     for line in bpe.split(|u| *u == '\n' as u8) {
         if line.len() == 0 {
             continue;
         }
-        let (l, r) = line.split_once_str(" ").ok_or_else(|| "Invalid BPE".to_string())?;
+        let (l, r) = line
+            .split_once_str(" ")
+            .ok_or_else(|| TokenizerError::load_failed("Invalid BPE"))?;
         tokens.insert(
-            BASE64.decode(&l).map_err(|e| e.to_string())?,
+            BASE64.decode(&l).map_err(TokenizerError::load_failed)?,
             r.as_bstr()
                 .to_str()
-                .map_err(|e| e.to_string())?
+                .map_err(TokenizerError::load_failed)?
                 .parse::<u32>()
-                .map_err(|e| e.to_string())?,
+                .map_err(TokenizerError::load_failed)?,
         );
     }
     Ok(tokens)
 }
 
+/// Special tokens to match while encoding, either all special tokens of the tokenizer or the
+/// given ones. `All` saves collecting the special tokens into a set on every call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecialSet<'a> {
+    All,
+    Tokens(HashSet<&'a str>),
+}
+
+impl SpecialSet<'_> {
+    pub fn none() -> Self { SpecialSet::Tokens(HashSet::new()) }
+
+    fn contains(&self, token: &str) -> bool {
+        match self {
+            SpecialSet::All => true,
+            SpecialSet::Tokens(tokens) => tokens.contains(token),
+        }
+    }
+
+    fn is_empty(&self) -> bool { matches!(self, SpecialSet::Tokens(tokens) if tokens.is_empty()) }
+}
 
 #[derive(Debug)]
 pub struct CoreBPE {
@@ -48,20 +73,22 @@ pub struct CoreBPE {
 impl CoreBPE {
     pub fn new(
         encoder: HashMap<Vec<u8>, u32>, special_tokens_encoder: HashMap<String, u32>, pattern: &str,
-    ) -> Result<Self, String> {
-        let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+    ) -> Result<Self, TokenizerError> {
+        let regex = Regex::new(pattern).map_err(TokenizerError::regex_error)?;
 
         let special_regex = {
             let _parts = special_tokens_encoder
                 .keys()
                 .map(|s| fancy_regex::escape(s))
                 .collect::<Vec<_>>();
-            Regex::new(&_parts.join("|")).map_err(|e| e.to_string())?
+            Regex::new(&_parts.join("|")).map_err(TokenizerError::regex_error)?
         };
 
         let decoder: HashMap<u32, Vec<u8>> = encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
 
-        assert!(encoder.len() == decoder.len());
+        if encoder.len() != decoder.len() {
+            return Err(TokenizerError::load_failed("BPE ranks are not unique"));
+        }
 
         let special_tokens_decoder: HashMap<u32, Vec<u8>> = special_tokens_encoder
             .iter()
@@ -82,62 +109,235 @@ impl CoreBPE {
         })
     }
 
-    pub fn encode(&self, text: &str) -> Vec<u32> { self._encode_native(text).0 }
+    pub fn special_tokens(&self) -> HashSet<&str> {
+        self.special_tokens_encoder.keys().map(|s| s.as_str()).collect()
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u32>, TokenizerError> {
+        Ok(self._encode_native(text, &SpecialSet::All)?.0)
+    }
+
+    pub fn encode_ordinary(&self, text: &str) -> Result<Vec<u32>, TokenizerError> {
+        self._encode_ordinary_native(text)
+    }
+
+    pub fn encode_with_allowed(
+        &self, text: &str, allowed_special: &SpecialSet,
+    ) -> Result<Vec<u32>, TokenizerError> {
+        Ok(self._encode_native(text, allowed_special)?.0)
+    }
+
+    pub fn count(&self, text: &str, allowed_special: &SpecialSet) -> Result<usize, TokenizerError> {
+        self._count_native(text, allowed_special)
+    }
+
+    pub fn encode_with_offsets(
+        &self, text: &str, allowed_special: &SpecialSet,
+    ) -> Result<(Vec<u32>, Vec<Range<usize>>), TokenizerError> {
+        self._encode_offsets_native(text, allowed_special)
+    }
+
+    pub fn encode_with_unstable(
+        &self, text: &str, allowed_special: &SpecialSet,
+    ) -> Result<(Vec<u32>, HashSet<Vec<u32>>), TokenizerError> {
+        self._encode_unstable_native(text, allowed_special)
+    }
 
-    pub fn encode_with_unstable(&self, text: &str) -> (Vec<u32>, HashSet<Vec<u32>>) {
-        self._encode_unstable_native(text)
+    pub fn encode_with_healing(
+        &self, text: &str, allowed_special: &SpecialSet,
+    ) -> Result<(Vec<u32>, Vec<u8>, Option<Vec<u32>>), TokenizerError> {
+        self._encode_healing_native(text, allowed_special)
     }
 
-    pub fn decode(&self, tokens: &[u32]) -> Vec<u8> { self._decode_native(tokens) }
+    /// Returns the first special token in `text` that is contained in `disallowed_special`
+    /// together with its byte position.
+    pub fn find_disallowed(
+        &self, text: &str, disallowed_special: &SpecialSet,
+    ) -> Result<Option<(String, usize)>, TokenizerError> {
+        Ok(self
+            ._next_special(text, 0, disallowed_special)?
+            .map(|m| (m.as_str().to_string(), m.start())))
+    }
+
+    /// Special tokens with their ranks, ordered by rank.
+    pub fn special_token_ranks(&self) -> Vec<(&str, u32)> {
+        let mut ranks = self
+            .special_tokens_encoder
+            .iter()
+            .map(|(token, rank)| (token.as_str(), *rank))
+            .collect::<Vec<_>>();
+        ranks.sort_by_key(|(_, rank)| *rank);
+        ranks
+    }
 
-    fn _decode_native(&self, tokens: &[u32]) -> Vec<u8> {
+    pub fn vocab_size(&self) -> usize { self.encoder.len() + self.special_tokens_encoder.len() }
+
+    pub fn max_token_id(&self) -> Option<u32> {
+        self.decoder.keys().chain(self.special_tokens_decoder.keys()).max().copied()
+    }
+
+    pub fn pattern(&self) -> &str { self.regex.as_str() }
+
+    /// Approximate heap usage of the vocabulary tables in bytes, not counting the compiled regexes.
+    pub fn memory_usage(&self) -> usize {
+        let entry = size_of::<Vec<u8>>() + size_of::<u32>();
+        let token_bytes = self.encoder.keys().map(|x| x.len()).sum::<usize>();
+        let special_bytes = self.special_tokens_encoder.keys().map(|x| x.len()).sum::<usize>();
+        // The encoder, decoder and sorted token bytes each hold a copy of every token
+        (self.encoder.len() * entry + token_bytes) * 3
+            + (self.special_tokens_encoder.len() * entry + special_bytes) * 2
+    }
+
+    /// Rank of the ordinary or special token spelling exactly `bytes`.
+    pub fn token_to_id(&self, bytes: &[u8]) -> Option<u32> {
+        self.encoder.get(bytes).copied().or_else(|| {
+            let token = std::str::from_utf8(bytes).ok()?;
+            self.special_tokens_encoder.get(token).copied()
+        })
+    }
+
+    pub fn id_to_token_bytes(&self, token: u32) -> Option<&[u8]> {
+        self.decoder
+            .get(&token)
+            .or_else(|| self.special_tokens_decoder.get(&token))
+            .map(Vec::as_slice)
+    }
+
+    pub fn is_special(&self, token: u32) -> bool {
+        self.special_tokens_decoder.contains_key(&token)
+    }
+
+    pub fn special_token_id(&self, name: &str) -> Option<u32> {
+        self.special_tokens_encoder.get(name).copied()
+    }
+
+    /// Byte positions in `text` where the pre-tokenization regex starts a new piece.
+    pub fn split_positions(&self, text: &str) -> Result<Vec<usize>, TokenizerError> {
+        self.regex
+            .find_iter(text)
+            .map(|mat| Ok(mat.map_err(TokenizerError::regex_error)?.start()))
+            .collect()
+    }
+
+    /// Ordinary tokens whose bytes start with `prefix`, in ascending order.
+    pub fn tokens_with_prefix(&self, prefix: &[u8]) -> Vec<u32> {
+        let mut tokens = self._tokens_with_prefix(prefix).collect::<Vec<_>>();
+        tokens.sort_unstable();
+        tokens
+    }
+
+    /// Ordinary tokens whose bytes are a prefix of `bytes`, shortest first.
+    pub fn prefix_tokens(&self, bytes: &[u8]) -> Vec<u32> {
+        (1..=bytes.len())
+            .filter_map(|end| self.encoder.get(&bytes[..end]).copied())
+            .collect()
+    }
+
+    pub fn is_valid_token(&self, token: u32) -> bool {
+        self.decoder.contains_key(&token) || self.special_tokens_decoder.contains_key(&token)
+    }
+
+    pub fn decode(&self, tokens: &[u32]) -> Result<Vec<u8>, TokenizerError> {
+        self._decode_native(tokens)
+    }
+
+    /// Decodes `tokens` with every special token replaced by `placeholder`, or skipped entirely
+    /// if there is no placeholder.
+    pub fn decode_with_placeholder(
+        &self, tokens: &[u32], placeholder: Option<&[u8]>,
+    ) -> Result<Vec<u8>, TokenizerError> {
         let mut ret = Vec::with_capacity(tokens.len() * 2);
         for token in tokens {
-            let token_bytes =
-                self.decoder.get(token).unwrap_or_else(|| &self.special_tokens_decoder[token]);
+            match self.decoder.get(token) {
+                Some(token_bytes) => ret.extend(token_bytes),
+                None if self.special_tokens_decoder.contains_key(token) => {
+                    ret.extend(placeholder.unwrap_or_default())
+                }
+                None => return Err(self._invalid_tokens(tokens)),
+            }
+        }
+        Ok(ret)
+    }
+
+    fn _decode_native(&self, tokens: &[u32]) -> Result<Vec<u8>, TokenizerError> {
+        let mut ret = Vec::with_capacity(tokens.len() * 2);
+        for token in tokens {
+            let token_bytes = self
+                .decoder
+                .get(token)
+                .or_else(|| self.special_tokens_decoder.get(token))
+                .ok_or_else(|| self._invalid_tokens(tokens))?;
             ret.extend(token_bytes);
         }
-        ret
+        Ok(ret)
+    }
+
+    fn _invalid_tokens(&self, tokens: &[u32]) -> TokenizerError {
+        TokenizerError::invalid_token_ids(tokens, |token| self.is_valid_token(token))
+            .unwrap_or(TokenizerError::InvalidTokenIds { tokens: vec![] })
     }
 
-    fn _encode_ordinary_native(&self, text: &str) -> Vec<u32> {
+    fn _encode_ordinary_native(&self, text: &str) -> Result<Vec<u32>, TokenizerError> {
         // This is the core of the encoding logic; the other functions in here
         // just make things complicated :-)
         let regex = &self.regex;
         let mut ret = vec![];
         for mat in regex.find_iter(text) {
-            let piece = mat.unwrap().as_str().as_bytes();
+            let piece = mat.map_err(TokenizerError::regex_error)?.as_str().as_bytes();
             if let Some(token) = self.encoder.get(piece) {
                 ret.push(*token);
                 continue;
             }
-            ret.extend(&byte_pair_encode(piece, &self.encoder));
+            ret.extend(&byte_pair_encode(piece, &self.encoder)?);
+        }
+        Ok(ret)
+    }
+
+    fn _next_special<'t>(
+        &self, text: &'t str, start: usize, allowed_special: &SpecialSet,
+    ) -> Result<Option<fancy_regex::Match<'t>>, TokenizerError> {
+        // Find the next allowed special token, if any
+        if allowed_special.is_empty() || self.special_tokens_encoder.is_empty() {
+            return Ok(None);
+        }
+        let mut start_find = start;
+        loop {
+            let m = match self
+                .special_regex
+                .find_from_pos(text, start_find)
+                .map_err(TokenizerError::regex_error)?
+            {
+                Some(m) => m,
+                None => return Ok(None),
+            };
+            if allowed_special.contains(m.as_str()) {
+                return Ok(Some(m));
+            }
+            start_find = m.start() + text[m.start()..].chars().next().map_or(1, char::len_utf8);
         }
-        ret
     }
 
-    fn _encode_native(&self, text: &str) -> (Vec<u32>, u32) {
-        let special_regex = &self.special_regex;
+    fn _encode_native(
+        &self, text: &str, allowed_special: &SpecialSet,
+    ) -> Result<(Vec<u32>, u32), TokenizerError> {
         let regex = &self.regex;
         let mut ret = vec![];
 
         let mut start = 0;
         let mut last_piece_token_len = 0;
         loop {
-            let next_special;
-            let start_find = start;
-            next_special = special_regex.find_from_pos(text, start_find).unwrap();
+            let next_special = self._next_special(text, start, allowed_special)?;
             let end = next_special.map_or(text.len(), |m| m.start());
 
             // Okay, here we go, compare this logic to _encode_ordinary_native
             for mat in regex.find_iter(&text[start..end]) {
-                let piece = mat.unwrap().as_str().as_bytes();
+                let piece = mat.map_err(TokenizerError::regex_error)?.as_str().as_bytes();
                 if let Some(token) = self.encoder.get(piece) {
                     last_piece_token_len = 1;
                     ret.push(*token);
                     continue;
                 }
-                let tokens = byte_pair_encode(piece, &self.encoder);
+                let tokens = byte_pair_encode(piece, &self.encoder)?;
                 last_piece_token_len = tokens.len() as u32;
                 ret.extend(&tokens);
             }
@@ -157,7 +357,82 @@ impl CoreBPE {
 
         // last_piece_token_len is how many tokens came from the last regex split. This is used
         // for determining unstable tokens, since you can't merge across (stable) regex splits
-        (ret, last_piece_token_len)
+        Ok((ret, last_piece_token_len))
+    }
+
+    fn _count_native(
+        &self, text: &str, allowed_special: &SpecialSet,
+    ) -> Result<usize, TokenizerError> {
+        // Same as _encode_native, but only sums up the number of tokens
+        let regex = &self.regex;
+        let mut count = 0;
+
+        let mut start = 0;
+        loop {
+            let next_special = self._next_special(text, start, allowed_special)?;
+            let end = next_special.map_or(text.len(), |m| m.start());
+
+            for mat in regex.find_iter(&text[start..end]) {
+                let piece = mat.map_err(TokenizerError::regex_error)?.as_str().as_bytes();
+                if piece.len() == 1 || self.encoder.contains_key(piece) {
+                    count += 1;
+                    continue;
+                }
+                count += _byte_pair_merge(piece, &self.encoder, |_| ()).len();
+            }
+
+            match next_special {
+                Some(m) => {
+                    count += 1;
+                    start = m.end();
+                }
+                None => break,
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn _encode_offsets_native(
+        &self, text: &str, allowed_special: &SpecialSet,
+    ) -> Result<(Vec<u32>, Vec<Range<usize>>), TokenizerError> {
+        // Same as _encode_native, but keeps track of the byte range of every token
+        let regex = &self.regex;
+        let mut ret = vec![];
+        let mut offsets = vec![];
+
+        let mut start = 0;
+        loop {
+            let next_special = self._next_special(text, start, allowed_special)?;
+            let end = next_special.map_or(text.len(), |m| m.start());
+
+            for mat in regex.find_iter(&text[start..end]) {
+                let mat = mat.map_err(TokenizerError::regex_error)?;
+                let piece = mat.as_str().as_bytes();
+                let offset = start + mat.start();
+                if let Some(token) = self.encoder.get(piece) {
+                    ret.push(*token);
+                    offsets.push(offset..offset + piece.len());
+                    continue;
+                }
+                for (token, range) in byte_pair_encode_with_offsets(piece, &self.encoder)? {
+                    ret.push(token);
+                    offsets.push(offset + range.start..offset + range.end);
+                }
+            }
+
+            match next_special {
+                Some(m) => {
+                    let token = self.special_tokens_encoder[m.as_str()];
+                    ret.push(token);
+                    offsets.push(m.start()..m.end());
+                    start = m.end();
+                }
+                None => break,
+            }
+        }
+
+        Ok((ret, offsets))
     }
 
     fn _increase_last_piece_token_len(
@@ -194,18 +469,20 @@ impl CoreBPE {
         (tokens, last_piece_token_len)
     }
 
-    fn _encode_unstable_native(&self, text: &str) -> (Vec<u32>, HashSet<Vec<u32>>) {
-        let (tokens, last_piece_token_len) = self._encode_native(text);
+    fn _encode_unstable_native(
+        &self, text: &str, allowed_special: &SpecialSet,
+    ) -> Result<(Vec<u32>, HashSet<Vec<u32>>), TokenizerError> {
+        let (tokens, last_piece_token_len) = self._encode_native(text, allowed_special)?;
         if last_piece_token_len == 0 {
             // If last_piece_token_len is zero, the last token was a special token and we have
             // no unstable bytes
-            return (tokens, HashSet::new());
+            return Ok((tokens, HashSet::new()));
         }
         let (mut tokens, last_piece_token_len) =
             self._increase_last_piece_token_len(tokens, last_piece_token_len);
 
         let unstable_bytes =
-            self._decode_native(&tokens[tokens.len() - last_piece_token_len as usize..]);
+            self._decode_native(&tokens[tokens.len() - last_piece_token_len as usize..])?;
         tokens.truncate(tokens.len() - last_piece_token_len as usize);
 
         // TODO: we should try harder to find additional stable tokens
@@ -214,7 +491,7 @@ impl CoreBPE {
 
         let mut completions = HashSet::new();
         if unstable_bytes.is_empty() {
-            return (tokens, completions);
+            return Ok((tokens, completions));
         }
 
         // This is the easy bit. Just find all single tokens that start with unstable_bytes
@@ -249,13 +526,13 @@ impl CoreBPE {
                     // So convert to UTF-8 and do regex splitting.
                     // E.g. with cl100k_base "  !" gets split to " " + " !",
                     // but byte_pair_encode("  !") != byte_pair_encode(" ")
-                    Ok(s) => self._encode_ordinary_native(s),
+                    Ok(s) => self._encode_ordinary_native(s)?,
 
                     // Technically, whether or not this arm is correct depends on whether there
                     // would be a regex split before the UTF-8 truncation point.
                     // Probably niche enough that no one will ever notice (after all, people didn't
                     // notice all the big holes in the previous unstable token implementation)
-                    Err(_) => byte_pair_encode(&possibility, &self.encoder),
+                    Err(_) => byte_pair_encode(&possibility, &self.encoder)?,
                     // Something like the following is intriguing but incorrect:
                     // Err(e) => self._encode_ordinary_native(unsafe {
                     //     std::str::from_utf8_unchecked(&possibility[..e.valid_up_to()])
@@ -291,16 +568,51 @@ impl CoreBPE {
                 let mut reencoded = byte_pair_encode(
                     &unstable_bytes[..unstable_bytes.len() - last_decoded.1],
                     &self.encoder,
-                );
+                )?;
                 reencoded.extend(byte_pair_encode(
                     &unstable_bytes[unstable_bytes.len() - last_decoded.1..],
                     &self.encoder,
-                ));
+                )?);
                 completions.insert(reencoded);
             }
         }
 
-        (tokens, completions)
+        Ok((tokens, completions))
+    }
+
+    fn _tokens_with_prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = u32> + 'a {
+        let point = self.sorted_token_bytes.partition_point(|x| x.as_slice() < prefix);
+        self.sorted_token_bytes[point..]
+            .iter()
+            .take_while(move |x| x.starts_with(prefix))
+            .map(move |x| self.encoder[x.as_slice()])
+    }
+
+    fn _encode_healing_native(
+        &self, text: &str, allowed_special: &SpecialSet,
+    ) -> Result<(Vec<u32>, Vec<u8>, Option<Vec<u32>>), TokenizerError> {
+        // Removes the unstable tail like _encode_unstable_native, but instead of listing
+        // completions returns every single token that could replace the removed bytes
+        let (tokens, last_piece_token_len) = self._encode_native(text, allowed_special)?;
+        if last_piece_token_len == 0 {
+            // Nothing follows the last special token, so any token may come next
+            return Ok((tokens, vec![], None));
+        }
+        let (mut tokens, last_piece_token_len) =
+            self._increase_last_piece_token_len(tokens, last_piece_token_len);
+
+        let mut removed = last_piece_token_len as usize;
+        let mut prefix = self._decode_native(&tokens[tokens.len() - removed..])?;
+        if removed > 1 && self._tokens_with_prefix(&prefix).next().is_none() {
+            // No single token spans the whole piece, so only the last token is healed, which
+            // always has at least itself as a replacement
+            removed = 1;
+            prefix = self._decode_native(&tokens[tokens.len() - 1..])?;
+        }
+        tokens.truncate(tokens.len() - removed);
+
+        let allowed = self._tokens_with_prefix(&prefix).collect();
+        Ok((tokens, prefix, Some(allowed)))
     }
 }
 
@@ -397,9 +709,35 @@ fn _byte_pair_merge<T>(
     out
 }
 
-pub fn byte_pair_encode(piece: &[u8], ranks: &HashMap<Vec<u8>, u32>) -> Vec<u32> {
+// Vocabularies without a token for every byte can't encode every piece, which only fails once
+// such a piece is encoded
+fn rank(piece: &[u8], ranks: &HashMap<Vec<u8>, u32>) -> Result<u32, TokenizerError> {
+    ranks.get(piece).copied().ok_or_else(|| {
+        TokenizerError::tokenizer_failed(format!("BPE has no token for the bytes {:?}", piece))
+    })
+}
+
+pub fn byte_pair_encode(
+    piece: &[u8], ranks: &HashMap<Vec<u8>, u32>,
+) -> Result<Vec<u32>, TokenizerError> {
+    if piece.len() == 1 {
+        return Ok(vec![rank(piece, ranks)?]);
+    }
+    _byte_pair_merge(piece, ranks, |p| rank(&piece[p.start as usize..p.end as usize], ranks))
+        .into_iter()
+        .collect()
+}
+
+pub fn byte_pair_encode_with_offsets(
+    piece: &[u8], ranks: &HashMap<Vec<u8>, u32>,
+) -> Result<Vec<(u32, Range<usize>)>, TokenizerError> {
     if piece.len() == 1 {
-        return vec![ranks[piece]];
+        return Ok(vec![(rank(piece, ranks)?, 0..1)]);
     }
-    _byte_pair_merge(piece, ranks, |p| ranks[&piece[p.start as usize..p.end as usize]])
+    _byte_pair_merge(piece, ranks, |p| {
+        let range = p.start as usize..p.end as usize;
+        Ok((rank(&piece[range.clone()], ranks)?, range))
+    })
+    .into_iter()
+    .collect()
 }
@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, Map};
+
+use crate::{error::TokenizerError, EncodeOptions, TokenizerVariant};
+
+/// Function definition, either on its own or wrapped in a `{"type": "function"}` tool.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ToolDefinition {
+    Tool { function: FunctionDefinition },
+    Function(FunctionDefinition),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionDefinition {
+    pub name:        String,
+    pub description: Option<String>,
+    pub parameters:  Option<JsonSchema>,
+}
+
+/// The subset of JSON schema that is rendered into the prompt.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct JsonSchema {
+    #[serde(rename = "type")]
+    pub kind:        Option<SchemaType>,
+    pub description: Option<String>,
+    // Kept as a list to preserve the order of the properties
+    #[serde_as(as = "Option<Map<_, _>>")]
+    pub properties:  Option<Vec<(String, JsonSchema)>>,
+    pub required:    Option<Vec<String>>,
+    #[serde(rename = "enum")]
+    pub values:      Option<Vec<EnumValue>>,
+    pub items:       Option<Box<JsonSchema>>,
+    #[serde(rename = "anyOf")]
+    pub any_of:      Option<Vec<JsonSchema>>,
+    #[serde(rename = "oneOf")]
+    pub one_of:      Option<Vec<JsonSchema>>,
+    #[serde(rename = "$ref")]
+    pub reference:   Option<String>,
+}
+
+/// A single `type` or a list of them, rendered as a union.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SchemaType {
+    Single(String),
+    Union(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum EnumValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Null,
+}
+
+impl EnumValue {
+    // Rendered as JSON literals, so quotes and control characters in strings are escaped
+    fn render(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "null".to_string())
+    }
+}
+
+// Both overheads are the ones openai-chat-tokens measured against the API. The 9 tokens are the 6
+// cl100k tokens of the `# Tools\n\n## functions\n\n` header plus the 3 tokens of the system
+// message the definitions are placed in
+const TOOLS_OVERHEAD: usize = 9;
+// Tokens saved when the definitions are merged into an existing system message instead
+const SYSTEM_MESSAGE_OVERLAP: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolTokens {
+    pub text:          String,
+    pub text_tokens:   usize,
+    pub prompt_tokens: usize,
+}
+
+/// Renders `tools` like the cl100k and o200k chat formats present them to the model and counts
+/// the tokens they add to the prompt.
+pub fn count_tool_tokens(
+    tokenizer: &TokenizerVariant, tools: &[ToolDefinition], system_message: bool,
+) -> Result<ToolTokens, TokenizerError> {
+    let text = render_tools(tools);
+    let text_tokens = tokenizer.count(&text, &EncodeOptions::new(Some(false), None, None))?;
+    let prompt_tokens = if tools.is_empty() {
+        0
+    } else if system_message {
+        text_tokens + TOOLS_OVERHEAD - SYSTEM_MESSAGE_OVERLAP
+    } else {
+        text_tokens + TOOLS_OVERHEAD
+    };
+    Ok(ToolTokens {
+        text,
+        text_tokens,
+        prompt_tokens,
+    })
+}
+
+/// Renders function definitions as a TypeScript namespace.
+pub fn render_tools(tools: &[ToolDefinition]) -> String {
+    let mut lines = vec!["namespace functions {".to_string(), String::new()];
+    for tool in tools {
+        let function = match tool {
+            ToolDefinition::Tool { function } | ToolDefinition::Function(function) => function,
+        };
+        if let Some(description) = &function.description {
+            lines.push(format!("// {}", description));
+        }
+        match &function.parameters {
+            Some(parameters) if parameters.properties.as_ref().is_some_and(|x| !x.is_empty()) => {
+                lines.push(format!("type {} = (_: {{", function.name));
+                lines.push(render_properties(parameters, 0));
+                lines.push("}) => any;".to_string());
+            }
+            _ => lines.push(format!("type {} = () => any;", function.name)),
+        }
+        lines.push(String::new());
+    }
+    lines.push("} // namespace functions".to_string());
+    lines.join("\n")
+}
+
+fn render_properties(schema: &JsonSchema, indent: usize) -> String {
+    let mut lines = vec![];
+    for (name, property) in schema.properties.iter().flatten() {
+        if let (Some(description), true) = (&property.description, indent < 2) {
+            lines.push(format!("// {}", description));
+        }
+        let required = schema.required.iter().flatten().any(|x| x == name);
+        let optional = if required { "" } else { "?" };
+        lines.push(format!("{}{}: {},", name, optional, render_type(property, indent)));
+    }
+    lines
+        .iter()
+        .map(|line| format!("{}{}", " ".repeat(indent), line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn render_type(schema: &JsonSchema, indent: usize) -> String {
+    // References are rendered as the name of the definition they point to
+    if let Some(reference) = &schema.reference {
+        return reference.rsplit('/').next().unwrap_or(reference).to_string();
+    }
+    if let Some(variants) = schema.any_of.as_ref().or(schema.one_of.as_ref()) {
+        return variants.iter().map(|x| render_type(x, indent)).collect::<Vec<_>>().join(" | ");
+    }
+    if let Some(values) = &schema.values {
+        return values.iter().map(EnumValue::render).collect::<Vec<_>>().join(" | ");
+    }
+    match &schema.kind {
+        Some(SchemaType::Single(kind)) => render_kind(schema, kind, indent),
+        Some(SchemaType::Union(kinds)) => kinds
+            .iter()
+            .map(|kind| render_kind(schema, kind, indent))
+            .collect::<Vec<_>>()
+            .join(" | "),
+        None => "any".to_string(),
+    }
+}
+
+fn render_kind(schema: &JsonSchema, kind: &str, indent: usize) -> String {
+    match kind {
+        "string" => "string".to_string(),
+        "number" | "integer" => "number".to_string(),
+        "boolean" => "boolean".to_string(),
+        "null" => "null".to_string(),
+        "array" => match &schema.items {
+            Some(items) => format!("{}[]", render_type(items, indent)),
+            None => "any[]".to_string(),
+        },
+        "object" => format!("{{\n{}\n}}", render_properties(schema, indent + 2)),
+        _ => "any".to_string(),
+    }
+}
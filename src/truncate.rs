@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{error::TokenizerError, EncodeOptions, TokenizerVariant};
+
+/// Which part of the text survives truncation.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum TruncationDirection {
+    #[default]
+    Head,
+    Tail,
+    // Keeps the start and end of the text joined by an ellipsis
+    Both,
+}
+
+pub const DEFAULT_ELLIPSIS: &str = "…";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truncation {
+    pub tokens:    Vec<u32>,
+    pub text:      String,
+    pub truncated: bool,
+}
+
+/// Truncates `text` to at most `max_tokens` tokens.
+///
+/// The returned text always re-encodes to exactly the returned tokens, which may differ from
+/// the corresponding tokens of the full text where the cut changes how the boundary merges.
+pub fn truncate(
+    tokenizer: &TokenizerVariant, text: &str, max_tokens: usize, direction: TruncationDirection,
+    ellipsis: &str, options: &EncodeOptions,
+) -> Result<Truncation, TokenizerError> {
+    let (tokens, offsets) = tokenizer.encode_with_offsets(text, options)?;
+    if tokens.len() <= max_tokens {
+        return Ok(Truncation {
+            tokens,
+            text: text.to_string(),
+            truncated: false,
+        });
+    }
+    let marker_tokens = tokenizer.count(ellipsis, options)?;
+    let direction = match direction {
+        TruncationDirection::Both if marker_tokens > max_tokens => TruncationDirection::Head,
+        direction => direction,
+    };
+    match direction {
+        TruncationDirection::Head => {
+            let (tokens, end) = keep_head(tokenizer, text, &offsets, max_tokens, options)?;
+            Ok(Truncation {
+                tokens,
+                text: text[..end].to_string(),
+                truncated: true,
+            })
+        }
+        TruncationDirection::Tail => {
+            let (tokens, start) = keep_tail(tokenizer, text, &offsets, max_tokens, options)?;
+            Ok(Truncation {
+                tokens,
+                text: text[start..].to_string(),
+                truncated: true,
+            })
+        }
+        TruncationDirection::Both => {
+            // Tokens can merge across the ellipsis, so shrink the budget until the joined text fits
+            let mut budget = max_tokens - marker_tokens;
+            loop {
+                let (_, end) = keep_head(tokenizer, text, &offsets, budget - budget / 2, options)?;
+                let (_, start) = keep_tail(tokenizer, text, &offsets, budget / 2, options)?;
+                let joined = [&text[..end], ellipsis, &text[start.max(end)..]].concat();
+                let tokens = tokenizer.encode(&joined, options)?;
+                if tokens.len() <= max_tokens || budget == 0 {
+                    return Ok(Truncation {
+                        tokens,
+                        text: joined,
+                        truncated: true,
+                    });
+                }
+                budget -= 1;
+            }
+        }
+    }
+}
+
+/// Longest prefix of `text` ending at a token boundary that encodes to at most `max_tokens`.
+fn keep_head(
+    tokenizer: &TokenizerVariant, text: &str, offsets: &[(usize, usize)], max_tokens: usize,
+    options: &EncodeOptions,
+) -> Result<(Vec<u32>, usize), TokenizerError> {
+    for keep in (1..=max_tokens.min(offsets.len())).rev() {
+        // Tokens added by a post-processor have empty offsets and don't bound the text
+        let end = offsets[..keep].iter().map(|(_, end)| *end).max().unwrap_or(0);
+        if !text.is_char_boundary(end) {
+            continue;
+        }
+        let tokens = tokenizer.encode(&text[..end], options)?;
+        if tokens.len() <= max_tokens {
+            return Ok((tokens, end));
+        }
+    }
+    Ok((tokenizer.encode("", options)?, 0))
+}
+
+/// Longest suffix of `text` starting at a token boundary that encodes to at most `max_tokens`.
+fn keep_tail(
+    tokenizer: &TokenizerVariant, text: &str, offsets: &[(usize, usize)], max_tokens: usize,
+    options: &EncodeOptions,
+) -> Result<(Vec<u32>, usize), TokenizerError> {
+    for keep in (1..=max_tokens.min(offsets.len())).rev() {
+        let start = offsets[offsets.len() - keep..]
+            .iter()
+            .filter(|(start, end)| start < end)
+            .map(|(start, _)| *start)
+            .min()
+            .unwrap_or(text.len());
+        if !text.is_char_boundary(start) {
+            continue;
+        }
+        let tokens = tokenizer.encode(&text[start..], options)?;
+        if tokens.len() <= max_tokens {
+            return Ok((tokens, start));
+        }
+    }
+    Ok((tokenizer.encode("", options)?, text.len()))
+}
@@ -0,0 +1,210 @@
+use tokenizers::{DecoderWrapper, PreTokenizerWrapper, Tokenizer};
+
+/// How the tokens of a Hugging Face vocabulary map onto raw bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenBytes {
+    // GPT-2 style, every byte is represented by a printable character
+    ByteLevel,
+    // SentencePiece style, bytes without a token of their own are spelled as `<0xXX>`
+    ByteFallback,
+    // SentencePiece style, spaces are spelled with the replacement character (`▁`) and bytes
+    // without a token of their own as `<0xXX>`
+    Metaspace(char),
+    Plain,
+}
+
+fn has_decoder(decoder: &DecoderWrapper, f: fn(&DecoderWrapper) -> bool) -> bool {
+    match decoder {
+        DecoderWrapper::Sequence(sequence) => {
+            sequence.get_decoders().iter().any(|decoder| has_decoder(decoder, f))
+        }
+        decoder => f(decoder),
+    }
+}
+
+fn metaspace_replacement(tokenizer: &Tokenizer) -> Option<char> {
+    fn find_decoder(decoder: &DecoderWrapper) -> Option<char> {
+        match decoder {
+            DecoderWrapper::Sequence(sequence) => {
+                sequence.get_decoders().iter().find_map(find_decoder)
+            }
+            DecoderWrapper::Metaspace(metaspace) => Some(metaspace.get_replacement()),
+            _ => None,
+        }
+    }
+    fn find_pre_tokenizer(pre_tokenizer: &PreTokenizerWrapper) -> Option<char> {
+        match pre_tokenizer {
+            PreTokenizerWrapper::Sequence(sequence) => {
+                sequence.get_pre_tokenizers().iter().find_map(find_pre_tokenizer)
+            }
+            PreTokenizerWrapper::Metaspace(metaspace) => Some(metaspace.get_replacement()),
+            _ => None,
+        }
+    }
+    tokenizer
+        .get_decoder()
+        .and_then(find_decoder)
+        .or_else(|| tokenizer.get_pre_tokenizer().and_then(find_pre_tokenizer))
+}
+
+fn token_bytes(tokenizer: &Tokenizer) -> TokenBytes {
+    let uses_decoder = |f: fn(&DecoderWrapper) -> bool| {
+        tokenizer.get_decoder().is_some_and(|decoder| has_decoder(decoder, f))
+    };
+    if uses_decoder(|x| matches!(x, DecoderWrapper::ByteLevel(_))) {
+        TokenBytes::ByteLevel
+    } else if let Some(replacement) = metaspace_replacement(tokenizer) {
+        TokenBytes::Metaspace(replacement)
+    } else if uses_decoder(|x| matches!(x, DecoderWrapper::ByteFallback(_))) {
+        TokenBytes::ByteFallback
+    } else {
+        TokenBytes::Plain
+    }
+}
+
+const fn is_printable(byte: u8) -> bool { matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF) }
+
+/// The GPT-2 byte-level alphabet, mapping every byte to the character that stands for it.
+/// Printable bytes stand for themselves, the others are numbered from U+0100 in byte order.
+const BYTE_LEVEL_ALPHABET: [char; 256] = {
+    let mut alphabet = ['\0'; 256];
+    let mut unprintable = 0;
+    let mut byte = 0;
+    while byte < 256 {
+        alphabet[byte] = if is_printable(byte as u8) {
+            byte as u8 as char
+        } else {
+            unprintable += 1;
+            match char::from_u32(255 + unprintable) {
+                Some(c) => c,
+                None => '\0',
+            }
+        };
+        byte += 1;
+    }
+    alphabet
+};
+
+/// The inverse of [`BYTE_LEVEL_ALPHABET`], indexed by character. The 68 unprintable bytes end at
+/// U+0143, so the table covers every character of the alphabet.
+const BYTE_LEVEL_BYTES: [Option<u8>; 0x144] = {
+    let mut bytes = [None; 0x144];
+    let mut byte = 0;
+    while byte < 256 {
+        bytes[BYTE_LEVEL_ALPHABET[byte] as usize] = Some(byte as u8);
+        byte += 1;
+    }
+    bytes
+};
+
+fn byte_level_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| BYTE_LEVEL_ALPHABET[*byte as usize]).collect()
+}
+
+fn byte_level_byte(c: char) -> Option<u8> { BYTE_LEVEL_BYTES.get(c as usize).copied().flatten() }
+
+fn byte_level_decode(token: &str) -> Option<Vec<u8>> {
+    token.chars().map(byte_level_byte).collect()
+}
+
+fn byte_fallback_decode(token: &str) -> Option<u8> {
+    let hex = token.strip_prefix("<0x")?.strip_suffix('>')?;
+    if hex.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
+/// Raw bytes of the token `id`, undoing the byte-level or byte fallback spelling of the model
+/// vocabulary. Added tokens are stored verbatim and returned as is.
+pub fn hf_id_to_token_bytes(tokenizer: &Tokenizer, id: u32) -> Option<Vec<u8>> {
+    let added = tokenizer.get_added_vocabulary().get_added_tokens_decoder();
+    if let Some(token) = added.get(&id) {
+        return Some(token.content.as_bytes().to_vec());
+    }
+    let token = tokenizer.id_to_token(id)?;
+    model_token_bytes(token, token_bytes(tokenizer))
+}
+
+fn model_token_bytes(token: String, spelling: TokenBytes) -> Option<Vec<u8>> {
+    match spelling {
+        TokenBytes::ByteLevel => byte_level_decode(&token),
+        TokenBytes::ByteFallback => match byte_fallback_decode(&token) {
+            Some(byte) => Some(vec![byte]),
+            None => Some(token.into_bytes()),
+        },
+        TokenBytes::Metaspace(replacement) => match byte_fallback_decode(&token) {
+            Some(byte) => Some(vec![byte]),
+            None => Some(token.replace(replacement, " ").into_bytes()),
+        },
+        TokenBytes::Plain => Some(token.into_bytes()),
+    }
+}
+
+/// Id of the token spelling exactly `bytes`, the inverse of [`hf_id_to_token_bytes`].
+pub fn hf_token_to_id(tokenizer: &Tokenizer, bytes: &[u8]) -> Option<u32> {
+    let token = std::str::from_utf8(bytes).ok();
+    if let Some(id) =
+        token.and_then(|token| tokenizer.get_added_vocabulary().get_vocab().get(token))
+    {
+        return Some(*id);
+    }
+    match token_bytes(tokenizer) {
+        TokenBytes::ByteLevel => tokenizer.token_to_id(&byte_level_encode(bytes)),
+        TokenBytes::ByteFallback => {
+            token.and_then(|token| tokenizer.token_to_id(token)).or_else(|| match bytes {
+                [byte] => tokenizer.token_to_id(&format!("<0x{:02X}>", byte)),
+                _ => None,
+            })
+        }
+        TokenBytes::Metaspace(replacement) => token
+            .and_then(|token| tokenizer.token_to_id(&token.replace(' ', &replacement.to_string())))
+            .or_else(|| match bytes {
+                [byte] => tokenizer.token_to_id(&format!("<0x{:02X}>", byte)),
+                _ => None,
+            }),
+        TokenBytes::Plain => tokenizer.token_to_id(token?),
+    }
+}
+
+pub fn hf_is_special(tokenizer: &Tokenizer, id: u32) -> bool {
+    let added = tokenizer.get_added_vocabulary().get_added_tokens_decoder();
+    added.get(&id).map(|token| token.special).unwrap_or(false)
+}
+
+/// Every non-special token with its raw bytes, sorted by bytes so prefix queries can binary
+/// search it like [`CoreBPE`](crate::tiktoken::CoreBPE) does. Built once when loading.
+pub fn hf_sorted_token_bytes(tokenizer: &Tokenizer) -> Vec<(Vec<u8>, u32)> {
+    let spelling = token_bytes(tokenizer);
+    let added = tokenizer.get_added_vocabulary().get_added_tokens_decoder();
+    let model = tokenizer
+        .get_vocab(false)
+        .into_iter()
+        .filter(|(_, id)| !added.contains_key(id))
+        .filter_map(|(token, id)| Some((model_token_bytes(token, spelling)?, id)));
+    let added = added
+        .iter()
+        .filter(|(_, token)| !token.special)
+        .map(|(id, token)| (token.content.as_bytes().to_vec(), *id));
+    let mut sorted_token_bytes = model.chain(added).collect::<Vec<_>>();
+    sorted_token_bytes.sort();
+    sorted_token_bytes
+}
+
+pub fn hf_tokens_with_prefix(sorted_token_bytes: &[(Vec<u8>, u32)], prefix: &[u8]) -> Vec<u32> {
+    let point = sorted_token_bytes.partition_point(|(bytes, _)| bytes.as_slice() < prefix);
+    let mut tokens = sorted_token_bytes[point..]
+        .iter()
+        .take_while(|(bytes, _)| bytes.starts_with(prefix))
+        .map(|(_, id)| *id)
+        .collect::<Vec<_>>();
+    tokens.sort_unstable();
+    tokens
+}
+
+pub fn hf_prefix_tokens(tokenizer: &Tokenizer, bytes: &[u8]) -> Vec<u32> {
+    (1..=bytes.len())
+        .filter_map(|end| hf_token_to_id(tokenizer, &bytes[..end]))
+        .filter(|id| !hf_is_special(tokenizer, *id))
+        .collect()
+}
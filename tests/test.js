@@ -56,6 +56,11 @@ test('encode-tiktoken', async () => {
     const tokens2 = tokenizer.encode('cl100k', 'hello <|endoftext|>')
     console.log('Tokens:', [...tokens2])
     assert.equal(JSON.stringify([...tokens2]), JSON.stringify([15339, 220, 100257]), 'Tokens should be [15339, 220, 100257]')
+
+    const counts = tokenizer.countBatch('cl100k', ['Hello World!', 'hello <|endoftext|>'])
+    console.log('Counts:', counts)
+    assert.equal(tokenizer.count('cl100k', 'Hello World!'), 3, 'Count should be 3')
+    assert.equal(JSON.stringify(counts), JSON.stringify([3, 3]), 'Counts should be [3, 3]')
 })
 
 test('decode-tiktoken', async () => {
@@ -85,6 +90,14 @@ test('decode-tiktoken', async () => {
     const string2 = tokenizer.decode('cl100k', new Uint32Array([15339, 220, 100257]))
     console.log('String:', string2)
     assert.equal(string2, 'hello <|endoftext|>', 'String should be "hello <|endoftext|>"')
+
+    const string3 = tokenizer.decode('cl100k', new Uint32Array([15339, 220, 100257]), false)
+    console.log('String:', string3)
+    assert.equal(string3, 'hello ', 'String should be "hello "')
+
+    const string4 = tokenizer.decode('cl100k', new Uint32Array([15339, 220, 100257]), false, { special_placeholder: '[SPECIAL]' })
+    console.log('String:', string4)
+    assert.equal(string4, 'hello [SPECIAL]', 'String should be "hello [SPECIAL]"')
 })
 
 test('encode-huggingface', async () => {
@@ -122,3 +135,411 @@ test('decode-huggingface', async () => {
     console.log('String:', string2)
     assert.equal(string2, 'hello <|endoftext|>', 'String should be "hello <|endoftext|>"')
 })
+
+test('batch-huggingface', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './neox_20b_tokenizer.json'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('neox20b', {
+        model: data,
+    })
+
+    const tokens = tokenizer.encodeBatch('neox20b', ['Hello World!', 'hello <|endoftext|>'])
+    console.log('Tokens:', tokens.map((x) => [...x]))
+    assert.equal(JSON.stringify([...tokens[0]]), JSON.stringify([12092, 3645, 2]), 'Tokens should be [12092, 3645, 2]')
+    assert.equal(JSON.stringify([...tokens[1]]), JSON.stringify([25521, 209, 0]), 'Tokens should be [25521, 209, 0]')
+
+    const strings = tokenizer.decodeBatch('neox20b', tokens)
+    console.log('Strings:', strings)
+    assert.equal(strings[0], 'Hello World!', 'String should be "Hello World!"')
+    assert.equal(strings[1], 'hello <|endoftext|>', 'String should be "hello <|endoftext|>"')
+})
+
+test('offsets-tiktoken', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', {
+        bpe: data,
+        special_bpe: [['<|endoftext|>', 100257]],
+        // eslint-disable-next-line max-len
+        regex: String.raw`(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+`,
+    })
+
+    const result = tokenizer.encodeWithOffsets('cl100k', 'Hello World!')
+    console.log('Offsets:', [...result.charOffsets])
+    assert.equal(JSON.stringify([...result.tokens]), JSON.stringify([9906, 4435, 0]), 'Tokens should be [9906, 4435, 0]')
+    assert.equal(JSON.stringify([...result.byteOffsets]), JSON.stringify([0, 5, 5, 11, 11, 12]), 'Offsets should be [0, 5, 5, 11, 11, 12]')
+    assert.equal(JSON.stringify([...result.charOffsets]), JSON.stringify([0, 5, 5, 11, 11, 12]), 'Offsets should be [0, 5, 5, 11, 11, 12]')
+
+    const text = 'Hi 🚀!'
+    const result2 = tokenizer.encodeWithOffsets('cl100k', text)
+    const spans = [...result2.tokens].map((_, i) => text.slice(result2.utf16Offsets[i * 2], result2.utf16Offsets[i * 2 + 1]))
+    console.log('Spans:', spans)
+    const loneSurrogate = /[\uD800-\uDBFF](?![\uDC00-\uDFFF])|(?<![\uD800-\uDBFF])[\uDC00-\uDFFF]/
+    assert.ok(
+        spans.every((x) => x.length > 0 && !loneSurrogate.test(x)),
+        'Spans should not split surrogate pairs'
+    )
+})
+
+test('stream-decoder-tiktoken', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', {
+        bpe: data,
+        special_bpe: [['<|endoftext|>', 100257]],
+        // eslint-disable-next-line max-len
+        regex: String.raw`(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+`,
+    })
+
+    const decoder = tokenizer.createDecoder('cl100k')
+    const parts = [...tokenizer.encode('cl100k', 'Hello 🚀 World!')].map((token) => decoder.push(new Uint32Array([token])))
+    parts.push(decoder.flush())
+    decoder.drop()
+    console.log('Parts:', parts)
+    assert.ok(
+        parts.every((x) => !x.includes('\uFFFD')),
+        'Parts should not contain replacement characters'
+    )
+    assert.equal(parts.join(''), 'Hello 🚀 World!', 'String should be "Hello 🚀 World!"')
+})
+
+test('errors', async () => {
+    const { Tokenizer, TokenizerError } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    assert.throws(
+        () => tokenizer.encode('missing', 'Hello World!'),
+        (error) => error instanceof TokenizerError && error.kind === 'tokenizer-not-found' && error.context.name === 'missing',
+        'Encoding with an unknown tokenizer should throw tokenizer-not-found'
+    )
+})
+
+test('invalid-tokens-tiktoken', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const { Tokenizer, TokenizerError } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', {
+        bpe: data,
+        special_bpe: [['<|endoftext|>', 100257]],
+        // eslint-disable-next-line max-len
+        regex: String.raw`(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+`,
+    })
+
+    const tokens = new Uint32Array([9906, 999999, 4435])
+    assert.throws(
+        () => tokenizer.decode('cl100k', tokens),
+        (error) => error instanceof TokenizerError && error.kind === 'invalid-token-ids' && error.context.tokens[0].position === 1,
+        'Decoding unknown token ids should throw invalid-token-ids'
+    )
+
+    const string1 = tokenizer.decode('cl100k', tokens, true, { lenient: true })
+    console.log('String:', string1)
+    assert.equal(string1, 'Hello World', 'String should be "Hello World"')
+
+    const string2 = tokenizer.decode('cl100k', tokens, true, { lenient: true, invalid_replacement: '?' })
+    console.log('String:', string2)
+    assert.equal(string2, 'Hello? World', 'String should be "Hello? World"')
+})
+
+test('preset-tiktoken', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', { preset: 'cl100k_base', bpe: data })
+
+    const tokens = tokenizer.encode('cl100k', 'hello <|endofprompt|>')
+    console.log('Tokens:', tokens)
+    assert.deepEqual([...tokens], [15339, 220, 100276], 'Tokens should be [15339, 220, 100276]')
+})
+
+test('encoding-for-model', async () => {
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    assert.equal(tokenizer.encodingForModel('gpt-4o'), 'o200k_base')
+    assert.equal(tokenizer.encodingForModel('gpt-4-0613'), 'cl100k_base')
+    assert.equal(tokenizer.encodingForModel('text-davinci-003'), 'p50k_base')
+    assert.throws(() => tokenizer.encodingForModel('llama-3'), (error) => error.kind === 'unknown-model')
+})
+
+test('handles', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './neox_20b_tokenizer.json'), 'utf-8')
+    const { Tokenizer, TokenizerError } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    const handle1 = tokenizer.load('neox20b', { model: data })
+    const tokens = tokenizer.encode(handle1, 'Hello World!')
+    assert.deepEqual([...tokens], [12092, 3645, 2], 'Tokens should be [12092, 3645, 2]')
+    assert.equal(tokenizer.decode('neox20b', tokens), 'Hello World!', 'Names should still resolve')
+
+    const handle2 = tokenizer.load('neox20b', { model: data })
+    assert.notEqual(handle1, handle2, 'Reloading should return a new handle')
+    assert.throws(
+        () => tokenizer.encode(handle1, 'Hello World!'),
+        (error) => error instanceof TokenizerError && error.kind === 'handle-not-found' && error.context.handle === handle1,
+        'Replaced handles should throw handle-not-found'
+    )
+
+    tokenizer.unload(handle2)
+    assert.deepEqual(tokenizer.getLoaded(), [], 'Unloading by handle should remove the name')
+    assert.throws(() => tokenizer.encode('neox20b', 'Hello World!'), (error) => error.kind === 'tokenizer-not-found')
+})
+
+test('list-tokenizers', async () => {
+    const tiktoken = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const huggingface = fs.readFileSync(path.resolve(__dirname, './neox_20b_tokenizer.json'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', { preset: 'cl100k_base', bpe: tiktoken })
+    assert.throws(() => tokenizer.load('broken', { model: '{}' }), (error) => error.kind === 'load-failed')
+    tokenizer.load('neox20b', { model: huggingface })
+    assert.deepEqual(tokenizer.getLoaded(), ['cl100k', 'neox20b'], 'Failed loads should not be listed')
+
+    const [cl100k, neox20b] = tokenizer.listTokenizers()
+    console.log('Tokenizers:', cl100k.name, cl100k.vocab_size, neox20b.name, neox20b.vocab_size)
+    assert.equal(cl100k.kind, 'tiktoken')
+    assert.equal(cl100k.max_token_id, 100276)
+    assert.deepEqual(cl100k.special_tokens[0], ['<|endoftext|>', 100257])
+    assert.equal(neox20b.kind, 'huggingface')
+    assert.equal(neox20b.regex, null)
+})
+
+test('bundle', async () => {
+    const huggingface = fs.readFileSync(path.resolve(__dirname, './neox_20b_tokenizer.json'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('neox20b', {
+        model: huggingface,
+        config: JSON.stringify({ add_bos_token: false, eos_token: '<|endoftext|>', model_max_length: 2048 }),
+        special_tokens_map: JSON.stringify({ pad_token: { content: '<|padding|>' } }),
+    })
+
+    const [info] = tokenizer.listTokenizers()
+    console.log('Roles:', info.special_token_roles)
+    assert.deepEqual(info.special_token_roles, [
+        { role: 'eos_token', token: '<|endoftext|>', id: 0 },
+        { role: 'pad_token', token: '<|padding|>', id: 1 },
+    ])
+    assert.equal(info.max_length, 2048)
+    assert.equal(info.add_bos_token, false)
+    assert.equal(info.add_eos_token, null)
+})
+
+test('masks', async () => {
+    const huggingface = fs.readFileSync(path.resolve(__dirname, './neox_20b_tokenizer.json'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('neox20b', { model: huggingface, config: JSON.stringify({ pad_token: '<|padding|>' }) })
+
+    const texts = ['Hello World', 'This is a longer sentence']
+    const padded = tokenizer.encodeWithMasks('neox20b', texts, true, { padding: 'longest', padding_side: 'left' })
+    console.log('Padded:', padded)
+    assert.deepEqual([...padded[0].tokens], [1, 1, 1, 12092, 3645])
+    assert.deepEqual([...padded[0].attention_mask], [0, 0, 0, 1, 1])
+    assert.deepEqual([...padded[1].attention_mask], [1, 1, 1, 1, 1])
+
+    const [truncated] = tokenizer.encodeWithMasks('neox20b', ['This is a longer sentence'], true, { max_length: 3, stride: 1 })
+    assert.deepEqual([...truncated.tokens], [1552, 310, 247])
+    assert.deepEqual(truncated.overflowing.map((x) => [...x.tokens]), [[247, 3356, 6197]])
+
+    const [pair] = tokenizer.encodeWithMasks('neox20b', ['Hello World'], true, { pairs: ['Hi'] })
+    assert.deepEqual([...pair.type_ids], [0, 0, 1])
+
+    assert.throws(() => tokenizer.encodeWithMasks('neox20b', ['Hello World'], true, { padding: 'max-length' }))
+})
+
+test('vocab', async () => {
+    const tiktoken = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const huggingface = fs.readFileSync(path.resolve(__dirname, './neox_20b_tokenizer.json'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', { preset: 'cl100k_base', bpe: tiktoken })
+    tokenizer.load('neox20b', { model: huggingface })
+
+    assert.equal(tokenizer.tokenToId('cl100k', 'Hello'), 9906)
+    const byte = tokenizer.tokenToId('cl100k', new Uint8Array([0xf0]))
+    assert.deepEqual([...tokenizer.idToTokenBytes('cl100k', byte)], [0xf0], 'Single bytes should round-trip')
+    assert.deepEqual([...tokenizer.idToTokenBytes('cl100k', 4435)], [...new TextEncoder().encode(' World')])
+    assert.equal(tokenizer.idToTokenBytes('cl100k', 100261), null)
+    assert.ok(tokenizer.isSpecial('cl100k', 100257))
+    assert.equal(tokenizer.specialTokenId('cl100k', '<|endofprompt|>'), 100276)
+
+    assert.equal(tokenizer.tokenToId('neox20b', ' World'), 3645)
+    assert.deepEqual([...tokenizer.idToTokenBytes('neox20b', 3645)], [...new TextEncoder().encode(' World')])
+    assert.ok(tokenizer.isSpecial('neox20b', 0))
+    assert.equal(tokenizer.specialTokenId('neox20b', '<|endoftext|>'), 0)
+})
+
+test('prefix-search', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', { preset: 'cl100k_base', bpe: data })
+
+    const tokens = tokenizer.tokensWithPrefix('cl100k', ' Wor')
+    console.log('Tokens:', tokens.length)
+    assert.ok(tokens.includes(4435), 'Tokens should contain " World"')
+    const decoder = new TextDecoder()
+    assert.ok([...tokens].every((x) => decoder.decode(tokenizer.idToTokenBytes('cl100k', x)).startsWith(' Wor')))
+
+    const prefixes = tokenizer.prefixTokens('cl100k', ' Worldwide')
+    console.log('Prefixes:', prefixes)
+    assert.deepEqual([...prefixes], [220, 468, 28357, 36636, 4435, 53035])
+})
+
+test('truncate', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', { preset: 'cl100k_base', bpe: data })
+
+    const text = 'Hello 🚀🚀🚀 World! This is a longer sentence that needs to be truncated.'
+    for (const direction of ['head', 'tail', 'both']) {
+        for (let max_tokens = 0; max_tokens < 10; max_tokens++) {
+            const result = tokenizer.truncate('cl100k', text, max_tokens, true, { direction })
+            assert.ok(result.truncated)
+            assert.ok(result.tokens.length <= max_tokens, `${direction} should fit ${max_tokens} tokens`)
+            assert.ok(!result.text.includes('�'), 'Text should not be cut inside of a character')
+            assert.deepEqual([...tokenizer.encode('cl100k', result.text)], [...result.tokens])
+        }
+    }
+
+    const result = tokenizer.truncate('cl100k', text, 8, true, { direction: 'both', ellipsis: ' [...] ' })
+    console.log('Truncated:', result.text)
+    assert.ok(result.text.startsWith('Hello') && result.text.includes('[...]') && result.text.endsWith('truncated.'))
+    assert.equal(tokenizer.truncate('cl100k', 'Hello', 8).text, 'Hello')
+})
+
+test('chunk', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', { preset: 'cl100k_base', bpe: data })
+
+    const text =
+        'The first paragraph has two sentences. This is the second one.\n\n' +
+        'The second paragraph talks about rockets 🚀🚀 and stars. It also has a second sentence!'
+    const total = tokenizer.count('cl100k', text)
+
+    const chunks = tokenizer.chunk('cl100k', text, 20, true, { slack: 10 })
+    const parts = chunks.map((x) => text.slice(...x.utf16))
+    console.log('Chunks:', parts)
+    assert.equal(parts.join(''), text, 'Chunks without overlap should cover the text')
+    assert.ok(parts[0].endsWith('second one.\n\n'), 'First chunk should end with the paragraph')
+
+    const overlapping = tokenizer.chunk('cl100k', text, 10, true, { overlap: 3 })
+    assert.equal(overlapping[0].tokens[0], 0)
+    assert.equal(overlapping[overlapping.length - 1].tokens[1], total)
+    for (let i = 1; i < overlapping.length; i++) {
+        assert.ok(overlapping[i].tokens[0] >= overlapping[i - 1].tokens[1] - 3, 'Chunks should overlap by at most 3 tokens')
+        assert.ok(overlapping[i].tokens[1] - overlapping[i].tokens[0] <= 10, 'Chunks should fit 10 tokens')
+    }
+})
+
+test('render-chat', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', {
+        bpe: data,
+        special_bpe: [
+            ['<|endoftext|>', 100257],
+            ['<|im_start|>', 100264],
+            ['<|im_end|>', 100265],
+        ],
+        // eslint-disable-next-line max-len
+        regex: String.raw`(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+`,
+    })
+
+    const messages = [
+        { role: 'system', content: 'You are a helpful assistant.' },
+        { role: 'user', name: 'example_user', content: 'Hello World!' },
+    ]
+    const result = tokenizer.renderChat('cl100k', messages)
+    console.log('Chat:', result.text, result.message_tokens, result.reply_tokens)
+    const count = (text) => tokenizer.count('cl100k', text, false)
+    assert.deepEqual(result.message_tokens, [
+        3 + count('system') + count('You are a helpful assistant.'),
+        4 + count('user') + count('example_user') + count('Hello World!'),
+    ])
+    assert.equal(result.reply_tokens, 3)
+    assert.equal(result.tokens.length, result.message_tokens[0] + result.message_tokens[1] + 3)
+})
+
+test('chat-template', async () => {
+    const huggingface = fs.readFileSync(path.resolve(__dirname, './neox_20b_tokenizer.json'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    const config = JSON.stringify({
+        bos_token: '<s>',
+        chat_template:
+            "{% for message in messages %}{{ bos_token + message['role'] + ': ' + message['content'] | trim }}\n{% endfor %}" +
+            "{% if add_generation_prompt %}{{ bos_token }}assistant:{% endif %}",
+    })
+    tokenizer.load('neox20b', { model: huggingface, config })
+
+    const messages = [{ role: 'user', content: ' Hello World! ' }]
+    const result = tokenizer.applyChatTemplate('neox20b', messages, { add_generation_prompt: true })
+    console.log('Prompt:', result.text)
+    assert.equal(result.text, '<s>user: Hello World!\n<s>assistant:')
+    assert.deepEqual(result.tokens, tokenizer.encode('neox20b', result.text, false))
+
+    const custom = tokenizer.applyChatTemplate('neox20b', messages, { chat_template: '{{ messages[0].role }}' })
+    assert.equal(custom.text, 'user')
+    assert.throws(() => tokenizer.applyChatTemplate('neox20b', messages, { chat_template: '{{ raise_exception("nope") }}' }))
+})
+
+test('tool-tokens', async () => {
+    const data = fs.readFileSync(path.resolve(__dirname, './cl100k_base.tiktoken'), 'utf-8')
+    const { Tokenizer } = await import('../dist/index.js')
+
+    const tokenizer = await Tokenizer.create()
+    tokenizer.load('cl100k', {
+        bpe: data,
+        special_bpe: [['<|endoftext|>', 100257]],
+        // eslint-disable-next-line max-len
+        regex: String.raw`(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+`,
+    })
+
+    const tools = [
+        {
+            type: 'function',
+            function: {
+                name: 'get_current_weather',
+                description: 'Get the current weather in a given location',
+                parameters: {
+                    type: 'object',
+                    properties: {
+                        location: { type: 'string', description: 'The city and state, e.g. San Francisco, CA' },
+                        unit: { type: 'string', enum: ['celsius', 'fahrenheit'] },
+                    },
+                    required: ['location'],
+                },
+            },
+        },
+    ]
+    const result = tokenizer.countToolTokens('cl100k', tools)
+    console.log('Tools:', result.text, result.text_tokens, result.prompt_tokens)
+    assert.ok(result.text.includes('type get_current_weather = (_: {'))
+    assert.ok(result.text.includes('unit?: "celsius" | "fahrenheit",'))
+    assert.equal(result.text_tokens, tokenizer.count('cl100k', result.text, false))
+    assert.equal(result.prompt_tokens, result.text_tokens + 9)
+    assert.equal(tokenizer.countToolTokens('cl100k', tools, true).prompt_tokens, result.text_tokens + 5)
+})